@@ -0,0 +1,176 @@
+use std::fs;
+
+use crate::spectrum;
+use crate::util::hash_str;
+use crate::error::{Error, Result};
+
+// Photometric filters. A filter is a transmission curve over photon energy, and the light of a
+// pixel through it is the integral of the transmission times the spectrum each ray picked up, in
+// the units of the images. The built-in optical filters are Gaussians with the effective
+// wavelength and FWHM of the Johnson-Cousins UBVRI (Bessell 2005) and SDSS ugriz (Fukugita et al.
+// 1996) bands, close enough for colours but not for precise photometry, for which the published
+// curves can be loaded instead. The X-ray bands are flat.
+
+const HC: f64 = 12398.42; // eV Å
+const GAUSSIAN_POINTS: usize = 61;
+const GAUSSIAN_EXTENT: f64 = 1.5; // Gaussian filters are cut this many FWHM either side of the peak
+const MAX_LOG_STEP: f64 = 0.005; // Largest step in ln(energy) of the quadrature over a filter
+const TABLE_LOG_STEP: f64 = 0.005; // Step in ln(temperature) of the tabulated blackbody light
+const TABLE_SPAN: f64 = 100.0; // The table reaches this factor below and above the filter's energies
+
+/// Effective wavelength and FWHM in Å of the built-in optical filters
+const OPTICAL: [(&str, f64, f64); 10] = [
+    ("U", 3660.0, 650.0), ("B", 4360.0, 890.0), ("V", 5450.0, 840.0), ("R", 6410.0, 1580.0), ("I", 7980.0, 1540.0),
+    ("u", 3557.0, 599.0), ("g", 4825.0, 1379.0), ("r", 6261.0, 1382.0), ("i", 7672.0, 1535.0), ("z", 9097.0, 1370.0),
+];
+/// Edges in eV of the built-in X-ray bands
+const XRAY: [(&str, f64, f64); 2] = [("0.3-2keV", 300.0, 2000.0), ("2-10keV", 2000.0, 10000.0)];
+
+pub struct Bandpass {
+    name: String,
+    /// Transmission at increasing photon energies in eV, zero outside
+    curve: Vec<(f64, f64)>,
+    /// Energies and weights that integrate over the curve, transmission included
+    quadrature: Vec<(f64, f64)>,
+    /// ln of the lowest temperature in eV in `table`
+    table_start: f64,
+    /// ln of the light of a blackbody through the filter at temperatures TABLE_LOG_STEP apart in
+    /// ln(temperature), so that it is not integrated for every piece of light
+    table: Vec<f64>,
+}
+
+impl Bandpass {
+    /// A filter called `name` with transmission `curve`, given as (energy in eV, transmission)
+    /// in any order.
+    pub fn new(name: &str, mut curve: Vec<(f64, f64)>) -> Result<Self> {
+        if curve.len() < 2 {
+            return Err(Error::InvalidScene(format!("filter {} needs at least two points", name)));
+        }
+        if curve.iter().any(|&(energy, transmission)| !(energy.is_finite() && energy > 0.0 && transmission.is_finite() && transmission >= 0.0)) {
+            return Err(Error::InvalidScene(format!("filter {} needs positive energies and transmissions of at least zero", name)));
+        }
+        curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+        // Midpoints in ln(energy) on each segment, close enough that the spectrum is resolved
+        // too, with the transmission interpolated linearly in energy
+        let mut quadrature = Vec::new();
+        for pair in curve.windows(2) {
+            let ((e0, t0), (e1, t1)) = (pair[0], pair[1]);
+            if e1 <= e0 {
+                continue;
+            }
+            let log_width = (e1 / e0).ln();
+            let steps = (log_width / MAX_LOG_STEP).ceil().max(1.0) as usize;
+            for k in 0..steps {
+                let energy = e0 * (log_width * (k as f64 + 0.5) / steps as f64).exp();
+                let transmission = t0 + (t1 - t0) * (energy - e0) / (e1 - e0);
+                quadrature.push((energy, transmission * energy * log_width / steps as f64));
+            }
+        }
+        let table_start = (curve[0].0 / TABLE_SPAN).ln();
+        let table_end = (curve[curve.len() - 1].0 * TABLE_SPAN).ln();
+        let points = ((table_end - table_start) / TABLE_LOG_STEP).ceil() as usize + 1;
+        let table = (0..points)
+            .map(|k| integrate(&quadrature, (table_start + k as f64 * TABLE_LOG_STEP).exp()).ln())
+            .collect();
+        Ok(Self { name: name.to_owned(), curve, quadrature, table_start, table })
+    }
+
+    /// Read a filter with one point per line: wavelength and transmission, separated by spaces
+    /// or commas. Wavelengths are in Å unless a line `# unit: nm`, `# unit: eV` or `# unit: keV`
+    /// says otherwise. Other lines starting with `#` are skipped. The filter is named after the
+    /// file.
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut to_energy: fn(f64) -> f64 = |angstrom| HC / angstrom;
+        let mut curve = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some(unit) = line.strip_prefix('#').and_then(|c| c.trim().strip_prefix("unit:")) {
+                to_energy = match unit.trim() {
+                    "angstrom" | "Å" => |angstrom| HC / angstrom,
+                    "nm" => |nm| HC / (10.0 * nm),
+                    "eV" => |ev| ev,
+                    "keV" => |kev| 1000.0 * kev,
+                    unit => return Err(Error::Format(format!("{} line {}: unknown unit {}", path, n + 1, unit))),
+                };
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values: Vec<f64> = line.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse())
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| Error::Format(format!("{} line {}: not a number", path, n + 1)))?;
+            if values.len() != 2 {
+                return Err(Error::Format(format!("{} line {}: needs wavelength and transmission", path, n + 1)));
+            }
+            curve.push((to_energy(values[0]), values[1]));
+        }
+        let name = std::path::Path::new(path).file_stem().map_or(path.into(), |s| s.to_string_lossy());
+        Self::new(&name, curve)
+    }
+
+    /// The built-in filter called `name`, one of `builtin_names`.
+    pub fn builtin(name: &str) -> Option<Self> {
+        if let Some(&(_, centre, width)) = OPTICAL.iter().find(|f| f.0 == name) {
+            let curve = (0..GAUSSIAN_POINTS).map(|k| {
+                let offset = GAUSSIAN_EXTENT * width * (2.0 * k as f64 / (GAUSSIAN_POINTS - 1) as f64 - 1.0);
+                (HC / (centre + offset), (-4.0 * 2f64.ln() * (offset / width).powi(2)).exp())
+            }).collect();
+            return Self::new(name, curve).ok();
+        }
+        let &(_, low, high) = XRAY.iter().find(|f| f.0 == name)?;
+        Self::new(name, vec![(low, 1.0), (high, 1.0)]).ok()
+    }
+
+    /// The built-in filter called `name`, or else the filter in the file `name`.
+    pub fn named(name: &str) -> Result<Self> {
+        match Self::builtin(name) {
+            Some(filter) => Ok(filter),
+            None => Self::load(name),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The filter's name and a hash of its curve, for hashing the scene.
+    pub fn describe(&self) -> String {
+        format!("{}:{:016x}", self.name, hash_str(&format!("{:?}", self.curve)))
+    }
+
+    /// Light through the filter of a blackbody at `temp` eV, per unit bolometric intensity.
+    pub fn blackbody(&self, temp: f64) -> f64 {
+        if !(temp.is_finite() && temp > 0.0) {
+            return 0.0;
+        }
+        // Interpolated linearly in ln(light) against ln(temperature), and integrated outright
+        // off the table
+        let position = (temp.ln() - self.table_start) / TABLE_LOG_STEP;
+        let k = position.floor();
+        if k < 0.0 || k as usize + 1 >= self.table.len() {
+            return integrate(&self.quadrature, temp);
+        }
+        let (low, high) = (self.table[k as usize], self.table[k as usize + 1]);
+        if !(low.is_finite() && high.is_finite()) {
+            return integrate(&self.quadrature, temp);
+        }
+        (low + (high - low) * (position - k)).exp()
+    }
+}
+
+/// Light of a blackbody at `temp` eV through the filter with `quadrature`, per unit bolometric
+/// intensity.
+fn integrate(quadrature: &[(f64, f64)], temp: f64) -> f64 {
+    quadrature.iter()
+        .map(|&(energy, weight)| weight * spectrum::blackbody_density(energy, temp))
+        .sum()
+}
+
+/// Names of the built-in filters
+pub fn builtin_names() -> Vec<&'static str> {
+    OPTICAL.iter().map(|f| f.0).chain(XRAY.iter().map(|f| f.0)).collect()
+}
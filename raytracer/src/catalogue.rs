@@ -0,0 +1,375 @@
+use std::fs;
+use std::fmt::Write as _;
+use std::f64::consts::PI;
+use ndarray::{Array2, Array3};
+use ndarray_npy::{read_npy, write_npy};
+
+use crate::util::*;
+use crate::observer::{PhotonData, Termination, WIDTH, HEIGHT, PIXEL_WIDTH};
+use crate::image::{self, ToneMap};
+use crate::skybox::{STAR_MIN_TEMP, STAR_MAX_TEMP};
+use crate::error::{Error, Result};
+
+// Point stars lensed by the hole. The escape direction of the rays through neighbouring pixel
+// centres maps each triangle of the image plane onto a triangle of the sky. A star lying in a
+// sky triangle has an image in the image triangle, found by interpolation, and the ratio of the
+// two triangles' solid angles is the image's magnification.
+
+const MIN_ESCAPE_FRACTION: f64 = 0.999; // Pixels with fewer escaped rays are not used
+const MAX_EDGE_ANGLE: f64 = 0.2; // Sky triangles with longer edges straddle a caustic or the shadow
+const MAX_KINK: f64 = 0.5; // Largest second difference of the sky map, relative to the first, before a pixel is an outlier
+const PSF_SIGMA: f64 = 0.7; // Width of a rendered star, in pixels
+const RANDOM_MAGNITUDES: (f64, f64) = (0.0, 8.0);
+const DEFAULT_TEMP: f64 = 6000.0; // Kelvin, for stars without a temperature
+
+#[derive(Debug, Clone)]
+pub struct Star {
+    /// Polar angle from the spin axis, in radians
+    pub theta: f64,
+    /// Azimuth, in radians. The observer sits at φ = 0.
+    pub phi: f64,
+    pub magnitude: f64,
+    /// Kelvin
+    pub temperature: f64,
+}
+
+impl Star {
+    fn direction(&self) -> Vec3 {
+        [self.theta.sin() * self.phi.cos(), self.theta.sin() * self.phi.sin(), self.theta.cos()]
+    }
+
+    fn flux(&self) -> f64 {
+        10f64.powf(-0.4 * self.magnitude)
+    }
+}
+
+/// One image of a star.
+pub struct StarImage {
+    /// Index of the star in the catalogue
+    pub star: usize,
+    /// Position in pixels, row 0 at the bottom
+    pub row: f64,
+    pub column: f64,
+    /// Signed magnification. Negative for images that are mirrored.
+    pub magnification: f64,
+}
+
+/// Read a catalogue with one star per line: polar angle and azimuth in degrees, magnitude and
+/// optionally temperature in kelvin, separated by spaces or commas. Lines starting with `#`
+/// are skipped.
+pub fn load(path: &str) -> Result<Vec<Star>> {
+    let text = fs::read_to_string(path)?;
+    let mut stars = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values: Vec<f64> = line.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| Error::Format(format!("{} line {}: not a number", path, n + 1)))?;
+        if values.len() < 3 {
+            return Err(Error::Format(format!("{} line {}: needs theta, phi and magnitude", path, n + 1)));
+        }
+        stars.push(Star {
+            theta: values[0].to_radians(),
+            phi: values[1].to_radians(),
+            magnitude: values[2],
+            temperature: values.get(3).copied().unwrap_or(DEFAULT_TEMP),
+        });
+    }
+    Ok(stars)
+}
+
+/// `count` stars spread uniformly over the sky, with magnitudes and temperatures drawn from
+/// `seed`.
+pub fn random(count: usize, seed: u64) -> Vec<Star> {
+    let rng = fastrand::Rng::with_seed(seed);
+    (0..count).map(|_| Star {
+        theta: (2.0 * rng.f64() - 1.0).acos(),
+        phi: 2.0 * PI * rng.f64(),
+        magnitude: RANDOM_MAGNITUDES.0 + (RANDOM_MAGNITUDES.1 - RANDOM_MAGNITUDES.0) * rng.f64(),
+        temperature: STAR_MIN_TEMP + (STAR_MAX_TEMP - STAR_MIN_TEMP) * rng.f64(),
+    }).collect()
+}
+
+/// Find every image of every star, given the (θ, φ) escape angles of each pixel as a
+/// (2, row, column) array and the fraction of each pixel's rays that escaped.
+pub fn find_images(escape: &Array3<f64>, escaped: &Array2<f64>, stars: &[Star]) -> Vec<StarImage> {
+    let raw: Array2<Option<Vec3>> = Array2::from_shape_fn((HEIGHT, WIDTH), |(i, j)| {
+        let (theta, phi) = (escape[(0, i, j)], escape[(1, i, j)]);
+        if escaped[(i, j)] >= MIN_ESCAPE_FRACTION && theta.is_finite() && phi.is_finite() {
+            Some([theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()])
+        } else {
+            None
+        }
+    });
+    // A single badly traced pixel folds the map over and makes a spurious pair of images, so
+    // drop pixels that sit well off the line through their neighbours
+    let directions = Array2::from_shape_fn((HEIGHT, WIDTH), |(i, j)| {
+        let here = raw[(i, j)]?;
+        let neighbours = [
+            (i.checked_sub(1).and_then(|i| raw[(i, j)]), raw.get((i + 1, j)).copied().flatten()),
+            (j.checked_sub(1).and_then(|j| raw[(i, j)]), raw.get((i, j + 1)).copied().flatten()),
+        ];
+        for (before, after) in neighbours {
+            if let (Some(before), Some(after)) = (before, after) {
+                let kink = length(add3(add3(before, after), mul3(here, -2.0)));
+                if kink > MAX_KINK * length(add3(after, mul3(before, -1.0))) {
+                    return None;
+                }
+            }
+        }
+        Some(here)
+    });
+    let star_directions: Vec<Vec3> = stars.iter().map(|s| s.direction()).collect();
+    let index = SkyIndex::new(stars);
+
+    let mut images = Vec::new();
+    for i in 0..HEIGHT - 1 {
+        for j in 0..WIDTH - 1 {
+            // Two triangles per square of pixel centres, each listed anticlockwise on the image
+            for corners in [[(i, j), (i, j + 1), (i + 1, j)], [(i + 1, j + 1), (i + 1, j), (i, j + 1)]] {
+                let sky = match (directions[corners[0]], directions[corners[1]], directions[corners[2]]) {
+                    (Some(a), Some(b), Some(c)) => [a, b, c],
+                    _ => continue,
+                };
+                if (0..3).any(|k| angle_between(sky[k], sky[(k + 1) % 3]) > MAX_EDGE_ANGLE) {
+                    continue;
+                }
+                let orientation = triple(sky[0], sky[1], sky[2]);
+                for star in index.near(sky) {
+                    let s = star_directions[star];
+                    // Barycentric weights of the star in the sky triangle, all of one sign if
+                    // it is inside
+                    let weights = [triple(s, sky[1], sky[2]), triple(sky[0], s, sky[2]), triple(sky[0], sky[1], s)];
+                    let total: f64 = weights.iter().sum();
+                    if dot3(s, sky[0]) <= 0.0 || weights.iter().any(|w| w * orientation < 0.0) || total == 0.0 {
+                        continue;
+                    }
+                    let row = (0..3).map(|k| weights[k] * corners[k].0 as f64).sum::<f64>() / total;
+                    let column = (0..3).map(|k| weights[k] * corners[k].1 as f64).sum::<f64>() / total;
+                    // An unlensed view maps anticlockwise image triangles to clockwise ones on
+                    // the sky seen from outside, so a negative orientation is the normal parity
+                    let parity = -orientation.signum();
+                    let magnification = parity * image_solid_angle(corners) / sky_solid_angle(sky);
+                    // A sky triangle folded flat, on a caustic, has no finite magnification
+                    if magnification.is_finite() {
+                        images.push(StarImage { star, row, column, magnification });
+                    }
+                }
+            }
+        }
+    }
+    images.sort_by(|a, b| a.star.cmp(&b.star).then(b.magnification.abs().total_cmp(&a.magnification.abs())));
+    images
+}
+
+/// The stars sorted into cells of polar angle and azimuth, so that only the stars near a sky
+/// triangle are tested against it.
+struct SkyIndex {
+    rows: usize,
+    columns: usize,
+    cells: Vec<Vec<usize>>,
+}
+
+impl SkyIndex {
+    fn new(stars: &[Star]) -> Self {
+        let rows = (PI / MAX_EDGE_ANGLE).ceil() as usize;
+        let columns = 2 * rows;
+        let mut cells = vec![Vec::new(); rows * columns];
+        for (k, star) in stars.iter().enumerate() {
+            let (row, column) = Self::cell(rows, columns, star.theta, star.phi);
+            cells[row * columns + column].push(k);
+        }
+        Self { rows, columns, cells }
+    }
+
+    fn cell(rows: usize, columns: usize, theta: f64, phi: f64) -> (usize, usize) {
+        let row = ((theta / PI * rows as f64) as usize).min(rows - 1);
+        let column = ((phi.rem_euclid(2.0 * PI) / (2.0 * PI) * columns as f64) as usize).min(columns - 1);
+        (row, column)
+    }
+
+    /// Every star in the cells that the cap around the triangle `sky` touches, in catalogue
+    /// order within each cell.
+    fn near(&self, sky: [Vec3; 3]) -> impl Iterator<Item = usize> + '_ {
+        let centre = normalize(add3(add3(sky[0], sky[1]), sky[2]));
+        let radius = sky.iter().map(|&corner| angle_between(centre, corner)).fold(0.0, f64::max);
+        let theta = centre[2].clamp(-1.0, 1.0).acos();
+        let phi = centre[1].atan2(centre[0]);
+        let (low, high) = (theta - radius, theta + radius);
+        // Caps over a pole cover every azimuth
+        let spread = if low <= 0.0 || high >= PI {
+            PI
+        } else {
+            (radius.sin() / low.sin().min(high.sin())).min(1.0).asin()
+        };
+        let (first_row, _) = Self::cell(self.rows, self.columns, low.max(0.0), 0.0);
+        let (last_row, _) = Self::cell(self.rows, self.columns, high.min(PI), 0.0);
+        let (_, first_column) = Self::cell(self.rows, self.columns, 0.0, phi - spread);
+        let span = if spread >= PI {
+            self.columns
+        } else {
+            ((2.0 * spread / (2.0 * PI) * self.columns as f64).ceil() as usize + 2).min(self.columns)
+        };
+        (first_row..=last_row).flat_map(move |row| {
+            (0..span).flat_map(move |c| self.cells[row * self.columns + (first_column + c) % self.columns].iter().copied())
+        })
+    }
+}
+
+/// Render the images as blurred points with their magnified fluxes, as a (3, row, column) array.
+pub fn render(images: &[StarImage], stars: &[Star]) -> Array3<f64> {
+    let mut picture = Array3::zeros((3, HEIGHT, WIDTH));
+    let reach = (3.0 * PSF_SIGMA).ceil() as i64;
+    for image in images {
+        let star = &stars[image.star];
+        let color = PhotonData::temp_to_color(star.temperature);
+        let flux = star.flux() * image.magnification.abs();
+        let norm = 1.0 / (2.0 * PI * PSF_SIGMA * PSF_SIGMA);
+        for i in image.row.round() as i64 - reach..=image.row.round() as i64 + reach {
+            for j in image.column.round() as i64 - reach..=image.column.round() as i64 + reach {
+                if i < 0 || j < 0 || i >= HEIGHT as i64 || j >= WIDTH as i64 {
+                    continue;
+                }
+                let r2 = (i as f64 - image.row).powi(2) + (j as f64 - image.column).powi(2);
+                let weight = flux * norm * (-0.5 * r2 / (PSF_SIGMA * PSF_SIGMA)).exp();
+                let (i, j) = (i as usize, j as usize);
+                picture[(0, i, j)] += weight * color.0;
+                picture[(1, i, j)] += weight * color.1;
+                picture[(2, i, j)] += weight * color.2;
+            }
+        }
+    }
+    picture
+}
+
+/// Find the images of `stars` and write `<base>-stars.csv`, a table of every image, and
+/// `<base>-stars.npy` (plus `.png` if `tone_map` is given), the rendered starfield. Returns the
+/// number of images.
+pub fn lens(base: &str, escape: &Array3<f64>, escaped: &Array2<f64>, stars: &[Star], tone_map: Option<&ToneMap>) -> Result<usize> {
+    let images = find_images(escape, escaped, stars);
+    let mut table = "star,theta_deg,phi_deg,magnitude,image,row,column,x,y,magnification,parity,lensed_magnitude\n".to_owned();
+    let mut last_star = None;
+    let mut index = 0;
+    for image in &images {
+        index = if last_star == Some(image.star) { index + 1 } else { 0 };
+        last_star = Some(image.star);
+        let star = &stars[image.star];
+        writeln!(
+            table, "{},{},{},{},{},{:.4},{:.4},{:.6},{:.6},{:.6},{},{:.4}",
+            image.star, star.theta.to_degrees(), star.phi.to_degrees(), star.magnitude, index,
+            image.row, image.column,
+            PIXEL_WIDTH * (image.column - (WIDTH / 2) as f64), PIXEL_WIDTH * (image.row - (HEIGHT / 2) as f64),
+            image.magnification, if image.magnification < 0.0 { -1 } else { 1 },
+            star.magnitude - 2.5 * image.magnification.abs().log10(),
+        ).unwrap();
+    }
+    fs::write(format!("{}-stars.csv", base), table)?;
+
+    let picture = render(&images, stars);
+    write_npy(format!("{}-stars.npy", base), &picture)?;
+    if let Some(tone_map) = tone_map {
+        // Point sources need the brightest pixel as the white point
+        let tone_map = ToneMap { white_percentile: 100.0, ..*tone_map };
+        image::write_png(&format!("{}-stars.png", base), &picture, &tone_map)?;
+    }
+    let seen = images.iter().map(|i| i.star).collect::<std::collections::HashSet<_>>().len();
+    println!("Found {} images of {} stars", images.len(), seen);
+    Ok(images.len())
+}
+
+/// Lens `stars` with the escape maps saved by an earlier render (or merge) of `file_name`.
+pub fn lens_saved(file_name: &str, stars: &[Star], tone_map: &ToneMap) -> Result<usize> {
+    let base = format!("../data/{}", file_name);
+    let escape: Array3<f64> = read_npy(format!("{}-escape.npy", base))?;
+    let terminations: Array3<f64> = read_npy(format!("{}-terminations.npy", base))?;
+    let escaped = terminations.index_axis(ndarray::Axis(0), Termination::Escape as usize).to_owned();
+    lens(&base, &escape, &escaped, stars, Some(tone_map))
+}
+
+fn triple(a: Vec3, b: Vec3, c: Vec3) -> f64 {
+    dot3(a, cross(b, c))
+}
+
+fn angle_between(a: Vec3, b: Vec3) -> f64 {
+    dot3(a, b).clamp(-1.0, 1.0).acos()
+}
+
+/// Solid angle of a triangle on the unit sphere (Van Oosterom and Strackee).
+fn sky_solid_angle(corners: [Vec3; 3]) -> f64 {
+    let [a, b, c] = corners;
+    let denominator = 1.0 + dot3(a, b) + dot3(b, c) + dot3(c, a);
+    2.0 * triple(a, b, c).abs().atan2(denominator)
+}
+
+/// Solid angle seen by the camera through a triangle of pixel centres. The image is a plane one
+/// unit in front of the camera, so area there is foreshortened away from the centre.
+fn image_solid_angle(corners: [(usize, usize); 3]) -> f64 {
+    let to_plane = |(i, j): (usize, usize)| [
+        PIXEL_WIDTH * (j as f64 - (WIDTH / 2) as f64),
+        PIXEL_WIDTH * (i as f64 - (HEIGHT / 2) as f64),
+        1.0,
+    ];
+    let [a, b, c] = corners.map(|corner| normalize(to_plane(corner)));
+    sky_solid_angle([a, b, c])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EINSTEIN_RADIUS: f64 = 20.0 * PIXEL_WIDTH;
+
+    /// The sky seen by a camera at φ = 0 on the equator, looking at a Schwarzschild hole far
+    /// away: each pixel at angle θ from the hole sees the sky at θ (1 − θ_E² / θ²), the point
+    /// lens. Angles are in the tangent plane, where the camera's pixels are laid out.
+    fn point_lens() -> (Array3<f64>, Array2<f64>) {
+        let (look, up, right) = ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]);
+        let mut escape = Array3::from_elem((2, HEIGHT, WIDTH), f64::NAN);
+        let mut escaped = Array2::zeros((HEIGHT, WIDTH));
+        for i in 0..HEIGHT {
+            for j in 0..WIDTH {
+                let (y, x) = (PIXEL_WIDTH * (i as f64 - (HEIGHT / 2) as f64), PIXEL_WIDTH * (j as f64 - (WIDTH / 2) as f64));
+                let r2 = x * x + y * y;
+                // Inside the shadow nothing escapes
+                if r2 < (EINSTEIN_RADIUS / 3.0).powi(2) {
+                    continue;
+                }
+                let scale = 1.0 - EINSTEIN_RADIUS * EINSTEIN_RADIUS / r2;
+                let d = normalize(add3(look, add3(mul3(up, y * scale), mul3(right, x * scale))));
+                escape[(0, i, j)] = d[2].acos();
+                escape[(1, i, j)] = d[1].atan2(d[0]);
+                escaped[(i, j)] = 1.0;
+            }
+        }
+        (escape, escaped)
+    }
+
+    #[test]
+    fn star_behind_the_hole_has_two_images_of_opposite_parity() {
+        let (escape, escaped) = point_lens();
+        // Half an Einstein radius from the hole, off the rows and columns of pixel centres
+        let (y, x) = (0.4 * EINSTEIN_RADIUS, 0.3 * EINSTEIN_RADIUS);
+        let d = normalize([-1.0, x, y]);
+        let star = Star { theta: d[2].acos(), phi: d[1].atan2(d[0]), magnitude: 0.0, temperature: DEFAULT_TEMP };
+        let images = find_images(&escape, &escaped, &[star]);
+        assert_eq!(images.len(), 2);
+
+        // The point lens: images at u ± √(u² + 4) / 2 Einstein radii, on either side of the hole,
+        // with magnifications (u² + 2) / (2u√(u² + 4)) ± 1/2
+        let u: f64 = 0.5;
+        let root = (u * u + 4.0).sqrt();
+        let expected = [((u + root) / 2.0, (u * u + 2.0) / (2.0 * u * root) + 0.5), ((u - root) / 2.0, -((u * u + 2.0) / (2.0 * u * root) - 0.5))];
+        for (image, (position, magnification)) in images.iter().zip(expected) {
+            let (row, column) = ((HEIGHT / 2) as f64 + position * 0.8 * 20.0, (WIDTH / 2) as f64 + position * 0.6 * 20.0);
+            assert!((image.row - row).abs() < 0.5 && (image.column - column).abs() < 0.5,
+                "image at ({}, {}), not ({}, {})", image.row, image.column, row, column);
+            assert!((image.magnification / magnification - 1.0).abs() < 0.05,
+                "magnification {}, not {}", image.magnification, magnification);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use crate::util::*;
+use crate::metrics::SPACETIME_EDGE;
+use crate::error::{Error, Result};
+
+// Compton scattering off the corona's electrons, with the Klein–Nishina cross section.
+//
+// Rays are traced backwards, so at a scattering the photon's direction afterwards, towards the
+// camera, is known and its direction before is to be found. The scattering is worked out in
+// the frame of the zero angular momentum observer, in which the corona is taken to be at rest:
+// 1. Draw the electron's Lorentz factor from its distribution, and its direction so that the
+//    photon's direction afterwards is uniform in the electron's rest frame. The rate of
+//    scatterings into a lab solid angle carries dΩ'/dΩ, which this cancels.
+// 2. Draw the photon's direction before, mostly towards points on the disk spread as its r^-2
+//    light and otherwise uniformly. The ray is traced on from there and picks up the light of
+//    the disk where it lands, so the draw carries the uniform density over the one it was drawn
+//    with as a weight. The photon's energy is drawn from the Planck spectrum at the temperature
+//    of the disk where the straight line back meets it.
+// 3. In the electron's rest frame, Compton's formula gives the energy afterwards and the
+//    Klein–Nishina cross section how likely the scattering is. The lab rate is
+//    n c (1 - β·n_in) dσ/dΩ, so the draw is kept with probability
+//    (1 - β·n_in) 4π/σ_T dσ/dΩ' / WEIGHT_BOUND and otherwise the electron is missed.
+// The corona's density is raised by WEIGHT_BOUND to make up for the misses, so the draws that
+// are kept follow the Klein–Nishina kernel exactly and the rate of scatterings is right.
+
+pub const ELECTRON_REST_ENERGY: f64 = 510_998.95; // eV
+/// Bound on (1 - β·n_in) 4π/σ_T dσ/dΩ': 2 times 3/2, the most the cross section can be
+pub const WEIGHT_BOUND: f64 = 3.0;
+const DISK_SHARE: f64 = 0.75; // Share of the directions before that aim at the disk
+const SEED_INNER: f64 = 1.0; // Radii of the disk the directions aim at
+const SEED_OUTER: f64 = SPACETIME_EDGE;
+const MIN_HEIGHT: f64 = 1e-6; // Closer to the disk's plane than this, all directions are uniform
+const ZETA_3: f64 = 1.202_056_903_159_594;
+const BESSEL_STEPS: usize = 400;
+
+/// How fast the corona's electrons move.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Electrons {
+    /// Maxwell–Jüttner at temperature Θ = kT / m_e c^2, or at the corona's own temperature
+    Thermal { temperature: Option<f64> },
+    /// dN/dγ ∝ γ^-index from `min` to `max`
+    PowerLaw { index: f64, min: f64, max: f64 },
+}
+
+impl Electrons {
+    /// Parse electrons from the command line: `thermal`, `thermal,KEV` or
+    /// `power-law,INDEX,MIN,MAX`.
+    pub fn named(spec: &str) -> Result<Self> {
+        let mut parts = spec.split(',');
+        let kind = parts.next().unwrap_or_default();
+        let values: Vec<f64> = parts.map(|s| s.parse()).collect::<std::result::Result<_, _>>()
+            .map_err(|_| Error::InvalidScene(format!("electrons {} have a value that is not a number", spec)))?;
+        match (kind, values.as_slice()) {
+            ("thermal", []) => Ok(Electrons::Thermal { temperature: None }),
+            ("thermal", &[kev]) if kev > 0.0 && kev.is_finite() => {
+                Ok(Electrons::Thermal { temperature: Some(1000.0 * kev / ELECTRON_REST_ENERGY) })
+            },
+            ("power-law", &[index, min, max]) if index.is_finite() && min >= 1.0 && max > min && max.is_finite() => {
+                Ok(Electrons::PowerLaw { index, min, max })
+            },
+            _ => Err(Error::InvalidScene(format!(
+                "unknown electrons {}: use thermal, thermal,KEV or power-law,INDEX,MIN,MAX with 1 <= MIN < MAX", spec
+            ))),
+        }
+    }
+
+    /// Draw a Lorentz factor, for a corona whose own temperature here is `temperature`.
+    pub fn sample(&self, temperature: f64, rng: &fastrand::Rng) -> f64 {
+        match *self {
+            Electrons::Thermal { temperature: fixed } => maxwell_juttner(fixed.unwrap_or(temperature), rng),
+            Electrons::PowerLaw { index, min, max } => {
+                let u = rng.f64();
+                if (index - 1.0).abs() < 1e-9 {
+                    min * (max / min).powf(u)
+                } else {
+                    let (low, high) = (min.powf(1.0 - index), max.powf(1.0 - index));
+                    (low + u * (high - low)).powf(1.0 / (1.0 - index))
+                }
+            },
+        }
+    }
+
+    /// Mean gain in energy, 1 + 4/3 <γ^2 β^2>, of light these electrons scatter in the Thomson
+    /// limit, for a corona whose own temperature is `temperature`.
+    pub fn mean_gain(&self, temperature: f64) -> f64 {
+        let momentum_squared = match *self {
+            Electrons::Thermal { temperature: fixed } => thermal_momentum_squared(fixed.unwrap_or(temperature)),
+            Electrons::PowerLaw { index, min, max } => {
+                // Integral of γ^n dN/dγ
+                let moment = |n: f64| {
+                    let power = n + 1.0 - index;
+                    if power.abs() < 1e-9 { (max / min).ln() } else { (max.powf(power) - min.powf(power)) / power }
+                };
+                moment(2.0) / moment(0.0) - 1.0
+            },
+        };
+        1.0 + 4.0 / 3.0 * momentum_squared
+    }
+
+    pub fn parameters(&self) -> Vec<(&'static str, f64)> {
+        match *self {
+            // At the corona's own temperature, which the disk records
+            Electrons::Thermal { temperature: None } => vec![],
+            Electrons::Thermal { temperature: Some(temperature) } => vec![("electron_temperature", temperature)],
+            Electrons::PowerLaw { index, min, max } => vec![
+                ("electron_index", index), ("electron_gamma_min", min), ("electron_gamma_max", max),
+            ],
+        }
+    }
+}
+
+/// Draw a Lorentz factor from the Maxwell–Jüttner distribution at temperature `theta`. The
+/// kinetic energy k = γ - 1 has density (1 + k) sqrt(k (k + 2)) e^(-k/Θ), which is at most
+/// sqrt(2) (k^1/2 + 2 k^3/2 + k^5/2) e^(-k/Θ), a mixture of gamma distributions. Draws from the
+/// mixture are kept with the ratio of the two, which is at least 1/sqrt(2 (k + 1)).
+fn maxwell_juttner(theta: f64, rng: &fastrand::Rng) -> f64 {
+    // Γ(3/2), 2 Γ(5/2) Θ and Γ(7/2) Θ^2, the shares of the three parts
+    let weights = [0.886_226_9, 2.658_680_8 * theta, 3.323_350_9 * theta * theta];
+    let total: f64 = weights.iter().sum();
+    loop {
+        let mut pick = rng.f64() * total;
+        let exponentials = weights.iter().position(|&w| { pick -= w; pick < 0.0 }).unwrap_or(2) + 1;
+        // Gamma with shape n + 1/2: n exponentials and half a squared normal
+        let normal = (-2.0 * (1.0 - rng.f64()).ln()).sqrt() * (2.0 * std::f64::consts::PI * rng.f64()).cos();
+        let sum: f64 = (0..exponentials).map(|_| -(1.0 - rng.f64()).ln()).sum();
+        let kinetic = theta * (sum + 0.5 * normal * normal);
+        if rng.f64() * std::f64::consts::SQRT_2 * (1.0 + kinetic) < (kinetic + 2.0).sqrt() {
+            return 1.0 + kinetic;
+        }
+    }
+}
+
+/// <γ^2 β^2> = 3 Θ K_3(1/Θ) / K_2(1/Θ) over the Maxwell–Jüttner distribution at temperature
+/// `theta`, with K_ν(z) e^z = ∫ e^(-z (cosh t - 1)) cosh νt dt from 0 to ∞ by the trapezium rule,
+/// which converges fast for this even, quickly falling integrand.
+fn thermal_momentum_squared(theta: f64) -> f64 {
+    let z = 1.0 / theta;
+    let end = (1.0 + 700.0 / z).acosh();
+    let step = end / BESSEL_STEPS as f64;
+    let bessel = |order: f64| -> f64 {
+        (0..=BESSEL_STEPS).map(|k| {
+            let t = k as f64 * step;
+            let weight = if k == 0 { 0.5 } else { 1.0 };
+            weight * (-z * (t.cosh() - 1.0)).exp() * (order * t).cosh()
+        }).sum()
+    };
+    3.0 * theta * bessel(3.0) / bessel(2.0)
+}
+
+/// Draw a photon energy, in units of kT, from the Planck spectrum x^2 / (e^x - 1). That is the
+/// sum over j of x^2 e^(-jx), gamma distributions with shape 3 and rate j that hold shares
+/// 1/j^3 of the whole.
+fn planck_energy(rng: &fastrand::Rng) -> f64 {
+    let mut pick = rng.f64() * ZETA_3;
+    let mut rate = 1.0;
+    while rate < 1000.0 {
+        pick -= 1.0 / (rate * rate * rate);
+        if pick < 0.0 {
+            break;
+        }
+        rate += 1.0;
+    }
+    let product: f64 = (0..3).map(|_| 1.0 - rng.f64()).product();
+    -product.ln() / rate
+}
+
+/// Scatter the photon with momentum `vel` at `pos` off an electron with Lorentz factor `gamma`.
+/// `seed_temperature` gives the temperature, in eV, of the disk's light at a radius. Returns the
+/// photon's energy at infinity afterwards over that before, which is what a shift in frequency
+/// found along the ray before needs multiplying by, the weight of the direction before, and the
+/// photon's momentum before. `None` where the electron is missed.
+pub fn scatter(
+    g: &Matrix4, pos: Vec4, vel: Vec4, gamma: f64, seed_temperature: impl Fn(f64) -> f64, rng: &fastrand::Rng,
+) -> Option<(f64, f64, Vec4)> {
+    let tetrad = zamo_tetrad(g);
+    let lower = matvecmul(g, vel);
+    let energy = -dot4(lower, tetrad[0]);
+    let out = normalize([dot4(lower, tetrad[1]), dot4(lower, tetrad[2]), dot4(lower, tetrad[3])]);
+
+    // Electron direction, with the photon's direction afterwards uniform in its rest frame
+    let beta = (1.0 - 1.0 / (gamma * gamma)).max(0.0).sqrt();
+    let rest_cosine = 2.0 * rng.f64() - 1.0;
+    let cosine = (rest_cosine + beta) / (1.0 + beta * rest_cosine);
+    let electron = rotate_from(out, cosine, 2.0 * std::f64::consts::PI * rng.f64());
+    let (into, weight, landing) = draw_into(pos, rng);
+
+    let seed = planck_energy(rng) * seed_temperature(landing.unwrap_or(pos[1])) / ELECTRON_REST_ENERGY;
+    let (out_energy, kept) = kinematics(into, out, electron, gamma, seed);
+    if rng.f64() * WEIGHT_BOUND >= kept {
+        return None;
+    }
+
+    // Momentum before, with unit energy in the frame of the zero angular momentum observer
+    let before: Vec4 = std::array::from_fn(|mu| {
+        tetrad[0][mu] + into[0] * tetrad[1][mu] + into[1] * tetrad[2][mu] + into[2] * tetrad[3][mu]
+    });
+    // Energy at infinity goes as p_t, so compare the photon's p_t afterwards and before
+    let shift = (out_energy / energy * lower[0]) / (seed * matvecmul(g, before)[0]);
+    Some((shift, weight, before))
+}
+
+/// Energy afterwards, in units of m_e c^2, of a photon with energy `seed` going along `into`
+/// that an electron going along `electron` with Lorentz factor `gamma` scatters along `out`, and
+/// the weight (1 - β·n_in) 4π/σ_T dσ/dΩ' of the scattering, which is at most WEIGHT_BOUND.
+fn kinematics(into: Vec3, out: Vec3, electron: Vec3, gamma: f64, seed: f64) -> (f64, f64) {
+    let beta = (1.0 - 1.0 / (gamma * gamma)).max(0.0).sqrt();
+    let approach = 1.0 - beta * dot3(electron, into);
+    let rest_in = gamma * seed * approach;
+    let (into_rest, out_rest) = (aberrate(into, electron, gamma, beta), aberrate(out, electron, gamma, beta));
+    let scattering = dot3(into_rest, out_rest).clamp(-1.0, 1.0);
+    let rest_out = rest_in / (1.0 + rest_in * (1.0 - scattering));
+
+    // 4π/σ_T dσ/dΩ' = 3/4 P^2 (P + 1/P - sin^2), with P the ratio of the energies
+    let ratio = rest_out / rest_in;
+    let weight = approach * 0.75 * ratio * ratio * (ratio + 1.0 / ratio - (1.0 - scattering * scattering));
+    (gamma * rest_out * (1.0 + beta * dot3(electron, out_rest)), weight)
+}
+
+/// Draw the direction a photon scattered at `pos` travelled in before, in the frame of the
+/// tetrad there. Most draws aim, along straight lines in that frame, at points on the disk
+/// between SEED_INNER and SEED_OUTER spread as r^-2, and the rest are uniform, which covers light
+/// bent onto the corona from anywhere and keeps the weight at most 1 / (1 - DISK_SHARE). Returns
+/// the direction, its weight, the uniform density over the density it was drawn with, and the
+/// radius where the straight line back meets the disk, if it does.
+fn draw_into(pos: Vec4, rng: &fastrand::Rng) -> (Vec3, f64, Option<f64>) {
+    use std::f64::consts::PI;
+    // Cylindrical radius, azimuth and height, with the azimuth along the tetrad's φ
+    let (sine, cosine) = pos[2].sin_cos();
+    let (cylinder, height) = (pos[1] * sine, pos[1] * cosine);
+    let aims = height.abs() > MIN_HEIGHT;
+    let source = if aims && rng.f64() < DISK_SHARE {
+        let radius = SEED_INNER * (SEED_OUTER / SEED_INNER).powf(rng.f64());
+        let azimuth = 2.0 * PI * rng.f64();
+        normalize([radius * azimuth.cos() - cylinder, radius * azimuth.sin(), -height])
+    } else {
+        random_direction(rng)
+    };
+
+    let landing = if source[2] * height < 0.0 {
+        let distance = -height / source[2];
+        Some(((cylinder + distance * source[0]).hypot(distance * source[1]), distance))
+    } else {
+        None
+    }.filter(|&(radius, _)| (SEED_INNER..=SEED_OUTER).contains(&radius));
+    // Density of aiming along `source`: that of the point on the disk, over the solid angle
+    // a unit of its area takes up
+    let aimed = match landing {
+        Some((radius, distance)) if aims => {
+            distance * distance / (source[2].abs() * 2.0 * PI * radius * radius * (SEED_OUTER / SEED_INNER).ln())
+        },
+        _ => 0.0,
+    };
+    let density = if aims { DISK_SHARE * aimed + (1.0 - DISK_SHARE) / (4.0 * PI) } else { 1.0 / (4.0 * PI) };
+
+    // The photon travelled away from the source, here along the tetrad's r, θ and φ
+    let into = [
+        -(sine * source[0] + cosine * source[2]),
+        -(cosine * source[0] - sine * source[2]),
+        -source[1],
+    ];
+    (into, 1.0 / (4.0 * PI * density), landing.map(|(radius, _)| radius))
+}
+
+/// Direction of a photon going along `direction` in the lab, seen by an electron moving along
+/// `electron` with Lorentz factor `gamma` and speed `beta`.
+fn aberrate(direction: Vec3, electron: Vec3, gamma: f64, beta: f64) -> Vec3 {
+    let cosine = dot3(direction, electron);
+    let along = (gamma - 1.0) * cosine - gamma * beta;
+    let scale = 1.0 / (gamma * (1.0 - beta * cosine));
+    std::array::from_fn(|i| (direction[i] + along * electron[i]) * scale)
+}
+
+/// Unit vector at `cosine` to `axis`, turned by `azimuth` around it.
+fn rotate_from(axis: Vec3, cosine: f64, azimuth: f64) -> Vec3 {
+    let helper = if axis[0].abs() < 0.9 { [1.0, 0.0, 0.0] } else { [0.0, 1.0, 0.0] };
+    let first = normalize(cross(axis, helper));
+    let second = cross(axis, first);
+    let sine = (1.0 - cosine * cosine).max(0.0).sqrt();
+    std::array::from_fn(|i| cosine * axis[i] + sine * (azimuth.cos() * first[i] + azimuth.sin() * second[i]))
+}
+
+fn random_direction(rng: &fastrand::Rng) -> Vec3 {
+    rotate_from([0.0, 0.0, 1.0], 2.0 * rng.f64() - 1.0, 2.0 * std::f64::consts::PI * rng.f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRAWS: usize = 200_000;
+
+    /// Flat space in spherical coordinates at radius `r` and polar angle `theta`.
+    fn flat(r: f64, theta: f64) -> Matrix4 {
+        let mut g = [0.0; 16];
+        g[0] = -1.0;
+        g[5] = 1.0;
+        g[10] = r * r;
+        g[15] = (r * theta.sin()).powi(2);
+        g
+    }
+
+    /// σ/σ_T for a photon of energy `x` in units of m_e c^2 hitting an electron at rest.
+    fn klein_nishina(x: f64) -> f64 {
+        let log = (1.0 + 2.0 * x).ln();
+        0.75 * ((1.0 + x) / (x * x * x) * (2.0 * x * (1.0 + x) / (1.0 + 2.0 * x) - log)
+            + log / (2.0 * x) - (1.0 + 3.0 * x) / ((1.0 + 2.0 * x) * (1.0 + 2.0 * x)))
+    }
+
+    #[test]
+    fn cross_section_is_klein_nishina_and_thomson_at_low_energy() {
+        let rng = fastrand::Rng::with_seed(1);
+        for (x, expected) in [(1e-6, 1.0), (1.0, klein_nishina(1.0)), (10.0, klein_nishina(10.0))] {
+            let mean = (0..DRAWS)
+                .map(|_| kinematics(random_direction(&rng), random_direction(&rng), [0.0, 0.0, 1.0], 1.0, x).1)
+                .sum::<f64>() / DRAWS as f64;
+            assert!((mean / expected - 1.0).abs() < 0.01, "σ/σ_T at x = {}: {} against {}", x, mean, expected);
+        }
+    }
+
+    #[test]
+    fn mean_gain_is_thomson_at_low_energy() {
+        let rng = fastrand::Rng::with_seed(2);
+        let (r, theta) = (30.0, 1.0);
+        let g = flat(r, theta);
+        let vel = [1.0, 1.0, 0.0, 0.0];
+        for gamma in [1.5, 5.0] {
+            let (mut light, mut count) = (0.0, 0.0);
+            for _ in 0..DRAWS {
+                if let Some((shift, weight, _)) = scatter(&g, [0.0, r, theta, 0.0], vel, gamma, |_| 1e-3, &rng) {
+                    light += shift * weight;
+                    count += weight;
+                }
+            }
+            let expected = 1.0 + 4.0 / 3.0 * (gamma * gamma - 1.0);
+            assert!((light / count / expected - 1.0).abs() < 0.03, "gain at γ = {}: {} against {}", gamma, light / count, expected);
+        }
+
+        for theta in [0.01, 1.0, 100.0] {
+            let draws: f64 = (0..DRAWS).map(|_| maxwell_juttner(theta, &rng).powi(2) - 1.0).sum();
+            let expected = 1.0 + 4.0 / 3.0 * draws / DRAWS as f64;
+            let gain = Electrons::Thermal { temperature: Some(theta) }.mean_gain(theta);
+            assert!((gain / expected - 1.0).abs() < 0.02, "thermal gain at Θ = {}: {} against {}", theta, gain, expected);
+        }
+    }
+
+    #[test]
+    fn weights_are_bounded() {
+        let rng = fastrand::Rng::with_seed(3);
+        for _ in 0..DRAWS {
+            let gamma = 10f64.powf(4.0 * rng.f64());
+            let seed = 10f64.powf(10.0 * rng.f64() - 8.0);
+            let (_, weight) = kinematics(random_direction(&rng), random_direction(&rng), random_direction(&rng), gamma, seed);
+            assert!(weight <= WEIGHT_BOUND * (1.0 + 1e-12), "weight {} at γ = {}, seed {}", weight, gamma, seed);
+
+            let pos = [0.0, 1.0 + 99.0 * rng.f64(), std::f64::consts::PI * rng.f64(), 0.0];
+            let (into, weight, _) = draw_into(pos, &rng);
+            assert!((length(into) - 1.0).abs() < 1e-9);
+            assert!(weight <= (1.0 + 1e-12) / (1.0 - DISK_SHARE), "weight {} of a direction at {:?}", weight, pos);
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use std::fs;
+use std::fmt::Write as _;
+use rayon::prelude::*;
+
+use crate::util::*;
+use crate::observer::{Photon, Termination};
+use crate::metrics::{Metric, SPACETIME_EDGE};
+use crate::source::{AccretionDisk, CORONA_DENSITY_HEIGHT, CORONA_ELECTRON_TEMPERATURE, CORONA_OPACITY};
+use crate::compton::ELECTRON_REST_ENERGY;
+use crate::disk;
+use crate::error::{Error, Result};
+
+// The corona, as a lamp post that lights the disk or as a cloud of electrons that scatters its
+// light.
+//
+// A lamp-post corona: a point source at rest on the spin axis at height h, shining a power law
+// of photon index Γ equally in all directions of its own frame. Rays are traced from the lamp
+// until they hit the disk, escape or fall in. Each annulus of the disk is lit in proportion to
+// Σ g^Γ / (A γ) (Dauser et al. 2013), where g is the energy a photon arrives with over the
+// energy it left with, A the proper area of the annulus and γ the Lorentz factor of the gas
+// relative to the zero angular momentum observers. The reflection fraction is the number of
+// photons that hit the disk over the number that escape (Dauser et al. 2016). Only the pattern of
+// the illumination is used: the disk lit by it still shines as a blackbody at its own
+// temperatures, with no reflection spectrum.
+//
+// The integrator runs rays backwards, as it does for the camera. A ray launched from the lamp
+// with its spatial momentum reversed therefore follows the forward path of the lamp's photon
+// mirrored in φ, which changes nothing for a source on the axis and gas on circular orbits.
+
+const AXIS_OFFSET: f64 = 1e-3; // Polar angle of the lamp, to stay off the coordinate singularity
+const EMISSIVITY_BINS: usize = 100; // Annuli, evenly spaced in log r
+pub const CORONA_DEPTH: f64 = 0.01; // Thomson depth of the uniform shapes by default, about that of the original
+/// First line of a corona table, naming the format and its version
+const CORONA_TABLE_HEADER: &str = "# corona table v1";
+/// Names of the columns of a corona table, the last of which may be left out
+const CORONA_TABLE_COLUMNS: [&str; 4] = ["radius", "height", "density", "kt_kev"];
+
+#[derive(Clone, Debug)]
+pub struct LampPost {
+    height: f64,
+    photon_index: f64,
+    inner: f64,
+    outer: f64,
+    /// Centres of the annuli, and the illumination of each per unit proper area
+    radii: Vec<f64>,
+    emissivity: Vec<f64>,
+    /// Rays that hit the disk, escaped, and fell in or were lost
+    hit: usize,
+    escaped: usize,
+    lost: usize,
+}
+
+impl LampPost {
+    /// Trace `rays` rays from a lamp at `height` with photon index `photon_index` onto the disk
+    /// of `source` between its ISCO and the edge of the scene. The emissivity is scaled so that
+    /// the disk gives out as much light in all as the r^-2 disk of the original model. Each ray
+    /// draws its random numbers from `seed` and its index, so the illumination is reproducible.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<M: Metric>(metric: &M, source: &AccretionDisk, height: f64, photon_index: f64, rays: usize,
+        max_iterations: usize, dtau: f64, seed: u64) -> Result<Self> {
+        if !(height > metric.get_horizon() && height < SPACETIME_EDGE) {
+            return Err(Error::InvalidScene(format!("lamp post must be between the horizon and the edge of the scene, not at {}", height)));
+        }
+        if !photon_index.is_finite() {
+            return Err(Error::InvalidScene(format!("photon index must be a number, not {}", photon_index)));
+        }
+        if rays == 0 {
+            return Err(Error::InvalidScene("lamp post needs at least one ray".to_owned()));
+        }
+        let inner = disk::isco(metric)?;
+        let outer = SPACETIME_EDGE;
+        let source = source.clone().without_corona();
+        let pos = [0.0, height, AXIS_OFFSET, 0.0];
+        let g = metric.get_metric(pos);
+        let log_width = (outer / inner).ln() / EMISSIVITY_BINS as f64;
+
+        // Directions evenly spaced in cos α, α measured from the axis, so each ray stands for
+        // the same solid angle
+        let ends: Vec<Option<(usize, f64)>> = (0..rays).into_par_iter().map(|k| {
+            let cos_alpha = 1.0 - 2.0 * (k as f64 + 0.5) / rays as f64;
+            let sin_alpha = (1.0 - cos_alpha * cos_alpha).sqrt();
+            let direction = [-cos_alpha / g[5].sqrt(), -sin_alpha / g[10].sqrt(), 0.0];
+            let photon = Photon::new(pos, (0, 0), get_vel_from_metric(direction, &g), fastrand::Rng::with_seed(mix_seed(seed, k as u64)));
+            let data = photon.run(max_iterations, dtau, metric, &source);
+            if data.failure.is_some_and(|f| f.quarantined()) {
+                return None;
+            }
+            match data.crossings.iter().find(|c| c.radius >= inner && c.radius <= outer) {
+                Some(c) => {
+                    // The shift is to the lamp from the gas, so the gas sees 1 / shift
+                    let bin = (((c.radius / inner).ln() / log_width) as usize).min(EMISSIVITY_BINS - 1);
+                    Some((bin, c.shift.recip().powf(photon_index)))
+                },
+                None if data.termination == Termination::Escape => Some((EMISSIVITY_BINS, 0.0)),
+                None => None,
+            }
+        }).collect();
+
+        let mut photons = vec![0.0; EMISSIVITY_BINS];
+        let (mut hit, mut escaped) = (0, 0);
+        for (bin, weight) in ends.iter().flatten() {
+            if *bin == EMISSIVITY_BINS {
+                escaped += 1;
+            } else {
+                photons[*bin] += weight;
+                hit += 1;
+            }
+        }
+        let radii: Vec<f64> = (0..EMISSIVITY_BINS).map(|k| inner * ((k as f64 + 0.5) * log_width).exp()).collect();
+        let areas: Vec<f64> = radii.iter().map(|&r| {
+            let g = disk::equatorial_metric(metric, r);
+            2.0 * std::f64::consts::PI * (g[5] * g[15]).sqrt() * r * log_width
+        }).collect();
+        let mut emissivity: Vec<f64> = radii.iter().zip(&photons).zip(&areas).map(|((&r, n), a)| {
+            zamo_gamma(metric, &source, r).map_or(0.0, |gamma| n / (a * gamma))
+        }).collect();
+        // As much light in all as the r^-2 disk over the same annuli
+        let total: f64 = emissivity.iter().zip(&areas).map(|(e, a)| e * a).sum();
+        let target: f64 = radii.iter().zip(&areas).map(|(r, a)| a / (r * r)).sum();
+        if total > 0.0 {
+            emissivity.iter_mut().for_each(|e| *e *= target / total);
+        }
+        Ok(Self { height, photon_index, inner, outer, radii, emissivity, hit, escaped, lost: rays - hit - escaped })
+    }
+
+    pub fn height(&self) -> f64 { self.height }
+
+    /// Photons that hit the disk over photons that escape, or `None` if none escape, as for a
+    /// lamp low enough that all its light falls in or lands on the disk.
+    pub fn reflection_fraction(&self) -> Option<f64> {
+        (self.escaped > 0).then(|| self.hit as f64 / self.escaped as f64)
+    }
+
+    /// Fractions of the lamp's photons that hit the disk, escape, and fall in or are lost.
+    pub fn fractions(&self) -> (f64, f64, f64) {
+        let rays = (self.hit + self.escaped + self.lost) as f64;
+        (self.hit as f64 / rays, self.escaped as f64 / rays, self.lost as f64 / rays)
+    }
+
+    /// Illumination at radius `r`, in the units of the disk's luminosity, or `None` off the
+    /// disk. Interpolated linearly in log r between the centres of the annuli.
+    pub fn emissivity(&self, r: f64) -> Option<f64> {
+        if !(r >= self.inner && r <= self.outer) {
+            return None;
+        }
+        let log_width = (self.outer / self.inner).ln() / EMISSIVITY_BINS as f64;
+        let x = ((r / self.inner).ln() / log_width - 0.5).clamp(0.0, (EMISSIVITY_BINS - 1) as f64);
+        let k = (x as usize).min(EMISSIVITY_BINS - 2);
+        let f = x - k as f64;
+        Some(self.emissivity[k] * (1.0 - f) + self.emissivity[k + 1] * f)
+    }
+
+    /// The reflection fraction is left out when no photon escaped.
+    pub fn parameters(&self) -> Vec<(&'static str, f64)> {
+        let mut parameters = vec![
+            ("lamp_height", self.height),
+            ("lamp_photon_index", self.photon_index),
+            ("lamp_inner", self.inner),
+            ("lamp_rays", (self.hit + self.escaped + self.lost) as f64),
+        ];
+        if let Some(fraction) = self.reflection_fraction() {
+            parameters.push(("reflection_fraction", fraction));
+        }
+        parameters
+    }
+
+    /// Write `<base>-emissivity.csv`, with the radius of each annulus and its illumination.
+    pub fn write(&self, base: &str) -> Result<()> {
+        let mut csv = "radius,emissivity\n".to_owned();
+        for (r, e) in self.radii.iter().zip(&self.emissivity) {
+            writeln!(csv, "{},{}", r, e).unwrap();
+        }
+        fs::write(format!("{}-emissivity.csv", base), csv)?;
+        Ok(())
+    }
+}
+
+/// Lorentz factor of the gas at radius `r` relative to the zero angular momentum observer
+/// there, -u·n = u^t sqrt((g_tφ^2 - g_tt g_φφ) / g_φφ). None where the gas has no 4-velocity.
+fn zamo_gamma<M: Metric>(metric: &M, source: &AccretionDisk, r: f64) -> Option<f64> {
+    let g = disk::equatorial_metric(metric, r);
+    let velocity = source.gas_velocity([0.0, r, std::f64::consts::FRAC_PI_2, 0.0], metric)?;
+    Some(velocity[0] * ((g[3] * g[3] - g[0] * g[15]) / g[15]).sqrt())
+}
+
+/// Where the corona's electrons are and how hot they are. Densities are in units of
+/// `CORONA_DENSITY_SCALE`. The uniform shapes are made from their Thomson optical depth, and
+/// their electrons all have the temperature `CORONA_ELECTRON_TEMPERATURE`.
+#[derive(Clone, Debug)]
+pub enum CoronaModel {
+    /// The original profile, 1 + s + s^2/2 with s = CORONA_DENSITY_HEIGHT / r, filling the scene
+    Original,
+    /// A sandwich: uniform layers `height` thick on both faces of the disk, out to cylindrical
+    /// radius `outer`
+    Slab { height: f64, outer: f64, density: f64 },
+    /// A uniform ball of radius `radius` around the hole
+    Sphere { radius: f64, density: f64 },
+    /// Uniform within `opening` radians of the plane of the disk, out to radius `outer`
+    Wedge { opening: f64, outer: f64, density: f64 },
+    /// An extended lamp post: a uniform cylinder of radius `radius` along the spin axis, from
+    /// height `base` to `top` on both sides of the disk
+    Column { base: f64, top: f64, radius: f64, density: f64 },
+    /// Density and electron temperature given on a grid
+    Table(CoronaTable),
+}
+
+impl CoronaModel {
+    /// A slab with Thomson depth `depth` from the disk to its top.
+    pub fn slab(height: f64, outer: f64, depth: f64) -> Result<Self> {
+        positive(&[("slab height", height), ("slab radius", outer)])?;
+        Ok(CoronaModel::Slab { height, outer, density: density_for(depth, height)? })
+    }
+
+    /// A sphere with Thomson depth `depth` from the centre to its edge.
+    pub fn sphere(radius: f64, depth: f64) -> Result<Self> {
+        positive(&[("sphere radius", radius)])?;
+        Ok(CoronaModel::Sphere { radius, density: density_for(depth, radius)? })
+    }
+
+    /// A wedge `opening` radians either side of the disk with Thomson depth `depth` along the
+    /// plane of the disk, from the centre to `outer`.
+    pub fn wedge(opening: f64, outer: f64, depth: f64) -> Result<Self> {
+        positive(&[("wedge opening", opening), ("wedge radius", outer)])?;
+        if opening > std::f64::consts::FRAC_PI_2 {
+            return Err(Error::InvalidScene(format!("wedge opening must be at most 90 degrees, not {}", opening.to_degrees())));
+        }
+        Ok(CoronaModel::Wedge { opening, outer, density: density_for(depth, outer)? })
+    }
+
+    /// A column with Thomson depth `depth` from the axis to its side.
+    pub fn column(base: f64, top: f64, radius: f64, depth: f64) -> Result<Self> {
+        positive(&[("column top", top), ("column radius", radius)])?;
+        if !(base >= 0.0 && base < top) {
+            return Err(Error::InvalidScene(format!("column base must be between 0 and its top {}, not {}", top, base)));
+        }
+        Ok(CoronaModel::Column { base, top, radius, density: density_for(depth, radius)? })
+    }
+
+    /// Parse a corona from the command line: `original`, `slab,HEIGHT,OUTER[,DEPTH]`,
+    /// `sphere,RADIUS[,DEPTH]`, `wedge,DEGREES,OUTER[,DEPTH]`,
+    /// `column,BASE,TOP,RADIUS[,DEPTH]`, or `file:PATH` for a table.
+    pub fn named(spec: &str) -> Result<Self> {
+        if let Some(path) = spec.strip_prefix("file:") {
+            return Ok(CoronaModel::Table(CoronaTable::load(path)?));
+        }
+        let mut parts = spec.split(',');
+        let shape = parts.next().unwrap_or_default();
+        let arity = match shape {
+            "original" => 0,
+            "sphere" => 1,
+            "slab" | "wedge" => 2,
+            "column" => 3,
+            _ => return Err(Error::InvalidScene(format!(
+                "unknown corona {}: use original, slab, sphere, wedge, column or file:PATH", shape
+            ))),
+        };
+        let values: Vec<f64> = parts.map(|s| s.parse()).collect::<std::result::Result<_, _>>()
+            .map_err(|_| Error::InvalidScene(format!("corona {} has a value that is not a number", spec)))?;
+        if values.len() != arity && values.len() != arity + 1 {
+            return Err(Error::InvalidScene(format!("corona {} needs {} numbers and optionally a depth", shape, arity)));
+        }
+        let depth = values.get(arity).copied().unwrap_or(CORONA_DEPTH);
+        match shape {
+            "original" => Ok(CoronaModel::Original),
+            "slab" => Self::slab(values[0], values[1], depth),
+            "sphere" => Self::sphere(values[0], depth),
+            "wedge" => Self::wedge(values[0].to_radians(), values[1], depth),
+            _ => Self::column(values[0], values[1], values[2], depth),
+        }
+    }
+
+    /// Electron density at `pos`, in units of `CORONA_DENSITY_SCALE`.
+    pub fn density(&self, pos: Vec4) -> f64 {
+        let (r, theta) = (pos[1], pos[2]);
+        let (cylinder, height) = (r * theta.sin().abs(), (r * theta.cos()).abs());
+        match self {
+            CoronaModel::Original => {
+                let scale_factor = CORONA_DENSITY_HEIGHT / r;
+                1.0 + scale_factor + scale_factor * scale_factor * 0.5
+            },
+            CoronaModel::Slab { height: top, outer, density } => inside(height <= *top && cylinder <= *outer, *density),
+            CoronaModel::Sphere { radius, density } => inside(r <= *radius, *density),
+            CoronaModel::Wedge { opening, outer, density } => {
+                inside((std::f64::consts::FRAC_PI_2 - theta).abs() <= *opening && r <= *outer, *density)
+            },
+            CoronaModel::Column { base, top, radius, density } => {
+                inside(height >= *base && height <= *top && cylinder <= *radius, *density)
+            },
+            CoronaModel::Table(table) => table.at(cylinder, height).0,
+        }
+    }
+
+    /// Temperature Θ = kT / m_e c^2 of the electrons at `pos`.
+    pub fn electron_temperature(&self, pos: Vec4) -> f64 {
+        match self {
+            CoronaModel::Table(table) => table.at(pos[1] * pos[2].sin().abs(), (pos[1] * pos[2].cos()).abs()).1,
+            _ => CORONA_ELECTRON_TEMPERATURE,
+        }
+    }
+
+    /// Temperature Θ of the electrons, averaged over a table weighted by density.
+    pub fn mean_electron_temperature(&self) -> f64 {
+        match self {
+            CoronaModel::Table(table) => table.mean_temperature(),
+            _ => CORONA_ELECTRON_TEMPERATURE,
+        }
+    }
+
+    /// Parameters of the shape, for recording alongside the output.
+    pub fn parameters(&self) -> Vec<(&'static str, f64)> {
+        match self {
+            CoronaModel::Original => vec![("corona_model", 0.0)],
+            CoronaModel::Slab { height, outer, density } => vec![
+                ("corona_model", 1.0), ("corona_height", *height), ("corona_outer", *outer), ("corona_density", *density),
+            ],
+            CoronaModel::Sphere { radius, density } => vec![
+                ("corona_model", 2.0), ("corona_radius", *radius), ("corona_density", *density),
+            ],
+            CoronaModel::Wedge { opening, outer, density } => vec![
+                ("corona_model", 3.0), ("corona_opening", *opening), ("corona_outer", *outer), ("corona_density", *density),
+            ],
+            CoronaModel::Column { base, top, radius, density } => vec![
+                ("corona_model", 4.0), ("corona_base", *base), ("corona_top", *top), ("corona_radius", *radius),
+                ("corona_density", *density),
+            ],
+            CoronaModel::Table(_) => vec![("corona_model", 5.0)],
+        }
+    }
+
+    /// The table the corona was read from, if any. The other shapes are given in full by
+    /// `parameters`.
+    pub fn table(&self) -> Option<&CoronaTable> {
+        match self {
+            CoronaModel::Table(table) => Some(table),
+            _ => None,
+        }
+    }
+}
+
+/// Electron density and temperature on a regular grid in cylindrical radius R and height |z|
+/// above the disk. Interpolated bilinearly, with no corona outside the grid.
+#[derive(Clone, Debug)]
+pub struct CoronaTable {
+    name: String,
+    radii: Vec<f64>,
+    heights: Vec<f64>,
+    /// (density, temperature Θ) at each radius, then each height
+    values: Vec<(f64, f64)>,
+    hash: u64,
+}
+
+impl CoronaTable {
+    /// Read a table whose first line is `CORONA_TABLE_HEADER` and whose next names the columns,
+    /// `radius,height,density` or `radius,height,density,kt_kev`, followed by one grid point per
+    /// line: R, z, density in units of `CORONA_DENSITY_SCALE` and, with the fourth column, the
+    /// electrons' temperature kT in keV, which is `CORONA_ELECTRON_TEMPERATURE` otherwise.
+    /// Values are separated by commas or spaces, and lines starting with `#` are skipped.
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.lines().enumerate()
+            .map(|(n, line)| (n, line.trim()))
+            .filter(|(_, line)| !line.is_empty());
+        if lines.next().map(|(_, line)| line) != Some(CORONA_TABLE_HEADER) {
+            return Err(Error::Format(format!("{}: must start with the line {}", path, CORONA_TABLE_HEADER)));
+        }
+        let columns = match lines.next().map(|(_, line)| split(line).collect::<Vec<_>>()) {
+            Some(names) if names == CORONA_TABLE_COLUMNS[..3] || names == CORONA_TABLE_COLUMNS => names.len(),
+            _ => return Err(Error::Format(format!(
+                "{} line 2: must name the columns {} with an optional {}", path, CORONA_TABLE_COLUMNS[..3].join(","), CORONA_TABLE_COLUMNS[3]
+            ))),
+        };
+        let mut points = Vec::new();
+        for (n, line) in lines {
+            if line.starts_with('#') {
+                continue;
+            }
+            let values: Vec<f64> = split(line)
+                .map(|s| s.parse())
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| Error::Format(format!("{} line {}: not a number", path, n + 1)))?;
+            if values.len() != columns {
+                return Err(Error::Format(format!("{} line {}: needs {} values, one per column", path, n + 1, columns)));
+            }
+            let temperature = values.get(3).map_or(CORONA_ELECTRON_TEMPERATURE, |kev| 1000.0 * kev / ELECTRON_REST_ENERGY);
+            if !(values[2] >= 0.0 && temperature > 0.0) {
+                return Err(Error::Format(format!("{} line {}: density must be at least 0 and temperature above 0", path, n + 1)));
+            }
+            points.push((values[0], values[1], values[2], temperature));
+        }
+        let axis = |pick: fn(&(f64, f64, f64, f64)) -> f64| {
+            let mut axis: Vec<f64> = points.iter().map(pick).collect();
+            axis.sort_by(f64::total_cmp);
+            axis.dedup();
+            axis
+        };
+        let (radii, heights) = (axis(|p| p.0), axis(|p| p.1));
+        if radii.len() < 2 || heights.len() < 2 || radii.len() * heights.len() != points.len() {
+            return Err(Error::Format(format!("{}: points must fill a grid at least 2 by 2, with each once", path)));
+        }
+        let mut values = vec![None; points.len()];
+        for &(r, z, density, temperature) in &points {
+            let index = radii.partition_point(|&x| x < r) * heights.len() + heights.partition_point(|&x| x < z);
+            if values[index].replace((density, temperature)).is_some() {
+                return Err(Error::Format(format!("{}: point R = {}, z = {} is given twice", path, r, z)));
+            }
+        }
+        let values: Vec<(f64, f64)> = values.into_iter().flatten().collect();
+        let hash = hash_str(&format!("{:?}{:?}{:?}", radii, heights, values));
+        let name = std::path::Path::new(path).file_name().map_or(path.into(), |s| s.to_string_lossy()).into_owned();
+        Ok(Self { name, radii, heights, values, hash })
+    }
+
+    /// Name of the file the table was read from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Hash of the grid and its values in hexadecimal, for telling tables apart.
+    pub fn hash(&self) -> String {
+        format!("{:016x}", self.hash)
+    }
+
+    /// Temperature averaged over the grid points, weighted by their density, or
+    /// `CORONA_ELECTRON_TEMPERATURE` if it holds no electrons.
+    fn mean_temperature(&self) -> f64 {
+        let (mass, heat) = self.values.iter().fold((0.0, 0.0), |(m, h), (density, temperature)| (m + density, h + density * temperature));
+        if mass > 0.0 { heat / mass } else { CORONA_ELECTRON_TEMPERATURE }
+    }
+
+    /// Density and temperature at cylindrical radius `cylinder` and height `height`.
+    fn at(&self, cylinder: f64, height: f64) -> (f64, f64) {
+        let (Some((i, x)), Some((j, y))) = (cell(&self.radii, cylinder), cell(&self.heights, height)) else {
+            return (0.0, CORONA_ELECTRON_TEMPERATURE);
+        };
+        let value = |i: usize, j: usize| self.values[i * self.heights.len() + j];
+        let corners = [(value(i, j), (1.0 - x) * (1.0 - y)), (value(i + 1, j), x * (1.0 - y)),
+            (value(i, j + 1), (1.0 - x) * y), (value(i + 1, j + 1), x * y)];
+        corners.iter().fold((0.0, 0.0), |(d, t), ((density, temperature), w)| (d + w * density, t + w * temperature))
+    }
+}
+
+/// The fields of a line of a table, separated by commas or spaces.
+fn split(line: &str) -> impl Iterator<Item = &str> {
+    line.split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty())
+}
+
+/// Index of the cell of `axis` that holds `x`, and how far across it `x` is.
+fn cell(axis: &[f64], x: f64) -> Option<(usize, f64)> {
+    if !(x >= axis[0] && x <= axis[axis.len() - 1]) {
+        return None;
+    }
+    let i = (axis.partition_point(|&a| a <= x) - 1).min(axis.len() - 2);
+    Some((i, (x - axis[i]) / (axis[i + 1] - axis[i])))
+}
+
+fn inside(inside: bool, density: f64) -> f64 {
+    if inside { density } else { 0.0 }
+}
+
+/// Uniform density, in units of `CORONA_DENSITY_SCALE`, for Thomson depth `depth` over
+/// `length`.
+fn density_for(depth: f64, length: f64) -> Result<f64> {
+    if !(depth >= 0.0 && depth.is_finite()) {
+        return Err(Error::InvalidScene(format!("corona depth must be at least 0, not {}", depth)));
+    }
+    Ok(depth / (CORONA_OPACITY * length))
+}
+
+fn positive(values: &[(&str, f64)]) -> Result<()> {
+    match values.iter().find(|(_, v)| !(*v > 0.0 && v.is_finite())) {
+        Some((name, value)) => Err(Error::InvalidScene(format!("{} must be above 0, not {}", name, value))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lamp(hit: usize, escaped: usize) -> LampPost {
+        LampPost {
+            height: 1.5, photon_index: 2.0, inner: 3.0, outer: 100.0,
+            radii: vec![10.0; EMISSIVITY_BINS], emissivity: vec![1.0; EMISSIVITY_BINS],
+            hit, escaped, lost: 10,
+        }
+    }
+
+    #[test]
+    fn reflection_fraction_needs_escaped_photons() {
+        assert_eq!(lamp(30, 20).reflection_fraction(), Some(1.5));
+        assert!(lamp(30, 20).parameters().contains(&("reflection_fraction", 1.5)));
+        let trapped = lamp(30, 0);
+        assert_eq!(trapped.reflection_fraction(), None);
+        assert!(trapped.parameters().iter().all(|(name, value)| *name != "reflection_fraction" && value.is_finite()));
+    }
+}
@@ -0,0 +1,342 @@
+use std::f64::consts::{PI, FRAC_PI_2};
+
+use crate::util::*;
+use crate::metrics::{Metric, SPACETIME_EDGE};
+use crate::error::{Error, Result};
+use crate::spectrum::BOLTZMANN_EV;
+
+// Gas in the equatorial plane going round on circular orbits, the Novikov–Thorne thin disk made
+// of it, with the flux profile of Page & Thorne (1974), and the gas plunging from its inner edge
+// into the hole. Everything is worked out from the metric alone: Ω from the radial derivatives
+// of g, the specific energy E = -u_t and angular momentum L = u_φ from u, and the innermost
+// stable circular orbit (ISCO) as the minimum of E. Orbits turn towards increasing φ, the way
+// `Kerr` spins for a positive spin, so they are prograde. Lengths are in Schwarzschild radii;
+// physical units appear only through the mass and accretion rate.
+
+const DERIVATIVE_STEP: f64 = 1e-4; // Relative step in r for radial derivatives
+const ISCO_SEARCH_POINTS: usize = 2000;
+const ISCO_REFINEMENTS: usize = 60;
+const MIN_RADIUS: f64 = 1e-2; // Inner end of the search for metrics without a horizon
+const HORIZON_MARGIN: f64 = 1e-3;
+const TABLE_SIZE: usize = 1000; // Radii at which the flux is tabulated, evenly spaced in log r
+
+/// Schwarzschild radius of one solar mass, in centimetres
+const SOLAR_SCHWARZSCHILD_RADIUS: f64 = 2.95325e5;
+const SOLAR_MASS: f64 = 1.98841e33; // Grams
+const YEAR: f64 = 3.15576e7; // Julian year in seconds
+const SPEED_OF_LIGHT: f64 = 2.99792458e10; // Centimetres per second
+const STEFAN_BOLTZMANN: f64 = 5.670374e-5; // erg s^-1 cm^-2 K^-4
+
+/// Gas moving round the hole in the equatorial plane with a fixed angular velocity.
+#[derive(Clone, Copy, Debug)]
+pub struct CircularMotion {
+    /// Ω = dφ/dt
+    pub angular_velocity: f64,
+    /// E = -u_t
+    pub energy: f64,
+    /// L = u_φ
+    pub angular_momentum: f64,
+    /// u = u^t (1, 0, 0, Ω)
+    pub velocity: Vec4,
+}
+
+/// The metric at radius `r` in the equatorial plane.
+pub fn equatorial_metric<M: Metric>(metric: &M, r: f64) -> Matrix4 {
+    metric.get_metric([0.0, r, FRAC_PI_2, 0.0])
+}
+
+/// Gas at radius `r` going round with angular velocity `angular_velocity`, or `None` if that
+/// would be faster than light.
+pub fn circular_motion<M: Metric>(metric: &M, r: f64, angular_velocity: f64) -> Option<CircularMotion> {
+    let g = equatorial_metric(metric, r);
+    let norm = -(g[0] + 2.0 * angular_velocity * g[3] + angular_velocity * angular_velocity * g[15]);
+    if norm.is_nan() || norm <= 0.0 {
+        return None;
+    }
+    let time = 1.0 / norm.sqrt();
+    Some(CircularMotion {
+        angular_velocity,
+        energy: -(g[0] + g[3] * angular_velocity) * time,
+        angular_momentum: (g[3] + g[15] * angular_velocity) * time,
+        velocity: [time, 0.0, 0.0, angular_velocity * time],
+    })
+}
+
+/// The circular geodesic at radius `r`, or `None` inside the photon orbit, where there is none.
+/// Ω = (-∂g_tφ + sqrt(∂g_tφ^2 - ∂g_tt ∂g_φφ)) / ∂g_φφ, with ∂ = ∂/∂r.
+pub fn circular_orbit<M: Metric>(metric: &M, r: f64) -> Option<CircularMotion> {
+    let h = DERIVATIVE_STEP * r;
+    let (above, below) = (equatorial_metric(metric, r + h), equatorial_metric(metric, r - h));
+    let d = |k: usize| (above[k] - below[k]) / (2.0 * h);
+    let discriminant = d(3) * d(3) - d(0) * d(15);
+    if discriminant < 0.0 {
+        return None;
+    }
+    circular_motion(metric, r, (-d(3) + discriminant.sqrt()) / d(15))
+}
+
+/// Radius of the innermost stable circular orbit, where the energy of circular orbits is least.
+pub fn isco<M: Metric>(metric: &M) -> Result<f64> {
+    let energy = |r: f64| circular_orbit(metric, r).map_or(f64::INFINITY, |o| o.energy);
+    let inner = metric.get_horizon().max(MIN_RADIUS) * (1.0 + HORIZON_MARGIN);
+    let radii: Vec<f64> = (0..ISCO_SEARCH_POINTS)
+        .map(|k| inner * (SPACETIME_EDGE / inner).powf(k as f64 / (ISCO_SEARCH_POINTS - 1) as f64))
+        .collect();
+    let energies: Vec<f64> = radii.iter().map(|r| energy(*r)).collect();
+    let mut lowest = 0;
+    for (k, e) in energies.iter().enumerate() {
+        if *e < energies[lowest] {
+            lowest = k;
+        }
+    }
+    if lowest == 0 || lowest == ISCO_SEARCH_POINTS - 1 || !energies[lowest - 1].is_finite() {
+        return Err(Error::InvalidScene(format!(
+            "the {} metric has no innermost stable circular orbit inside r = {}", metric.name(), SPACETIME_EDGE
+        )));
+    }
+
+    // Golden section search between the neighbours of the lowest point
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let (mut low, mut high) = (radii[lowest - 1], radii[lowest + 1]);
+    for _ in 0..ISCO_REFINEMENTS {
+        let (left, right) = (high - ratio * (high - low), low + ratio * (high - low));
+        if energy(left) < energy(right) {
+            high = right;
+        } else {
+            low = left;
+        }
+    }
+    Ok((low + high) / 2.0)
+}
+
+/// A geometrically thin, optically thick disk of gas on circular geodesics from the ISCO
+/// outwards, radiating the flux of Page & Thorne as a blackbody, or a diluted blackbody with
+/// a colour correction.
+#[derive(Clone, Debug)]
+pub struct PageThorne {
+    /// Of the hole, in solar masses
+    mass: f64,
+    /// In solar masses per year
+    accretion_rate: f64,
+    /// Ratio of colour temperature to effective temperature, f_col
+    colour_correction: f64,
+    isco: f64,
+    /// 1 - E at the ISCO: the fraction of the rest mass accreted that is radiated
+    efficiency: f64,
+    /// Flux from each face at the radii of the table, in erg s^-1 cm^-2
+    flux: Vec<f64>,
+}
+
+impl PageThorne {
+    /// The disk around a hole of `mass` solar masses in `metric`, fed at `accretion_rate` solar
+    /// masses per year.
+    pub fn new<M: Metric>(metric: &M, mass: f64, accretion_rate: f64) -> Result<Self> {
+        if !(mass.is_finite() && mass > 0.0 && accretion_rate.is_finite() && accretion_rate >= 0.0) {
+            return Err(Error::InvalidScene(format!(
+                "a disk needs a positive mass and accretion rate, not {} and {}", mass, accretion_rate
+            )));
+        }
+        let isco = isco(metric)?;
+        let radii: Vec<f64> = (0..TABLE_SIZE).map(|k| table_radius(isco, k as f64)).collect();
+
+        // F = Ṁ / (4π sqrt(-g)) (-∂Ω) / (E - ΩL)^2 ∫_isco^r (E - ΩL) ∂L dr, with sqrt(-g) that of
+        // the (t, r, φ) part of the metric in the plane. This is per unit Ṁ, in units of c^2 / R_S^2.
+        let mut integral = 0.0;
+        let mut previous = None;
+        let mut flux = Vec::with_capacity(TABLE_SIZE);
+        for r in radii {
+            let h = DERIVATIVE_STEP * r;
+            let orbits = (circular_orbit(metric, r), circular_orbit(metric, r + h), circular_orbit(metric, r - h));
+            let (orbit, above, below) = match orbits {
+                (Some(orbit), Some(above), Some(below)) => (orbit, above, below),
+                _ => return Err(Error::Numerical(format!("no circular orbit at r = {} outside the ISCO at {}", r, isco))),
+            };
+            let d_omega = (above.angular_velocity - below.angular_velocity) / (2.0 * h);
+            let d_momentum = (above.angular_momentum - below.angular_momentum) / (2.0 * h);
+            let redshifted_energy = orbit.energy - orbit.angular_velocity * orbit.angular_momentum;
+            let integrand = redshifted_energy * d_momentum;
+            if let Some((last_r, last_integrand)) = previous {
+                integral += (r - last_r) * (integrand + last_integrand) / 2.0;
+            }
+            previous = Some((r, integrand));
+            let g = equatorial_metric(metric, r);
+            let volume = (g[5] * (g[3] * g[3] - g[0] * g[15])).sqrt();
+            flux.push(-d_omega / (redshifted_energy * redshifted_energy) * integral / (4.0 * PI * volume));
+        }
+
+        let radius = SOLAR_SCHWARZSCHILD_RADIUS * mass;
+        let scale = accretion_rate * SOLAR_MASS / YEAR * SPEED_OF_LIGHT * SPEED_OF_LIGHT / (radius * radius);
+        let efficiency = 1.0 - circular_orbit(metric, isco).map_or(f64::NAN, |o| o.energy);
+        Ok(Self {
+            mass,
+            accretion_rate,
+            colour_correction: 1.0,
+            isco,
+            efficiency,
+            flux: flux.into_iter().map(|f| f * scale).collect(),
+        })
+    }
+
+    pub fn with_colour_correction(mut self, colour_correction: f64) -> Self {
+        self.colour_correction = colour_correction;
+        self
+    }
+
+    pub fn isco(&self) -> f64 { self.isco }
+    pub fn efficiency(&self) -> f64 { self.efficiency }
+
+    /// Flux from each face at radius `r`, in erg s^-1 cm^-2, or `None` off the disk.
+    pub fn flux(&self, r: f64) -> Option<f64> {
+        let index = table_index(self.isco, r);
+        if !(0.0..=(TABLE_SIZE - 1) as f64).contains(&index) {
+            return None;
+        }
+        let low = (index as usize).min(TABLE_SIZE - 2);
+        let frac = index - low as f64;
+        Some(self.flux[low] * (1.0 - frac) + self.flux[low + 1] * frac)
+    }
+
+    /// Colour temperature f_col (F / σ)^(1/4) of the gas at radius `r`, in eV, or `None` off the
+    /// disk.
+    pub fn temperature(&self, r: f64) -> Option<f64> {
+        self.flux(r).map(|f| self.colour_temperature(f))
+    }
+
+    /// Colour temperature, in eV, of gas radiating `flux` erg s^-1 cm^-2.
+    pub fn colour_temperature(&self, flux: f64) -> f64 {
+        self.colour_correction * (flux / STEFAN_BOLTZMANN).powf(0.25) * BOLTZMANN_EV
+    }
+
+    /// Largest flux from the disk, in erg s^-1 cm^-2.
+    pub fn peak_flux(&self) -> f64 {
+        self.flux.iter().cloned().fold(0.0, f64::max)
+    }
+
+    /// Radius and colour temperature, in kelvin, of the hottest part of the disk.
+    pub fn hottest(&self) -> (f64, f64) {
+        let peak = self.peak_flux();
+        let k = self.flux.iter().position(|f| *f == peak).unwrap_or(0);
+        (table_radius(self.isco, k as f64), self.colour_temperature(peak) / BOLTZMANN_EV)
+    }
+
+    pub fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![
+            ("hole_mass", self.mass),
+            ("accretion_rate", self.accretion_rate),
+            ("colour_correction", self.colour_correction),
+            ("isco", self.isco),
+            ("efficiency", self.efficiency),
+        ]
+    }
+}
+
+/// Gas inside the ISCO, falling in along the geodesic that leaves the ISCO with the energy and
+/// angular momentum of the orbit there, and radiating with an emissivity of its own: the flux
+/// goes as r^-index, starting from a fraction `edge` of that of the brightest part of the disk.
+#[derive(Clone, Debug)]
+pub struct Plunge {
+    isco: f64,
+    energy: f64,
+    angular_momentum: f64,
+    edge: f64,
+    index: f64,
+}
+
+impl Plunge {
+    pub fn new<M: Metric>(metric: &M, edge: f64, index: f64) -> Result<Self> {
+        if !(edge.is_finite() && edge >= 0.0 && index.is_finite()) {
+            return Err(Error::InvalidScene(format!(
+                "the plunging region needs a flux at its edge of at least zero and a finite index, not {} and {}", edge, index
+            )));
+        }
+        let isco = isco(metric)?;
+        let orbit = circular_orbit(metric, isco)
+            .ok_or_else(|| Error::Numerical(format!("no circular orbit at the ISCO at r = {}", isco)))?;
+        Ok(Self { isco, energy: orbit.energy, angular_momentum: orbit.angular_momentum, edge, index })
+    }
+
+    pub fn isco(&self) -> f64 { self.isco }
+
+    /// Whether gas at radius `r` is plunging.
+    pub fn contains(&self, r: f64) -> bool {
+        r < self.isco
+    }
+
+    /// 4-velocity of the gas at radius `r` in the equatorial plane, from u_t = -E, u_φ = L and
+    /// g(u, u) = -1 with u^r < 0, or `None` where there is no such motion.
+    pub fn velocity<M: Metric>(&self, metric: &M, r: f64) -> Option<Vec4> {
+        let g = equatorial_metric(metric, r);
+        let determinant = g[3] * g[3] - g[0] * g[15];
+        if determinant.is_nan() || determinant <= 0.0 || g[5] <= 0.0 {
+            return None;
+        }
+        let time = (g[15] * self.energy + g[3] * self.angular_momentum) / determinant;
+        let phi = -(g[3] * self.energy + g[0] * self.angular_momentum) / determinant;
+        let radial = (-1.0 + self.energy * time - self.angular_momentum * phi) / g[5];
+        Some([time, -radial.max(0.0).sqrt(), 0.0, phi])
+    }
+
+    /// Flux from each face at radius `r`, when the brightest part of the disk gives `brightest`.
+    pub fn flux(&self, r: f64, brightest: f64) -> f64 {
+        self.edge * brightest * (r / self.isco).powf(-self.index)
+    }
+
+    pub fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![
+            ("plunge_isco", self.isco),
+            ("plunge_edge", self.edge),
+            ("plunge_index", self.index),
+        ]
+    }
+}
+
+fn table_radius(isco: f64, index: f64) -> f64 {
+    isco * (SPACETIME_EDGE / isco).powf(index / (TABLE_SIZE - 1) as f64)
+}
+
+fn table_index(isco: f64, r: f64) -> f64 {
+    (r / isco).ln() / (SPACETIME_EDGE / isco).ln() * (TABLE_SIZE - 1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Kerr, Schwarzschild};
+
+    /// Prograde ISCO of a hole with spin a/M = `spin`, in Schwarzschild radii (Bardeen, Press
+    /// and Teukolsky 1972).
+    fn bardeen_isco(spin: f64) -> f64 {
+        let z1 = 1.0 + (1.0 - spin * spin).cbrt() * ((1.0 + spin).cbrt() + (1.0 - spin).cbrt());
+        let z2 = (3.0 * spin * spin + z1 * z1).sqrt();
+        0.5 * (3.0 + z2 - ((3.0 - z1) * (3.0 + z1 + 2.0 * z2)).sqrt())
+    }
+
+    /// 1 - E of the ISCO at radius `isco`, for any spin: E = sqrt(1 - 2M / 3r).
+    fn bardeen_efficiency(isco: f64) -> f64 {
+        1.0 - (1.0 - 1.0 / (3.0 * isco)).sqrt()
+    }
+
+    fn isco_of_kerr(spin: f64) -> f64 {
+        isco(&Kerr::new(spin).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn isco_is_three_schwarzschild_radii_without_spin() {
+        let isco = isco(&Schwarzschild::new()).unwrap();
+        assert!((isco - 3.0).abs() < 1e-4, "ISCO at {}", isco);
+        let isco = isco_of_kerr(0.0);
+        assert!((isco - 3.0).abs() < 1e-4, "ISCO at {} for a = 0", isco);
+    }
+
+    #[test]
+    fn isco_and_efficiency_match_bardeen() {
+        for spin in [0.0, 0.5, 0.8] {
+            let disk = PageThorne::new(&Kerr::new(spin).unwrap(), 1e8, 1.0).unwrap();
+            let (isco, efficiency) = (bardeen_isco(spin), bardeen_efficiency(bardeen_isco(spin)));
+            assert!((disk.isco() / isco - 1.0).abs() < 1e-4, "a = {}: ISCO at {}, not {}", spin, disk.isco(), isco);
+            assert!((disk.efficiency() / efficiency - 1.0).abs() < 1e-4, "a = {}: efficiency {}, not {}", spin, disk.efficiency(), efficiency);
+        }
+        // Retrograde orbits sit further out
+        assert!(isco_of_kerr(-0.8) > 3.0);
+    }
+}
@@ -1,151 +1,1294 @@
 use std::thread;
-use std::sync::mpsc::channel;
-use ndarray::{Array3, Array2};
-use ndarray_npy::write_npy;
+use std::fs;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{mpsc, Arc};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use ndarray::{Array1, Array2, Array3, Axis, Zip};
+use ndarray_npy::{read_npy, write_npy};
+use rayon::prelude::*;
 
-use crate::observer::{Observer, Photon, PhotonData, PHOTON_BATCH_SIZE, WIDTH, HEIGHT};
+use crate::observer::{Observer, PhotonData, Termination, Failure, PHOTON_BATCH_SIZE, WIDTH, HEIGHT};
 use crate::metrics::Metric;
 use crate::source::AccretionDisk;
+use crate::util::hash_str;
+use crate::preview;
+use crate::fits::{self, Card, Value, Hdu, WcsUnits};
+use crate::image::{self, ToneMap};
+use crate::error::{Error, Result};
+use crate::skybox::Skybox;
+use crate::catalogue::{self, Star};
+use crate::spectrum::EnergyGrid;
+use crate::bandpass::Bandpass;
 
 const PARALLELISM: bool = true;
-
-enum ToThread {
-    Photons([Option<Photon>; PHOTON_BATCH_SIZE]),
-    Terminate(),
-}
-
-struct FromThread {
-    results: Vec<PhotonData>,
-    thread_index: usize,
+pub const DATA_DIRECTORY: &str = "../data"; // Where output goes by default
+const CHUNK_SIZE: usize = 0x1000; // Photons handed to the thread pool at once, a whole number of batches
+const CHUNKS_AHEAD: usize = 4; // Chunks per thread handed out beyond the oldest unfinished one
+const BACKGROUND_WHITE_PERCENTILE: f64 = 99.0;
+/// Running sums of the light arriving at each pixel.
+pub struct Accumulator {
+    pub optical: Array3<f64>,
+    pub xray: Array3<f64>,
+    pub counts: Array2<f64>,
+    /// Photons per pixel with each kind of failure, in the order of `Failure::ALL`. Quarantined
+    /// photons are counted here but not in `counts`.
+    pub failures: Array3<f64>,
+    /// Photons per pixel that stopped for each reason, in the order of `Termination::ALL`
+    pub terminations: Array3<f64>,
+    /// Sum of the escape directions (x, y, z) of the escaped photons in each pixel, leaving out
+    /// those scattered by the corona, whose direction says nothing about the lensing
+    pub escape: Array3<f64>,
+    /// Sum of the affine lengths of all photons traced in each pixel
+    pub affine: Array2<f64>,
+    /// Light from the background sky, summed like `optical`
+    pub background: Array3<f64>,
+    /// Energy bins of the spectra, if they are being accumulated
+    pub energy_grid: Option<EnergyGrid>,
+    /// Light from the disk in each energy bin, as a (bin, row, column) cube summed like
+    /// `optical`. Has no bins unless there is an energy grid.
+    pub disk_spectrum: Array3<f64>,
+    /// Light scattered by the corona in each energy bin, summed like `xray`
+    pub scattered_spectrum: Array3<f64>,
+    /// Names of the filters the light is also seen through
+    pub band_names: Vec<String>,
+    /// Light from the disk and the corona through each filter, as a (filter, row, column) cube
+    /// summed like `optical`
+    pub bands: Array3<f64>,
+    pub photon_count: usize,
 }
 
 pub struct Engine<O: Observer> {
     observer: O,
-    optical_name: String,
-    xray_name: String,
-    optical: Array3<f64>,
-    xray: Array3<f64>,
-    counts: Array2<f64>,
-    photon_count: usize,
+    file_name: String,
+    directory: String,
+    image: Accumulator,
+    seed: u64,
+    /// Whether the seed was given rather than drawn at random
+    seed_given: bool,
+    num_threads: usize,
+    checkpoint_interval: Option<usize>,
+    preview_interval: Option<usize>,
+    shard: Option<(usize, usize)>,
+    resumed_hash: Option<u64>,
+    /// Seconds spent rendering before the last resume
+    resumed_wall_time: f64,
+    /// Indices of photons that panicked in an earlier attempt, which are left out
+    skipped: BTreeSet<usize>,
+    wcs_units: WcsUnits,
+    tone_map: Option<ToneMap>,
+    skybox: Option<Arc<Skybox>>,
+    catalogue: Option<Vec<Star>>,
+    energy_grid: Option<EnergyGrid>,
+    filters: Arc<Vec<Bandpass>>,
+    /// Stop with an error once this many photons are summed, as if the run were killed
+    #[cfg(test)]
+    interrupt_after: Option<usize>,
 }
 
 impl<O: Observer> Engine<O> {
     pub fn new(observer: O, file_name: String) -> Self {
         Self {
             observer,
-            optical_name: format!("../data/{}-optical.npy", file_name),
-            xray_name: format!("../data/{}-xray.npy", file_name),
-            optical: Array3::zeros((3, HEIGHT, WIDTH)),
-            xray: Array3::zeros((3, HEIGHT, WIDTH)),
-            counts: Array2::zeros((HEIGHT, WIDTH)),
-            photon_count: 0,
+            file_name,
+            directory: DATA_DIRECTORY.to_owned(),
+            image: Accumulator::new(),
+            seed: fastrand::u64(..),
+            seed_given: false,
+            num_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            checkpoint_interval: None,
+            preview_interval: None,
+            shard: None,
+            resumed_hash: None,
+            resumed_wall_time: 0.0,
+            skipped: BTreeSet::new(),
+            wcs_units: WcsUnits::GravitationalRadii,
+            tone_map: None,
+            skybox: None,
+            catalogue: None,
+            energy_grid: None,
+            filters: Arc::new(Vec::new()),
+            #[cfg(test)]
+            interrupt_after: None,
         }
     }
 
-    pub fn save(&self) {
-        write_npy(&self.optical_name, &(&self.optical / &self.counts)).unwrap();
-        write_npy(&self.xray_name, &(&self.xray / &self.counts)).unwrap();
+    /// Write the output, checkpoints and previews to `directory` instead of `DATA_DIRECTORY`.
+    pub fn with_directory(mut self, directory: &str) -> Self {
+        self.directory = directory.to_owned();
+        self
+    }
+
+    /// Fix the seed that every photon's random numbers are derived from.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.seed_given = true;
+        self
+    }
+
+    /// Set the number of worker threads. Defaults to the number of available cores.
+    pub fn with_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = usize::max(1, num_threads);
+        self
+    }
+
+    /// Write a checkpoint every time roughly `interval` more photons have been accumulated.
+    pub fn with_checkpoints(mut self, interval: usize) -> Self {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Write preview images every time roughly `interval` more photons have been accumulated.
+    pub fn with_previews(mut self, interval: usize) -> Self {
+        self.preview_interval = Some(interval);
+        self
+    }
+
+    /// Set the units of the world coordinates in the FITS output.
+    pub fn with_wcs(mut self, units: WcsUnits) -> Self {
+        self.wcs_units = units;
+        self
+    }
+
+    /// Also write the finished images as PNG, tone mapped with `tone_map`, and as linear OpenEXR.
+    pub fn with_images(mut self, tone_map: ToneMap) -> Self {
+        self.tone_map = Some(tone_map);
+        self
+    }
+
+    /// Let rays that escape see `skybox`. Its light is kept separate from the disk's, in the
+    /// background image.
+    pub fn with_skybox(mut self, skybox: Skybox) -> Self {
+        self.skybox = Some(Arc::new(skybox));
+        self
+    }
+
+    /// After rendering, find the lensed images of `stars` and write them as a table and a
+    /// picture (see `catalogue::lens`).
+    pub fn with_catalogue(mut self, stars: Vec<Star>) -> Self {
+        self.catalogue = Some(stars);
+        self
+    }
+
+    /// Also accumulate the spectrum of every pixel in the bins of `grid`, separately for light
+    /// from the disk and light scattered by the corona. Call this before `resume`.
+    pub fn with_spectra(mut self, grid: EnergyGrid) -> Self {
+        self.energy_grid = Some(grid);
+        self.image = self.empty_image();
+        self
+    }
+
+    /// Also sum the light of every pixel through each of `filters`, one image per filter. Call
+    /// this before `resume`.
+    pub fn with_filters(mut self, filters: Vec<Bandpass>) -> Self {
+        self.filters = Arc::new(filters);
+        self.image = self.empty_image();
+        self
+    }
+
+    /// An accumulator with nothing in it and the layers this engine fills.
+    fn empty_image(&self) -> Accumulator {
+        let image = Accumulator::new().with_bands(self.filters.iter().map(|f| f.name().to_owned()).collect());
+        match &self.energy_grid {
+            Some(grid) => image.with_spectra(grid.clone()),
+            None => image,
+        }
+    }
+
+    /// Only trace shard `index` of `count` equal slices of the photons, and write the raw sums to
+    /// a partial directory instead of the final images. Partial directories are combined with
+    /// `shard::merge`. Call this before `resume`, since each shard keeps its own checkpoint.
+    pub fn with_shard(mut self, index: usize, count: usize) -> Self {
+        self.shard = Some((index, count));
+        self
+    }
+
+    /// Everything that determines the image apart from the seed, as recorded in the manifest.
+    pub fn scene<M: Metric>(&self, max_iterations: usize, dtau: f64, metric: &M, source: &AccretionDisk) -> serde_json::Value {
+        serde_json::json!({
+            "metric": metric.name(),
+            "metric_parameters": parameters_json(metric.parameters()),
+            "disk": parameters_json(source.parameters()),
+            "corona_table": source.corona_table().map(|t| serde_json::json!({ "file": t.name(), "hash": t.hash() })),
+            "observer": parameters_json(self.observer.parameters()),
+            "description": format!("{:?} | {:?} | {}", metric, source, self.observer.describe()),
+            "skybox": self.skybox.as_ref().map(|s| s.describe()),
+            "filters": self.filters.iter().map(|f| f.describe()).collect::<Vec<_>>(),
+            "energy_grid": self.energy_grid.as_ref().map(|g| serde_json::json!({ "min_ev": g.min, "max_ev": g.max, "bins": g.bins })),
+            "max_iterations": max_iterations,
+            "dtau": dtau,
+            "width": WIDTH,
+            "height": HEIGHT,
+            "photons": self.observer.num_photons(),
+        })
+    }
+
+    /// Hash of the scene, so that checkpoints and shards from different scenes are never mixed up.
+    pub fn scene_hash(scene: &serde_json::Value) -> u64 {
+        hash_str(&scene.to_string())
+    }
+
+    /// The range of photon indices this engine traces. Shard boundaries fall on whole batches.
+    fn photon_range(&self) -> (usize, usize) {
+        let num_batches = self.observer.num_photons().div_ceil(PHOTON_BATCH_SIZE);
+        match self.shard {
+            Some((index, count)) => (
+                num_batches * index / count * PHOTON_BATCH_SIZE,
+                num_batches * (index + 1) / count * PHOTON_BATCH_SIZE,
+            ),
+            None => (0, self.total_photons()),
+        }
+    }
+
+    /// Number of photons in the whole render, including those of other shards.
+    fn total_photons(&self) -> usize {
+        self.observer.num_photons().div_ceil(PHOTON_BATCH_SIZE) * PHOTON_BATCH_SIZE
+    }
+
+    /// FITS header cards recording everything that produced the image.
+    pub fn header_cards<M: Metric>(&self, max_iterations: usize, dtau: f64, metric: &M, source: &AccretionDisk, scene_hash: u64) -> Vec<Card> {
+        // Observer distance is in Schwarzschild radii, which are two gravitational radii
+        let distance = 2.0 * self.observer.distance();
+        let mut cards = fits::wcs_cards(self.wcs_units, WIDTH, HEIGHT, distance * self.observer.pixel_scale().tan(), distance);
+        cards.push(Card::new("CREATOR", Value::Str(format!("raytracer {}", env!("CARGO_PKG_VERSION"))), ""));
+        cards.push(Card::new("METRIC", Value::Str(metric.name().to_owned()), "Spacetime"));
+        for (key, value) in metric.parameters() {
+            cards.push(Card::new(&format!("METRIC_{}", key), Value::Float(value), ""));
+        }
+        for (key, value) in source.parameters() {
+            cards.push(Card::new(&format!("DISK_{}", key), Value::Float(value), ""));
+        }
+        if let Some(table) = source.corona_table() {
+            cards.push(Card::new("CORTABLE", Value::Str(table.hash()), "Hash of the corona table"));
+        }
+        for (key, value) in self.observer.parameters() {
+            cards.push(Card::new(&format!("OBS_{}", key), Value::Float(value), ""));
+        }
+        cards.push(Card::new("DTAU", Value::Float(dtau), "Affine parameter step"));
+        cards.push(Card::new("MAXITER", Value::Int(max_iterations as i64), "Step limit per photon"));
+        cards.push(Card::new("SEED", Value::Str(self.seed.to_string()), "Random seed"));
+        cards.push(Card::new("SCNHASH", Value::Str(format!("{:016x}", scene_hash)), "Hash of the scene"));
+        cards
+    }
+
+    /// Everything about a run needed to tell later what produced its output: the scene, its
+    /// constants, the observer, seed, threads, timing and how the photons ended.
+    pub fn manifest(&self, scene: &serde_json::Value, scene_hash: u64, num_threads: usize, wall_time: f64) -> serde_json::Value {
+        let (start, end) = self.photon_range();
+        serde_json::json!({
+            "name": self.file_name,
+            "version": env!("CARGO_PKG_VERSION"),
+            "scene_hash": format!("{:016x}", scene_hash),
+            "scene": scene,
+            "image": {
+                "x_ray_weight": image::X_RAY_WEIGHT,
+                "remove_pole": image::REMOVE_POLE,
+            },
+            "seed": self.seed.to_string(),
+            "threads": num_threads,
+            "finished_unix": SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            "wall_time_seconds": wall_time,
+            "resumed": self.resumed_hash.is_some(),
+            "shard": self.shard.map(|(index, count)| serde_json::json!({ "index": index, "count": count })),
+            "photon_range": [start, end],
+            "total_photons": self.total_photons(),
+            "photon_count": self.image.photon_count,
+            "skipped_photons": self.skipped,
+            "terminations": self.image.termination_stats(),
+            "failures": self.image.failure_stats(),
+        })
+    }
+
+    /// Path of the output without an extension, or of the partial directory for a shard.
+    fn base(&self) -> String {
+        match self.shard {
+            Some((index, count)) => shard_name(&self.directory, &self.file_name, index, count),
+            None => format!("{}/{}", self.directory, self.file_name),
+        }
+    }
+
+    fn checkpoint_name(&self) -> String {
+        format!("{}-checkpoint", self.base())
+    }
+
+    fn preview_name(&self) -> String {
+        format!("{}-preview", self.base())
+    }
+
+    pub fn save(&self, cards: &[Card]) -> Result<()> {
+        let base = self.base();
+        self.image.save(&base)?;
+        self.image.save_fits(&format!("{}.fits", base), cards)?;
+        if let Some(tone_map) = &self.tone_map {
+            self.image.save_images(&base, tone_map);
+        }
+        if let Some(stars) = &self.catalogue {
+            let escaped = self.image.termination_fractions().index_axis(Axis(0), Termination::Escape as usize).to_owned();
+            catalogue::lens(&base, &self.image.escape_angles(), &escaped, stars, self.tone_map.as_ref())?;
+        }
+        Ok(())
     }
 
-    pub fn run<M: Metric>(&mut self, max_iterations: usize, dtau: f64, metric: M, source: AccretionDisk) -> usize {
-        let num_threads = if PARALLELISM {
-            thread::available_parallelism().unwrap().get() - 1
-        } else {
-            1
+    /// Save the raw accumulators, the number of photons handed out before them, `progress`, the
+    /// seed and the photons to skip so that the run can be resumed.
+    /// The checkpoint directory is replaced as a whole (see `Accumulator::write_raw`), so a crash
+    /// mid-write leaves the previous checkpoint intact.
+    pub fn save_checkpoint(&self, progress: usize, scene_hash: u64, wall_time: f64) -> Result<()> {
+        self.image.write_raw(&self.checkpoint_name(), &format!(
+            "num_counts={}\nphoton_count={}\nseed={}\nscene_hash={:016x}\nwall_time={}\nskipped={}\n",
+            progress, self.image.photon_count, self.seed, scene_hash, wall_time,
+            self.skipped.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(","),
+        ))
+    }
+
+    /// Load the last checkpoint written for this file name, if there is one. Returns whether a
+    /// checkpoint was found. The following call to `run` continues where the checkpoint left off,
+    /// with the checkpoint's seed. Fails if a different seed was given with `with_seed`.
+    pub fn resume(&mut self) -> Result<bool> {
+        let name = &self.checkpoint_name();
+        let (image, metadata) = match Accumulator::read_raw(name)? {
+            Some(raw) => raw,
+            None => return Ok(false),
+        };
+        let seed = field(&metadata, "seed", name)?;
+        if self.seed_given && seed != self.seed {
+            return Err(Error::InvalidScene(format!(
+                "{} was made with seed {}, not {}. Rerun with --seed {} to resume it, or delete it to start over",
+                name, seed, self.seed, seed
+            )));
+        }
+        self.image = image;
+        self.observer.set_progress(field(&metadata, "num_counts", name)?);
+        self.seed = seed;
+        self.resumed_hash = match metadata.get("scene_hash") {
+            Some(h) => Some(u64::from_str_radix(h, 16).map_err(|_| Error::Format(format!("{}: bad scene_hash", name)))?),
+            None => None,
         };
-        println!("Using {} threads", num_threads);
-        let mut threads = Vec::with_capacity(num_threads);
-        let mut senders = Vec::with_capacity(num_threads);
-        let (from_threads_sender, from_threads_receiver) = channel();
-        let rng = fastrand::Rng::new();
-
-        for thread_index in 0..num_threads {
-            let (to_thread_sender, to_thread_receiver) = channel();
-            let this_sender = from_threads_sender.clone();
-            let this_source = source.clone();
-            let this_rng = rng.clone();
-
-            // Spawn the threads
-            let t = thread::spawn(move || {
-                while let Ok(msg) = to_thread_receiver.recv() {
-                    // Handle message
-                    let mut results = Vec::with_capacity(PHOTON_BATCH_SIZE);
-                    match msg {
-                        ToThread::Photons(photons) => {
-                            for photon in photons {
-                                results.push(match photon {
-                                    Some(p) => p.run(max_iterations, dtau, &metric, &this_source, &this_rng),
-                                    None => break,
-                                });
+        self.resumed_wall_time = if metadata.contains_key("wall_time") { field(&metadata, "wall_time", name)? } else { 0.0 };
+        self.skipped = metadata.get("skipped").map_or(Ok(BTreeSet::new()), |list| {
+            list.split(',').filter(|i| !i.is_empty())
+                .map(|i| i.parse().map_err(|_| Error::Format(format!("{}: bad skipped photon {}", name, i))))
+                .collect()
+        })?;
+        println!("Resuming from checkpoint at {} photons", self.observer.progress());
+        if !self.skipped.is_empty() {
+            println!("WARNING: skipping photons {:?}, which panicked before", self.skipped);
+        }
+        Ok(true)
+    }
+
+    /// Write the optical and X-ray images accumulated so far, plus a map of samples per pixel.
+    /// Each call overwrites the previous preview.
+    pub fn save_preview(&self) {
+        let name = self.preview_name();
+        let result = preview::write_color(&format!("{}-optical.png", name), &self.image.optical, &self.image.counts)
+            .and_then(|_| preview::write_color(&format!("{}-xray.png", name), &self.image.xray, &self.image.counts))
+            .and_then(|_| preview::write_counts(&format!("{}-counts.png", name), &self.image.counts));
+        // A failed preview should not stop the render
+        if let Err(e) = result {
+            println!("WARNING: could not write preview: {}", e);
+        }
+    }
+
+    fn remove_checkpoint(&self) {
+        let name = self.checkpoint_name();
+        for dir in [format!("{}.tmp", name), format!("{}.old", name), name] {
+            let _ = remove_raw(&dir);
+        }
+    }
+
+    /// Trace every photon of the scene (or of this engine's shard) and write the output. Returns
+    /// the number of photons traced.
+    ///
+    /// If a worker thread panics, or the output cannot be written, the photons summed so far are
+    /// saved as a checkpoint before the error is returned, so the next run picks up from there.
+    /// Photons that panicked while being traced are recorded in the checkpoint and skipped by the
+    /// next run, so the same panic does not stop every rerun.
+    pub fn run<M: Metric>(&mut self, max_iterations: usize, dtau: f64, metric: M, source: AccretionDisk) -> Result<usize> {
+        let timer = Instant::now();
+        if !(dtau.is_finite() && dtau > 0.0) {
+            return Err(Error::InvalidScene(format!("dtau must be positive, not {}", dtau)));
+        }
+        if max_iterations == 0 {
+            return Err(Error::InvalidScene("max_iterations must be at least 1".to_owned()));
+        }
+        if let Some((index, count)) = self.shard {
+            if index >= count {
+                return Err(Error::InvalidScene(format!("shard index {} out of range for {} shards", index, count)));
+            }
+        }
+        let num_threads = if PARALLELISM { self.num_threads } else { 1 };
+        println!("Using {} threads, seed {}", num_threads, self.seed);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build()
+            .map_err(|e| Error::Worker(e.to_string()))?;
+
+        let scene = self.scene(max_iterations, dtau, &metric, &source);
+        let scene_hash = Self::scene_hash(&scene);
+        let cards = self.header_cards(max_iterations, dtau, &metric, &source, scene_hash);
+        if let Some(hash) = self.resumed_hash.filter(|h| *h != scene_hash) {
+            return Err(Error::InvalidScene(format!(
+                "{} was made for a different scene (hash {:016x}, not {:016x}). Rerun it as it was to resume it, or delete it to start over",
+                self.checkpoint_name(), hash, scene_hash
+            )));
+        }
+        let (start, end) = self.photon_range();
+        if let Some((index, count)) = self.shard {
+            println!("Rendering shard {} of {} (photons {} to {})", index, count, start, end);
+        }
+        if self.observer.progress() < start {
+            self.observer.set_progress(start);
+        }
+
+        let mut merged = self.observer.progress();
+        let mut last_checkpoint = merged;
+        let mut last_preview = merged;
+        let mut last_printed = 0;
+
+        // Hand the photons to the pool a chunk at a time. Idle threads steal photons from busy
+        // ones within a chunk, and up to CHUNKS_AHEAD chunks per thread are handed out beyond
+        // the oldest unfinished one, so a chunk stuck on photons orbiting near the photon
+        // sphere does not hold up the others. Each chunk's light is summed on its own, and the
+        // chunks are added to the image in photon order whatever thread traced them, so the
+        // image is bit-for-bit the same for any number of threads. Checkpoints and previews are
+        // written as the summed photons pass each interval.
+        let (sender, receiver) = mpsc::channel();
+        let mut finished = BTreeMap::new();
+        let mut in_flight = 0;
+        let mut failure = None;
+        pool.in_place_scope(|scope| loop {
+            while failure.is_none() && in_flight < CHUNKS_AHEAD * num_threads && self.observer.progress() < end {
+                let chunk_start = self.observer.progress();
+                let chunk_end = (chunk_start + CHUNK_SIZE).min(end);
+                let mut photons = Vec::with_capacity(CHUNK_SIZE);
+                while self.observer.progress() < chunk_end {
+                    let first = self.observer.progress();
+                    photons.extend(self.observer.next_photons(self.seed).into_iter().enumerate()
+                        .filter_map(|(i, photon)| photon.map(|photon| (first + i, photon)))
+                        .filter(|(index, _)| !self.skipped.contains(index)));
+                }
+                let (sender, metric, source) = (sender.clone(), &metric, &source);
+                let (skybox, filters, grid) = (self.skybox.clone(), self.filters.clone(), self.energy_grid.clone());
+                scope.spawn(move |_| {
+                    // The worker that finishes the chunk sums its light, so sampling the sky and
+                    // spreading light over filters and spectra happens in parallel too
+                    // Each photon is traced on its own so that one that panics can be named
+                    let data = photons.into_par_iter()
+                        .map(|(index, photon)| panic::catch_unwind(AssertUnwindSafe(|| photon.run(max_iterations, dtau, metric, source)))
+                            .map_err(|payload| (index, panic_message(&payload))))
+                        .collect::<Vec<_>>();
+                    let panicked = data.iter().filter_map(|d| d.as_ref().err()).collect::<Vec<_>>();
+                    let results = if let Some((_, message)) = panicked.first() {
+                        Err((panicked.iter().map(|(index, _)| *index).collect(), message.clone()))
+                    } else {
+                        panic::catch_unwind(AssertUnwindSafe(|| {
+                            let mut sums = ChunkSums::new(grid, filters.len());
+                            for p in data.into_iter().flatten() {
+                                sums.add(p, skybox.as_deref(), &filters);
                             }
-                        },
-                        ToThread::Terminate() => {
-                            return;
-                        }
+                            sums
+                        })).map_err(|payload| (Vec::new(), panic_message(&payload)))
                     };
-                    
-                    // Commit results
-                    this_sender.send(FromThread {
-                        thread_index, 
-                        results,
-                    }).unwrap();
+                    // The receiver lives until every chunk has been sent
+                    let _ = sender.send((chunk_start, chunk_end, results));
+                });
+                in_flight += 1;
+            }
+            if in_flight == 0 {
+                break;
+            }
+            let (chunk_start, chunk_end, sums) = receiver.recv().expect("a chunk is still being traced");
+            in_flight -= 1;
+            finished.insert(chunk_start, (chunk_end, sums));
+
+            // After a failure the chunks still being traced are waited for but not summed
+            while failure.is_none() {
+                let Some((chunk_end, sums)) = finished.remove(&merged) else { break };
+                let sums = match sums {
+                    Ok(sums) => sums,
+                    Err((panicked, message)) => {
+                        // Stop handing out chunks, and sum nothing from this one onwards
+                        self.skipped.extend(panicked.iter().copied());
+                        failure = Some(match panicked.as_slice() {
+                            [] => message,
+                            _ => format!("photons {:?} panicked: {}. They will be skipped when the render is resumed", panicked, message),
+                        });
+                        break;
+                    },
+                };
+                self.image.add_chunk(&sums);
+                merged = chunk_end;
+                #[cfg(test)]
+                if self.interrupt_after.is_some_and(|n| merged >= n && merged < end) {
+                    failure = Some("interrupted".to_owned());
+                    break;
                 }
-            });
 
-            to_thread_sender.send(ToThread::Photons(self.observer.next_photons())).unwrap();
+                let percentage = (merged - start) * 100 / (end - start);
+                if percentage / 10 > last_printed / 10 {
+                    println!("{}%", percentage / 10 * 10);
+                }
+                last_printed = percentage;
+
+                if let Some(interval) = self.checkpoint_interval {
+                    if merged - last_checkpoint >= interval && merged < end {
+                        // A failed checkpoint should not stop the render
+                        if let Err(e) = self.save_checkpoint(merged, scene_hash, self.resumed_wall_time + timer.elapsed().as_secs_f64()) {
+                            println!("WARNING: could not write checkpoint: {}", e);
+                        }
+                        last_checkpoint = merged;
+                    }
+                }
+                if let Some(interval) = self.preview_interval {
+                    if merged - last_preview >= interval {
+                        self.save_preview();
+                        last_preview = merged;
+                    }
+                }
+            }
+        });
+        if let Some(mut message) = failure {
+            // Photons that panicked in chunks after the one that failed are skipped too, so that
+            // each rerun does not stop on the next of them
+            let later = finished.into_values()
+                .filter_map(|(_, sums)| sums.err())
+                .flat_map(|(panicked, _)| panicked)
+                .filter(|index| self.skipped.insert(*index))
+                .collect::<Vec<_>>();
+            if !later.is_empty() {
+                message = format!("{}. Photons {:?} in later chunks panicked too", message, later);
+            }
+            // Checkpoint the photons summed before the chunk that failed
+            self.observer.set_progress(merged);
+            self.save_checkpoint(merged, scene_hash, self.resumed_wall_time + timer.elapsed().as_secs_f64())?;
+            return Err(Error::Worker(message));
+        }
 
-            threads.push(t);
-            senders.push(to_thread_sender);
+        self.image.print_summary();
+        let wall_time = self.resumed_wall_time + timer.elapsed().as_secs_f64();
+        let manifest = self.manifest(&scene, scene_hash, num_threads, wall_time);
+        if let Err(e) = self.write_output(&cards, &manifest, scene_hash) {
+            // Keep the finished sums so that rerunning only has to write the output again
+            self.save_checkpoint(self.observer.progress(), scene_hash, wall_time)?;
+            return Err(e);
         }
+        self.remove_checkpoint();
 
-        // Main loop
-        let mut last_percentage = 0;
-        while let Ok(msg) = from_threads_receiver.recv() {
-            // Update the observer with new info
-            let percentage = self.observer.update(&msg.results);
-            if percentage > last_percentage {
-                if percentage % 10 == 0 {
-                    println!("{}%", percentage);
+        Ok(self.image.photon_count)
+    }
+
+    /// Write the manifest and either the final images or, for a shard, the partial directory.
+    fn write_output(&self, cards: &[Card], manifest: &serde_json::Value, scene_hash: u64) -> Result<()> {
+        let base = self.base();
+        write_manifest(&format!("{}.json", base), manifest)?;
+
+        match self.shard {
+            Some((index, count)) => {
+                let (start, end) = self.photon_range();
+                let mut metadata = format!(
+                    "shard={}/{}\nstart={}\nend={}\ntotal={}\nphoton_count={}\nseed={}\nscene_hash={:016x}\n",
+                    index, count, start, end, self.total_photons(), self.image.photon_count, self.seed, scene_hash
+                );
+                // Keep the FITS header so the merge can write it
+                for (i, card) in cards.iter().enumerate() {
+                    metadata += &format!("card.{}={}\n", i, card.text().trim_end());
                 }
-                last_percentage = percentage;
+                self.image.write_raw(&base, &metadata)
+            },
+            None => self.save(cards),
+        }
+    }
+}
+
+fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+/// Parse `key` from the metadata of the raw directory `dir`.
+pub fn field<T: FromStr>(metadata: &HashMap<String, String>, key: &str, dir: &str) -> Result<T> {
+    metadata.get(key)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| Error::Format(format!("{}/metadata.txt: missing or bad {}", dir, key)))
+}
+
+fn parameters_json(parameters: Vec<(&'static str, f64)>) -> serde_json::Value {
+    parameters.into_iter()
+        .map(|(key, value)| (key.to_owned(), value.into()))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Write a run manifest as pretty-printed JSON.
+pub fn write_manifest(path: &str, manifest: &serde_json::Value) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(manifest)? + "\n")?;
+    Ok(())
+}
+
+/// Path of the partial directory written to `directory` by shard `index` of `count`.
+pub fn shard_name(directory: &str, file_name: &str, index: usize, count: usize) -> String {
+    format!("{}/{}-shard{}of{}", directory, file_name, index, count)
+}
+
+impl Accumulator {
+    pub fn new() -> Self {
+        Self {
+            optical: Array3::zeros((3, HEIGHT, WIDTH)),
+            xray: Array3::zeros((3, HEIGHT, WIDTH)),
+            counts: Array2::zeros((HEIGHT, WIDTH)),
+            failures: Array3::zeros((Failure::ALL.len(), HEIGHT, WIDTH)),
+            terminations: Array3::zeros((Termination::ALL.len(), HEIGHT, WIDTH)),
+            escape: Array3::zeros((3, HEIGHT, WIDTH)),
+            affine: Array2::zeros((HEIGHT, WIDTH)),
+            background: Array3::zeros((3, HEIGHT, WIDTH)),
+            energy_grid: None,
+            disk_spectrum: Array3::zeros((0, HEIGHT, WIDTH)),
+            scattered_spectrum: Array3::zeros((0, HEIGHT, WIDTH)),
+            band_names: Vec::new(),
+            bands: Array3::zeros((0, HEIGHT, WIDTH)),
+            photon_count: 0,
+        }
+    }
+
+    /// Also sum the light in each bin of `grid`.
+    pub fn with_spectra(mut self, grid: EnergyGrid) -> Self {
+        self.disk_spectrum = Array3::zeros((grid.bins, HEIGHT, WIDTH));
+        self.scattered_spectrum = Array3::zeros((grid.bins, HEIGHT, WIDTH));
+        self.energy_grid = Some(grid);
+        self
+    }
+
+    /// Also sum the light through the filters called `names`.
+    pub fn with_bands(mut self, names: Vec<String>) -> Self {
+        self.bands = Array3::zeros((names.len(), HEIGHT, WIDTH));
+        self.band_names = names;
+        self
+    }
+
+    /// An accumulator with nothing in it and the same layers as this one.
+    fn empty_like(&self) -> Self {
+        let image = Self::new().with_bands(self.band_names.clone());
+        match &self.energy_grid {
+            Some(grid) => image.with_spectra(grid.clone()),
+            None => image,
+        }
+    }
+
+    /// Add the light of a chunk of photons.
+    fn add_chunk(&mut self, chunk: &ChunkSums) {
+        self.photon_count += chunk.photon_count;
+        for sums in &chunk.pixels {
+            let (i, j) = sums.pixel;
+            for k in 0..3 {
+                self.optical[(k, i, j)] += sums.optical[k];
+                self.xray[(k, i, j)] += sums.xray[k];
+                self.escape[(k, i, j)] += sums.escape[k];
+                self.background[(k, i, j)] += sums.background[k];
             }
-            if percentage >= 100 {
-                break;
+            self.counts[(i, j)] += sums.counts;
+            for (k, n) in sums.failures.iter().enumerate() {
+                self.failures[(k, i, j)] += n;
+            }
+            for (k, n) in sums.terminations.iter().enumerate() {
+                self.terminations[(k, i, j)] += n;
+            }
+            self.affine[(i, j)] += sums.affine;
+            for (k, light) in sums.disk_spectrum.iter().enumerate() {
+                self.disk_spectrum[(k, i, j)] += light;
+            }
+            for (k, light) in sums.scattered_spectrum.iter().enumerate() {
+                self.scattered_spectrum[(k, i, j)] += light;
+            }
+            for (k, light) in sums.bands.iter().enumerate() {
+                self.bands[(k, i, j)] += light;
+            }
+        }
+    }
+
+    pub fn merge_from(&mut self, other: &Self) {
+        // An empty accumulator takes the layers of the first one merged into it
+        if self.photon_count == 0 {
+            *self = other.empty_like();
+        }
+        self.optical += &other.optical;
+        self.xray += &other.xray;
+        self.counts += &other.counts;
+        self.failures += &other.failures;
+        self.terminations += &other.terminations;
+        self.escape += &other.escape;
+        self.affine += &other.affine;
+        self.background += &other.background;
+        self.disk_spectrum += &other.disk_spectrum;
+        self.scattered_spectrum += &other.scattered_spectrum;
+        self.bands += &other.bands;
+        self.photon_count += other.photon_count;
+    }
+
+    /// Termination counts keyed by the name of the reason.
+    pub fn termination_stats(&self) -> serde_json::Value {
+        layer_totals(&self.terminations, Termination::ALL.iter().map(|t| t.name()))
+    }
+
+    /// Failure counts keyed by the name of the failure.
+    pub fn failure_stats(&self) -> serde_json::Value {
+        layer_totals(&self.failures, Failure::ALL.iter().map(|f| f.name()))
+    }
+
+    /// Fraction of the photons traced in each pixel that stopped for each reason.
+    pub fn termination_fractions(&self) -> Array3<f64> {
+        image::normalise(&self.terminations, &self.terminations.sum_axis(Axis(0)))
+    }
+
+    /// Polar angle θ and azimuth φ of the mean escape direction in each pixel, in radians, as a
+    /// (2, row, column) array. NaN where no photon escaped.
+    pub fn escape_angles(&self) -> Array3<f64> {
+        let mut angles = Array3::from_elem((2, HEIGHT, WIDTH), f64::NAN);
+        for i in 0..HEIGHT {
+            for j in 0..WIDTH {
+                let v = [self.escape[(0, i, j)], self.escape[(1, i, j)], self.escape[(2, i, j)]];
+                let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+                if length > 0.0 {
+                    angles[(0, i, j)] = (v[2] / length).clamp(-1.0, 1.0).acos();
+                    angles[(1, i, j)] = v[1].atan2(v[0]);
+                }
+            }
+        }
+        angles
+    }
+
+    /// Mean affine length of the photons traced in each pixel.
+    pub fn mean_affine(&self) -> Array2<f64> {
+        let traced = self.terminations.sum_axis(Axis(0));
+        Zip::from(&self.affine).and(&traced).map_collect(|&affine, &n| if n > 0.0 { affine / n } else { 0.0 })
+    }
+
+    /// Print how the photons ended, how many failed each health check, the pixel where most
+    /// failed, and how many pixels were left without a sample.
+    pub fn print_summary(&self) {
+        let fraction = |n: f64| 100.0 * n / self.photon_count.max(1) as f64;
+        println!("Photon terminations ({} photons):", self.photon_count);
+        for t in Termination::ALL {
+            let total = self.terminations.index_axis(Axis(0), t as usize).sum();
+            println!("  {}: {} ({:.3}%)", t.name(), total, fraction(total));
+        }
+        println!("Photon health:");
+        for f in Failure::ALL {
+            let layer = self.failures.index_axis(Axis(0), f as usize);
+            let total = layer.sum();
+            if total == 0.0 {
+                println!("  {}: none", f.name());
+                continue;
+            }
+            let ((i, j), worst) = layer.indexed_iter()
+                .fold(((0, 0), 0.0), |best, (pixel, &n)| if n > best.1 { (pixel, n) } else { best });
+            println!(
+                "  {}: {} ({:.3}%){}, most at pixel ({}, {}) with {}",
+                f.name(), total, fraction(total),
+                if f.quarantined() { ", left out of the image" } else { "" }, i, j, worst
+            );
+        }
+        let empty = self.counts.iter().filter(|&&n| n == 0.0).count();
+        if empty > 0 {
+            println!("  {} pixels have no samples and are 0 in the images and .npy output", empty);
+        }
+    }
+
+    /// Write the counts-normalised images to `<base>-optical.npy` and `<base>-xray.npy`, plus
+    /// the per-pixel maps: samples, failure counts, termination fractions, escape angles and mean affine
+    /// length, and the colour table `<base>-colours.csv` (see `image::colour_table`). The
+    /// background image `<base>-background.npy` is only written if there is one.
+    /// With an energy grid, the spectral cubes go to `<base>-disk-spectrum.npy` and
+    /// `<base>-scattered-spectrum.npy`, the bin edges to `<base>-energies.npy`, and the spectra
+    /// summed over the image to `<base>-spectrum.csv`. Images through filters go to
+    /// `<base>-bands.npy`, one layer per filter, with the filter names in order in
+    /// `<base>-bands.txt`.
+    pub fn save(&self, base: &str) -> Result<()> {
+        write_npy(format!("{}-optical.npy", base), &image::normalise(&self.optical, &self.counts))?;
+        write_npy(format!("{}-xray.npy", base), &image::normalise(&self.xray, &self.counts))?;
+        write_npy(format!("{}-counts.npy", base), &self.counts)?;
+        write_npy(format!("{}-failures.npy", base), &self.failures)?;
+        write_npy(format!("{}-terminations.npy", base), &self.termination_fractions())?;
+        write_npy(format!("{}-escape.npy", base), &self.escape_angles())?;
+        write_npy(format!("{}-affine.npy", base), &self.mean_affine())?;
+        fs::write(format!("{}-colours.csv", base), image::colour_table())?;
+        if image::has_light(&self.background) {
+            write_npy(format!("{}-background.npy", base), &image::normalise(&self.background, &self.counts))?;
+        }
+        if let Some(grid) = &self.energy_grid {
+            let disk = image::normalise(&self.disk_spectrum, &self.counts);
+            let scattered = image::normalise(&self.scattered_spectrum, &self.counts);
+            let edges = grid.edges();
+            write_npy(format!("{}-energies.npy", base), &Array1::from(edges.to_vec()))?;
+            write_npy(format!("{}-disk-spectrum.npy", base), &disk)?;
+            write_npy(format!("{}-scattered-spectrum.npy", base), &scattered)?;
+            let mut table = "energy_low_ev,energy_high_ev,disk,scattered\n".to_owned();
+            for (k, (disk, scattered)) in image_totals(&disk).into_iter().zip(image_totals(&scattered)).enumerate() {
+                table += &format!("{},{},{},{}\n", edges[k], edges[k + 1], disk, scattered);
+            }
+            fs::write(format!("{}-spectrum.csv", base), table)?;
+        }
+        if !self.band_names.is_empty() {
+            write_npy(format!("{}-bands.npy", base), &image::normalise(&self.bands, &self.counts))?;
+            fs::write(format!("{}-bands.txt", base), self.band_names.join("\n") + "\n")?;
+        }
+        Ok(())
+    }
+
+    /// Write a FITS file whose primary image is the total optical intensity, followed by the
+    /// optical and X-ray colour cubes, the number of samples per pixel, the number of failed
+    /// photons per pixel (one plane per kind of failure), the fraction of photons ending each
+    /// way, the escape angles, the mean affine length and the background, if any, then the
+    /// spectral cubes and the edges of their energy bins, if any, and an image through each
+    /// filter, named `BAND_<filter>`. Every image gets the same header `cards`.
+    pub fn save_fits(&self, path: &str, cards: &[Card]) -> Result<()> {
+        let optical = image::normalise(&self.optical, &self.counts);
+        let xray = image::normalise(&self.xray, &self.counts);
+        let intensity = optical.sum_axis(Axis(0));
+        let terminations = self.termination_fractions();
+        let escape = self.escape_angles();
+        let affine = self.mean_affine();
+        let hdu = |name, shape: &[usize], data: Vec<f64>, unit: &str| {
+            let mut all_cards = vec![Card::new("BUNIT", Value::Str(unit.to_owned()), "")];
+            all_cards.extend(cards.iter().map(|c| Card::from_raw(c.text())));
+            Hdu { name, shape: shape.to_vec(), data, cards: all_cards }
+        };
+        let mut hdus = vec![
+            hdu("INTENSITY", intensity.shape(), intensity.iter().cloned().collect(), "arbitrary"),
+            hdu("OPTICAL", optical.shape(), optical.iter().cloned().collect(), "arbitrary"),
+            hdu("XRAY", xray.shape(), xray.iter().cloned().collect(), "arbitrary"),
+            hdu("COUNTS", self.counts.shape(), self.counts.iter().cloned().collect(), "photons"),
+            hdu("FAILURES", self.failures.shape(), self.failures.iter().cloned().collect(), "photons"),
+            hdu("TERMINATIONS", terminations.shape(), terminations.iter().cloned().collect(), "fraction"),
+            hdu("ESCAPE", escape.shape(), escape.iter().cloned().collect(), "rad"),
+            hdu("AFFINE", affine.shape(), affine.iter().cloned().collect(), "arbitrary"),
+        ];
+        if image::has_light(&self.background) {
+            let background = image::normalise(&self.background, &self.counts);
+            hdus.push(hdu("BACKGROUND", background.shape(), background.iter().cloned().collect(), "arbitrary"));
+        }
+        if let Some(grid) = &self.energy_grid {
+            let disk = image::normalise(&self.disk_spectrum, &self.counts);
+            let scattered = image::normalise(&self.scattered_spectrum, &self.counts);
+            hdus.push(hdu("DISKSPEC", disk.shape(), disk.iter().cloned().collect(), "arbitrary"));
+            hdus.push(hdu("SCATSPEC", scattered.shape(), scattered.iter().cloned().collect(), "arbitrary"));
+            hdus.push(hdu("ENERGIES", &[grid.bins + 1], grid.edges().to_vec(), "eV"));
+        }
+        let bands = image::normalise(&self.bands, &self.counts);
+        let band_hdus: Vec<String> = self.band_names.iter().map(|name| format!("BAND_{}", name)).collect();
+        for (hdu_name, band) in band_hdus.iter().zip(bands.outer_iter()) {
+            hdus.push(hdu(hdu_name, band.shape(), band.iter().cloned().collect(), "arbitrary"));
+        }
+        fits::write(path, &hdus)?;
+        Ok(())
+    }
+
+    /// Write `<base>-optical.png`, `<base>-xray.png` and `<base>-tog.png`, the optical and X-ray
+    /// light together, plus linear `<base>-optical.exr` and `<base>-xray.exr`, and the same for
+    /// the background. X-ray and background images are skipped when there is no such light.
+    /// Failures are reported but do not lose the render.
+    pub fn save_images(&self, base: &str, tone_map: &ToneMap) {
+        let optical = image::normalise(&self.optical, &self.counts);
+        let xray = image::normalise(&self.xray, &self.counts);
+        let mut result = image::write_png(&format!("{}-optical.png", base), &optical, tone_map)
+            .and_then(|_| image::write_exr(&format!("{}-optical.exr", base), &optical));
+        if image::has_light(&xray) {
+            result = result
+                .and_then(|_| image::write_png(&format!("{}-xray.png", base), &image::to_xray(&xray), tone_map))
+                .and_then(|_| image::write_exr(&format!("{}-xray.exr", base), &xray))
+                .and_then(|_| image::write_png(&format!("{}-tog.png", base), &image::composite(&optical, &xray), tone_map));
+        }
+        if image::has_light(&self.background) {
+            // Most of a starfield is dark, so the usual white point would saturate every star
+            let sky_tone_map = ToneMap { white_percentile: tone_map.white_percentile.max(BACKGROUND_WHITE_PERCENTILE), ..*tone_map };
+            let background = image::normalise(&self.background, &self.counts);
+            result = result
+                .and_then(|_| image::write_png(&format!("{}-background.png", base), &background, &sky_tone_map))
+                .and_then(|_| image::write_exr(&format!("{}-background.exr", base), &background));
+        }
+        if let Err(e) = result {
+            println!("WARNING: could not write images: {}", e);
+        }
+    }
+
+    /// Write the raw sums and counts to the directory `dir`, one `.npy` file per layer, plus
+    /// `metadata` as key=value lines in `metadata.txt`. The files are written to `<dir>.tmp`,
+    /// which then replaces `dir` in one rename, so a crash leaves either the old sums or the new
+    /// ones and never a mix of the two. The old directory is moved to `<dir>.old` for the
+    /// moment in between, and `read_raw` falls back to it.
+    pub fn write_raw(&self, dir: &str, metadata: &str) -> Result<()> {
+        let (temporary, old) = (format!("{}.tmp", dir), format!("{}.old", dir));
+        remove_raw(&temporary)?;
+        fs::create_dir_all(&temporary)?;
+        let path = |layer: &str| format!("{}/{}", temporary, layer);
+        write_npy(path("optical.npy"), &self.optical)?;
+        write_npy(path("xray.npy"), &self.xray)?;
+        write_npy(path("counts.npy"), &self.counts)?;
+        write_npy(path("failures.npy"), &self.failures)?;
+        write_npy(path("terminations.npy"), &self.terminations)?;
+        write_npy(path("escape.npy"), &self.escape)?;
+        write_npy(path("affine.npy"), &self.affine)?;
+        write_npy(path("background.npy"), &self.background)?;
+        let edges = self.energy_grid.as_ref().map_or(Vec::new(), |grid| grid.edges().to_vec());
+        write_npy(path("energies.npy"), &Array1::from(edges))?;
+        write_npy(path("disk-spectrum.npy"), &self.disk_spectrum)?;
+        write_npy(path("scattered-spectrum.npy"), &self.scattered_spectrum)?;
+        write_npy(path("bands.npy"), &self.bands)?;
+        fs::write(path("bands.txt"), self.band_names.iter().map(|n| format!("{}\n", n)).collect::<String>())?;
+        fs::write(path("metadata.txt"), metadata)?;
+        remove_raw(&old)?;
+        match fs::rename(dir, &old) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {},
+        }
+        fs::rename(&temporary, dir)?;
+        remove_raw(&old)
+    }
+
+    /// Read the directory written by `write_raw`, or the one it was replacing if a crash came
+    /// between the two renames. Returns `None` if there is neither.
+    pub fn read_raw(dir: &str) -> Result<Option<(Self, HashMap<String, String>)>> {
+        match Self::read_raw_dir(dir)? {
+            Some(raw) => Ok(Some(raw)),
+            None => Self::read_raw_dir(&format!("{}.old", dir)),
+        }
+    }
+
+    fn read_raw_dir(dir: &str) -> Result<Option<(Self, HashMap<String, String>)>> {
+        let text = match fs::read_to_string(format!("{}/metadata.txt", dir)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let metadata: HashMap<String, String> = text.lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+        let path = |layer: &str| format!("{}/{}", dir, layer);
+        let edges: Array1<f64> = read_npy(path("energies.npy"))?;
+        let band_names = fs::read_to_string(path("bands.txt"))?.lines().map(|line| line.to_owned()).collect();
+        let empty = Self::new().with_bands(band_names);
+        let empty = match edges.len() {
+            0 => empty,
+            _ => empty.with_spectra(EnergyGrid::from_edges(&edges.to_vec())?),
+        };
+        let image = Self {
+            optical: read_npy(path("optical.npy"))?,
+            xray: read_npy(path("xray.npy"))?,
+            counts: read_npy(path("counts.npy"))?,
+            failures: read_npy(path("failures.npy"))?,
+            terminations: read_npy(path("terminations.npy"))?,
+            escape: read_npy(path("escape.npy"))?,
+            affine: read_npy(path("affine.npy"))?,
+            background: read_npy(path("background.npy"))?,
+            energy_grid: empty.energy_grid,
+            disk_spectrum: read_npy(path("disk-spectrum.npy"))?,
+            scattered_spectrum: read_npy(path("scattered-spectrum.npy"))?,
+            bands: read_npy(path("bands.npy"))?,
+            band_names: empty.band_names,
+            photon_count: field(&metadata, "photon_count", dir)?,
+        };
+        Ok(Some((image, metadata)))
+    }
+}
+
+/// The light of one chunk of photons, summed pixel by pixel by the worker that traced them. A
+/// chunk's photons land on few pixels, so only those are kept.
+struct ChunkSums {
+    pixels: Vec<PixelSums>,
+    /// Where each pixel's sums are in `pixels`
+    index: HashMap<(usize, usize), usize>,
+    energy_grid: Option<EnergyGrid>,
+    bands: usize,
+    photon_count: usize,
+}
+
+/// The sums of `Accumulator` for a single pixel.
+struct PixelSums {
+    pixel: (usize, usize),
+    optical: [f64; 3],
+    xray: [f64; 3],
+    counts: f64,
+    failures: [f64; Failure::ALL.len()],
+    terminations: [f64; Termination::ALL.len()],
+    escape: [f64; 3],
+    affine: f64,
+    background: [f64; 3],
+    disk_spectrum: Vec<f64>,
+    scattered_spectrum: Vec<f64>,
+    bands: Vec<f64>,
+}
+
+impl ChunkSums {
+    /// Sums with nothing in them, with spectra on `energy_grid` if there is one and `bands`
+    /// filters.
+    fn new(energy_grid: Option<EnergyGrid>, bands: usize) -> Self {
+        Self { pixels: Vec::new(), index: HashMap::new(), energy_grid, bands, photon_count: 0 }
+    }
+
+    /// Add the light of `p`, seen through `filters`, which must be those the sums were made for.
+    fn add(&mut self, p: PhotonData, skybox: Option<&Skybox>, filters: &[Bandpass]) {
+        self.photon_count += 1;
+        let bins = self.energy_grid.as_ref().map_or(0, |grid| grid.bins);
+        let pixels = &mut self.pixels;
+        let slot = *self.index.entry(p.pixel).or_insert_with(|| {
+            pixels.push(PixelSums {
+                pixel: p.pixel,
+                optical: [0.0; 3],
+                xray: [0.0; 3],
+                counts: 0.0,
+                failures: [0.0; Failure::ALL.len()],
+                terminations: [0.0; Termination::ALL.len()],
+                escape: [0.0; 3],
+                affine: 0.0,
+                background: [0.0; 3],
+                disk_spectrum: vec![0.0; bins],
+                scattered_spectrum: vec![0.0; bins],
+                bands: vec![0.0; self.bands],
+            });
+            pixels.len() - 1
+        });
+        let sums = &mut pixels[slot];
+        sums.terminations[p.termination as usize] += 1.0;
+        sums.affine += p.affine_length;
+        if let Some(direction) = p.escape_direction.filter(|_| !p.scattered) {
+            for (k, component) in direction.iter().enumerate() {
+                sums.escape[k] += component;
             }
-            senders[msg.thread_index].send(ToThread::Photons(self.observer.next_photons())).unwrap(); // Start new results
+        }
+        if let Some(failure) = p.failure {
+            sums.failures[failure as usize] += 1.0;
+            if failure.quarantined() {
+                return;
+            }
+        }
+        sums.optical[0] += p.optical_color.0;
+        sums.optical[1] += p.optical_color.1;
+        sums.optical[2] += p.optical_color.2;
+        sums.xray[0] += p.xray_color.0;
+        sums.xray[1] += p.xray_color.1;
+        sums.xray[2] += p.xray_color.2;
+        sums.counts += 1.0;
+        if let (Some(skybox), Some(direction)) = (skybox, p.escape_direction) {
+            let color = skybox.sample(direction);
+            sums.background[0] += color.0 * p.transmission;
+            sums.background[1] += color.1 * p.transmission;
+            sums.background[2] += color.2 * p.transmission;
+        }
+        for (k, filter) in filters.iter().enumerate() {
+            sums.bands[k] += p.emission.iter().map(|&(temp, lum)| lum * filter.blackbody(temp)).sum::<f64>();
+        }
+        if let Some(grid) = &self.energy_grid {
+            let spectrum = if p.scattered { &mut sums.scattered_spectrum } else { &mut sums.disk_spectrum };
+            for (temp, lum) in p.emission {
+                grid.add_blackbody(spectrum, temp, lum);
+            }
+        }
+    }
+}
+
+/// Remove a directory of raw sums, if it is there.
+pub fn remove_raw(dir: &str) -> Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Sum of each plane of `cube` over the pixels that have samples.
+fn image_totals(cube: &Array3<f64>) -> Vec<f64> {
+    cube.outer_iter()
+        .map(|plane| plane.iter().filter(|v| v.is_finite()).sum())
+        .collect()
+}
+
+/// Totals of each plane of `layers`, keyed by `names`.
+fn layer_totals<'a>(layers: &Array3<f64>, names: impl Iterator<Item = &'a str>) -> serde_json::Value {
+    names.enumerate()
+        .map(|(k, name)| (name.to_owned(), (layers.index_axis(Axis(0), k).sum() as usize).into()))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Christoffel, Schwarzschild, State};
+    use crate::observer::{Photon, Simple};
+    use crate::util::{mix_seed, Matrix4, Vec4};
 
-            self.add(msg.results);
+    const PHOTONS: usize = CHUNK_SIZE + CHUNK_SIZE / 2; // Ends partway through a chunk
+    const DTAU: f64 = 0.1;
+    const MAX_ITERATIONS: usize = 20_000;
+
+    /// Hands out PHOTONS rays spread over the whole image, instead of a full render. The photons
+    /// in `doomed` start at time `DOOMED`, where `Fragile` panics.
+    struct Sparse {
+        camera: Simple<Schwarzschild>,
+        progress: usize,
+        doomed: BTreeSet<usize>,
+    }
+
+    const DOOMED: f64 = 1e5;
+
+    /// Schwarzschild, except that it panics for photons starting at time `DOOMED`.
+    #[derive(Debug, Copy, Clone)]
+    struct Fragile(Schwarzschild);
+
+    impl Metric for Fragile {
+        fn get_state(&self, pos: Vec4) -> State { self.0.get_state(pos) }
+        fn christoffel(&self, pos: Vec4) -> Christoffel { self.0.christoffel(pos) }
+        fn get_metric(&self, pos: Vec4) -> Matrix4 {
+            assert!(pos[0] < DOOMED, "doomed photon");
+            self.0.get_metric(pos)
         }
-        
-        // Clean up
-        for sender in senders {
-            sender.send(ToThread::Terminate()).unwrap();
+        fn get_horizon(&self) -> f64 { self.0.get_horizon() }
+        fn name(&self) -> &'static str { self.0.name() }
+        fn parameters(&self) -> Vec<(&'static str, f64)> { self.0.parameters() }
+        fn spherical(&self) -> bool { self.0.spherical() }
+    }
+
+    impl Observer for Sparse {
+        fn update(&mut self, _photon_data: &[PhotonData]) -> u32 { 0 }
+
+        fn next_photons(&mut self, seed: u64) -> [Option<Photon>; PHOTON_BATCH_SIZE] {
+            let first = self.progress;
+            self.progress += PHOTON_BATCH_SIZE;
+            std::array::from_fn(|i| {
+                let index = first + i;
+                // The R2 low-discrepancy sequence over the image
+                let point = ((index as f64 * 0.754_877_666).fract() * HEIGHT as f64, (index as f64 * 0.569_840_291).fract() * WIDTH as f64);
+                let photon = self.camera.photon_through(point, fastrand::Rng::with_seed(mix_seed(seed, index as u64)));
+                let photon = if self.doomed.contains(&index) { Photon::new([DOOMED, 10.0, 1.27, 0.0], (0, 0), [1.0, -1.0, 0.0, 0.0], fastrand::Rng::new()) } else { photon };
+                (index < PHOTONS).then_some(photon)
+            })
         }
-        for thread in threads {
-            thread.join().unwrap();
+
+        fn progress(&self) -> usize { self.progress }
+        fn set_progress(&mut self, num_counts: usize) { self.progress = num_counts; }
+        fn num_photons(&self) -> usize { PHOTONS }
+        fn describe(&self) -> String { format!("Sparse {{ photons: {}, camera: {} }}", PHOTONS, self.camera.describe()) }
+        fn parameters(&self) -> Vec<(&'static str, f64)> { self.camera.parameters() }
+        fn distance(&self) -> f64 { self.camera.distance() }
+        fn pixel_scale(&self) -> f64 { self.camera.pixel_scale() }
+        fn photon_through(&self, point: (f64, f64), rng: fastrand::Rng) -> Photon { self.camera.photon_through(point, rng) }
+    }
+
+    /// An engine for a small render written to its own directory under the system's temporary one.
+    fn engine(directory: &str, threads: usize) -> Engine<Sparse> {
+        let theta: f64 = 1.27;
+        let camera = Simple::new([10.0, theta, 0.0], [-theta.sin(), 0.0, -theta.cos()], Schwarzschild::new());
+        Engine::new(Sparse { camera, progress: 0, doomed: BTreeSet::new() }, "test".to_owned())
+            .with_directory(directory)
+            .with_seed(7)
+            .with_threads(threads)
+    }
+
+    fn directory(name: &str) -> String {
+        let directory = std::env::temp_dir().join(format!("raytracer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory.to_string_lossy().into_owned()
+    }
+
+    fn assert_same(a: &Accumulator, b: &Accumulator) {
+        assert_eq!(a.photon_count, b.photon_count);
+        for (name, x, y) in [("optical", &a.optical, &b.optical), ("xray", &a.xray, &b.xray), ("escape", &a.escape, &b.escape),
+            ("terminations", &a.terminations, &b.terminations), ("failures", &a.failures, &b.failures)] {
+            assert!(x.iter().zip(y.iter()).all(|(x, y)| x.to_bits() == y.to_bits()), "{} differs", name);
         }
-        // Consume the very last photons
-        while let Ok(msg) = from_threads_receiver.try_recv() {
-            // Update the observer with new info
-            self.observer.update(&msg.results);
-            self.add(msg.results);
+        for (name, x, y) in [("counts", &a.counts, &b.counts), ("affine", &a.affine, &b.affine)] {
+            assert!(x.iter().zip(y.iter()).all(|(x, y)| x.to_bits() == y.to_bits()), "{} differs", name);
         }
+    }
+
+    #[test]
+    fn resumed_run_matches_an_uninterrupted_one() {
+        let whole = directory("whole");
+        let mut reference = engine(&whole, 2);
+        reference.run(MAX_ITERATIONS, DTAU, Schwarzschild::new(), AccretionDisk::corona()).unwrap();
+        // Both the disk and the corona are seen, so both kinds of random draws are checked
+        assert!(reference.image.optical.sum() > 0.0 && reference.image.xray.sum() > 0.0);
 
-        self.save();
+        let pieces = directory("pieces");
+        let mut interrupted = engine(&pieces, 2);
+        interrupted.interrupt_after = Some(CHUNK_SIZE);
+        assert!(interrupted.run(MAX_ITERATIONS, DTAU, Schwarzschild::new(), AccretionDisk::corona()).is_err());
+        let mut resumed = engine(&pieces, 2);
+        assert!(resumed.resume().unwrap());
+        assert_eq!(resumed.observer.progress(), CHUNK_SIZE);
+        resumed.run(MAX_ITERATIONS, DTAU, Schwarzschild::new(), AccretionDisk::corona()).unwrap();
+        assert_same(&reference.image, &resumed.image);
+        assert!(fs::metadata(format!("{}/test-checkpoint", pieces)).is_err());
 
-        self.photon_count
+        let _ = fs::remove_dir_all(whole);
+        let _ = fs::remove_dir_all(pieces);
     }
 
-    fn add(&mut self, results: Vec<PhotonData>) {
-        self.photon_count += results.len();
-        for p in results {
-            self.optical[(0, p.pixel.0,p.pixel.1)] += p.optical_color.0;
-            self.optical[(1, p.pixel.0,p.pixel.1)] += p.optical_color.1;
-            self.optical[(2, p.pixel.0,p.pixel.1)] += p.optical_color.2;
-            self.xray[(0, p.pixel.0,p.pixel.1)] += p.xray_color.0;
-            self.xray[(1, p.pixel.0,p.pixel.1)] += p.xray_color.1;
-            self.xray[(2, p.pixel.0,p.pixel.1)] += p.xray_color.2;
-            self.counts[(p.pixel.0,p.pixel.1)] += 1.0;
+    #[test]
+    fn image_is_the_same_for_any_number_of_threads() {
+        let (one, many) = (directory("one-thread"), directory("many-threads"));
+        let mut single = engine(&one, 1);
+        single.run(MAX_ITERATIONS, DTAU, Schwarzschild::new(), AccretionDisk::corona()).unwrap();
+        let mut parallel = engine(&many, 4);
+        parallel.run(MAX_ITERATIONS, DTAU, Schwarzschild::new(), AccretionDisk::corona()).unwrap();
+        assert_same(&single.image, &parallel.image);
+        let _ = fs::remove_dir_all(one);
+        let _ = fs::remove_dir_all(many);
+    }
+
+    #[test]
+    fn checkpoint_of_another_scene_is_refused() {
+        let dir = directory("other-scene");
+        let mut interrupted = engine(&dir, 1);
+        interrupted.interrupt_after = Some(CHUNK_SIZE);
+        assert!(interrupted.run(MAX_ITERATIONS, DTAU, Schwarzschild::new(), AccretionDisk::corona()).is_err());
+        let mut resumed = engine(&dir, 1);
+        assert!(resumed.resume().unwrap());
+        let error = resumed.run(MAX_ITERATIONS, 2.0 * DTAU, Schwarzschild::new(), AccretionDisk::corona()).unwrap_err();
+        assert!(matches!(error, Error::InvalidScene(_)), "{}", error);
+        // The checkpoint is kept for the scene it belongs to
+        assert!(fs::metadata(format!("{}/test-checkpoint", dir)).is_ok());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn photons_that_panic_in_every_chunk_are_skipped() {
+        let dir = directory("panics");
+        let doomed = BTreeSet::from([10, CHUNK_SIZE + 10]);
+        let mut failing = engine(&dir, 2);
+        failing.observer.doomed = doomed.clone();
+        let error = failing.run(MAX_ITERATIONS, DTAU, Fragile(Schwarzschild::new()), AccretionDisk::corona()).unwrap_err();
+        assert!(matches!(error, Error::Worker(_)), "{}", error);
+        // Both chunks are recorded, not only the first, so a rerun does not stop on the second
+        let mut resumed = engine(&dir, 2);
+        assert!(resumed.resume().unwrap());
+        assert_eq!(resumed.observer.progress(), 0);
+        assert_eq!(resumed.skipped, doomed);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn quarantined_photons_add_no_light() {
+        let data = Photon::new([0.0, 10.0, 1.27, 0.0], (3, 5), [1.0, f64::NAN, 0.0, 0.0], fastrand::Rng::with_seed(1))
+            .run(MAX_ITERATIONS, DTAU, &Schwarzschild::new(), &AccretionDisk::corona());
+        // Light picked up before the path went wrong is not trusted either
+        let data = PhotonData { optical_color: (1.0, 1.0, 1.0), xray_color: (1.0, 1.0, 1.0), emission: vec![(1.0, 1.0)], ..data };
+        let grid = EnergyGrid::new(0.1, 10.0, 4).unwrap();
+        let mut sums = ChunkSums::new(Some(grid.clone()), 0);
+        sums.add(data, None, &[]);
+        let mut image = Accumulator::new().with_spectra(grid);
+        image.add_chunk(&sums);
+        assert_eq!(image.photon_count, 1);
+        assert_eq!(image.failures[(Failure::Nan as usize, 3, 5)], 1.0);
+        assert_eq!(image.terminations[(Termination::Invalid as usize, 3, 5)], 1.0);
+        assert_eq!(image.counts.sum(), 0.0);
+        for layer in [&image.optical, &image.xray, &image.disk_spectrum] {
+            assert!(layer.iter().all(|&x| x == 0.0));
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+
+/// Everything that can stop a render or a merge.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed
+    Io(std::io::Error),
+    /// A checkpoint, partial or output file could not be read or written in its format
+    Format(String),
+    /// The scene or command line asks for something that cannot be rendered
+    InvalidScene(String),
+    /// A calculation produced NaN or failed to converge
+    Numerical(String),
+    /// A worker thread panicked while tracing photons
+    Worker(String),
+    /// Shards that cannot be merged
+    Merge(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Format(s) => write!(f, "bad file: {}", s),
+            Error::InvalidScene(s) => write!(f, "invalid scene: {}", s),
+            Error::Numerical(s) => write!(f, "numerical failure: {}", s),
+            Error::Worker(s) => write!(f, "worker thread panicked: {}", s),
+            Error::Merge(s) => write!(f, "cannot merge shards: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ndarray_npy::WriteNpyError> for Error {
+    fn from(e: ndarray_npy::WriteNpyError) -> Self {
+        Error::Format(e.to_string())
+    }
+}
+
+impl From<ndarray_npy::ReadNpyError> for Error {
+    fn from(e: ndarray_npy::ReadNpyError) -> Self {
+        Error::Format(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Format(e.to_string())
+    }
+}
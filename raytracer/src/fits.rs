@@ -0,0 +1,240 @@
+use std::fs::File;
+use std::io::{Write, BufWriter};
+
+// Minimal FITS writer: a primary image and any number of IMAGE extensions, all stored as 64-bit
+// floats. See the FITS standard 4.0 (https://fits.gsfc.nasa.gov/fits_standard.html).
+
+const BLOCK: usize = 2880;
+const CARD: usize = 80;
+
+// G M_sun / c^2 in metres, parsecs in metres, and microarcseconds per radian
+const SOLAR_GRAV_RADIUS: f64 = 1476.625;
+const PARSEC: f64 = 3.0857e16;
+const UAS_PER_RAD: f64 = 206264.806247e6;
+
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+}
+
+/// One 80-character header record
+pub struct Card(String);
+
+impl Card {
+    /// Keywords longer than eight characters are written with the HIERARCH convention, which
+    /// astropy and DS9 both read. Headers cannot hold NaN or infinity as numbers, so those are
+    /// written as the strings 'NaN', 'inf' or '-inf', with a warning.
+    pub fn new(key: &str, value: Value, comment: &str) -> Self {
+        let value = match value {
+            Value::Int(i) => format!("{:>20}", i),
+            Value::Float(f) if f.is_finite() => format!("{:>20}", format!("{:.12E}", f)),
+            Value::Float(f) => {
+                println!("WARNING: FITS card {} is {}, which is written as a string", key, f);
+                format!("'{:<8}'", f)
+            },
+            Value::Str(s) => format!("'{:<8}'", s.replace('\'', "''")),
+            Value::Bool(b) => format!("{:>20}", if b { "T" } else { "F" }),
+        };
+        let key = key.to_uppercase();
+        let mut text = if key.len() <= 8 && key.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+            format!("{:<8}= {}", key, value)
+        } else {
+            format!("HIERARCH {} = {}", key, value.trim_start())
+        };
+        if !comment.is_empty() {
+            text = format!("{} / {}", text, comment);
+        }
+        Self::from_raw(&text)
+    }
+
+    /// Make a card from its text, padding or truncating it to 80 characters.
+    pub fn from_raw(text: &str) -> Self {
+        let mut text: String = text.chars().filter(|c| c.is_ascii() && !c.is_ascii_control()).take(CARD).collect();
+        while text.len() < CARD {
+            text.push(' ');
+        }
+        Self(text)
+    }
+
+    pub fn text(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Units of the world coordinates attached to each image.
+#[derive(Debug, Clone, Copy)]
+pub enum WcsUnits {
+    /// Impact parameter in gravitational radii, GM/c^2
+    GravitationalRadii,
+    /// Angle on the sky for a hole of `mass` solar masses at `distance` parsecs
+    Microarcseconds { mass: f64, distance: f64 },
+}
+
+/// World coordinate cards for an image of `width` by `height` pixels, centred on the hole.
+/// `scale` is the size of one pixel in gravitational radii, taken as `distance` times the tangent
+/// of the angle between pixels. That is the impact parameter only for a distant observer in flat
+/// space, and an approximation for a camera a few tens of gravitational radii out, which the
+/// header says in COMMENT cards.
+pub fn wcs_cards(units: WcsUnits, width: usize, height: usize, scale: f64, distance: f64) -> Vec<Card> {
+    let mut cards = vec![
+        Card::from_raw("COMMENT Pixel sizes are the camera distance times the tangent of its angle"),
+        Card::from_raw(&format!("COMMENT between pixels, with the camera at r = {} GM/c^2. This is", distance)),
+        Card::from_raw("COMMENT exact only for a distant observer in flat space."),
+        Card::new("CRPIX1", Value::Float((width / 2) as f64 + 1.0), "Pixel of the hole"),
+        Card::new("CRPIX2", Value::Float((height / 2) as f64 + 1.0), "Pixel of the hole"),
+        Card::new("CRVAL1", Value::Float(0.0), ""),
+        Card::new("CRVAL2", Value::Float(0.0), ""),
+    ];
+    match units {
+        WcsUnits::GravitationalRadii => {
+            cards.push(Card::new("CTYPE1", Value::Str("X".to_owned()), "Impact parameter"));
+            cards.push(Card::new("CTYPE2", Value::Str("Y".to_owned()), "Impact parameter"));
+            cards.push(Card::new("CUNIT1", Value::Str("r_g".to_owned()), "GM/c^2"));
+            cards.push(Card::new("CUNIT2", Value::Str("r_g".to_owned()), "GM/c^2"));
+            cards.push(Card::new("CDELT1", Value::Float(scale), ""));
+            cards.push(Card::new("CDELT2", Value::Float(scale), ""));
+        },
+        WcsUnits::Microarcseconds { mass, distance } => {
+            let uas = scale * mass * SOLAR_GRAV_RADIUS / (distance * PARSEC) * UAS_PER_RAD;
+            cards.push(Card::new("CTYPE1", Value::Str("RA---TAN".to_owned()), ""));
+            cards.push(Card::new("CTYPE2", Value::Str("DEC--TAN".to_owned()), ""));
+            cards.push(Card::new("CUNIT1", Value::Str("deg".to_owned()), ""));
+            cards.push(Card::new("CUNIT2", Value::Str("deg".to_owned()), ""));
+            cards.push(Card::new("CDELT1", Value::Float(-uas / 3.6e9), "Pixel size in degrees"));
+            cards.push(Card::new("CDELT2", Value::Float(uas / 3.6e9), "Pixel size in degrees"));
+            cards.push(Card::new("PIXEL_UAS", Value::Float(uas), "Pixel size in microarcseconds"));
+            cards.push(Card::new("BH_MASS_MSUN", Value::Float(mass), ""));
+            cards.push(Card::new("BH_DISTANCE_PC", Value::Float(distance), ""));
+        },
+    }
+    cards
+}
+
+/// An image to write: its name (EXTNAME), shape with the slowest-varying axis first as in
+/// ndarray, data in that order, and extra header cards.
+pub struct Hdu<'a> {
+    pub name: &'a str,
+    pub shape: Vec<usize>,
+    pub data: Vec<f64>,
+    pub cards: Vec<Card>,
+}
+
+/// Write `hdus[0]` as the primary image and the rest as IMAGE extensions.
+pub fn write(path: &str, hdus: &[Hdu]) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for (i, hdu) in hdus.iter().enumerate() {
+        let mut header = if i == 0 {
+            vec![Card::new("SIMPLE", Value::Bool(true), "Conforms to FITS standard")]
+        } else {
+            vec![Card::new("XTENSION", Value::Str("IMAGE".to_owned()), "Image extension")]
+        };
+        header.push(Card::new("BITPIX", Value::Int(-64), "64-bit floats"));
+        header.push(Card::new("NAXIS", Value::Int(hdu.shape.len() as i64), ""));
+        // FITS lists the fastest-varying axis first
+        for (axis, length) in hdu.shape.iter().rev().enumerate() {
+            header.push(Card::new(&format!("NAXIS{}", axis + 1), Value::Int(*length as i64), ""));
+        }
+        if i == 0 {
+            header.push(Card::new("EXTEND", Value::Bool(true), ""));
+        } else {
+            header.push(Card::new("PCOUNT", Value::Int(0), ""));
+            header.push(Card::new("GCOUNT", Value::Int(1), ""));
+        }
+        header.push(Card::new("EXTNAME", Value::Str(hdu.name.to_owned()), ""));
+
+        let mut written = 0;
+        for card in header.iter().chain(hdu.cards.iter()) {
+            file.write_all(card.text().as_bytes())?;
+            written += CARD;
+        }
+        file.write_all(Card::from_raw("END").text().as_bytes())?;
+        written += CARD;
+        pad(&mut file, written, b' ')?;
+
+        for value in &hdu.data {
+            file.write_all(&value.to_be_bytes())?;
+        }
+        pad(&mut file, hdu.data.len() * 8, 0)?;
+    }
+    file.flush()
+}
+
+fn pad(file: &mut impl Write, written: usize, byte: u8) -> std::io::Result<()> {
+    let remainder = written % BLOCK;
+    if remainder != 0 {
+        file.write_all(&vec![byte; BLOCK - remainder])?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The header cards of each HDU in `bytes`, and its data read back as big-endian floats.
+    fn read(bytes: &[u8]) -> Vec<(Vec<String>, Vec<f64>)> {
+        let mut hdus = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let mut cards = Vec::new();
+            loop {
+                let card = std::str::from_utf8(&bytes[offset..offset + CARD]).unwrap().to_owned();
+                offset += CARD;
+                if card.trim_end() == "END" {
+                    break;
+                }
+                cards.push(card);
+            }
+            offset = offset.div_ceil(BLOCK) * BLOCK;
+            let value = |key: &str| -> usize {
+                let card = cards.iter().find(|c| c[..8].trim_end() == key).unwrap();
+                card[10..30].trim().parse().unwrap()
+            };
+            let length: usize = (1..=value("NAXIS")).map(|axis| value(&format!("NAXIS{}", axis))).product();
+            let data = bytes[offset..offset + 8 * length].chunks(8).map(|b| f64::from_be_bytes(b.try_into().unwrap())).collect();
+            offset = (offset + 8 * length).div_ceil(BLOCK) * BLOCK;
+            hdus.push((cards, data));
+        }
+        hdus
+    }
+
+    #[test]
+    fn written_file_reads_back() {
+        let path = std::env::temp_dir().join(format!("raytracer-fits-{}.fits", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let image: Vec<f64> = (0..6).map(|i| i as f64 * 0.5).collect();
+        let cube: Vec<f64> = (0..24).map(|i| -(i as f64)).collect();
+        let mut cards = wcs_cards(WcsUnits::GravitationalRadii, 3, 2, 0.25, 20.0);
+        cards.push(Card::new("DISK_RATIO", Value::Float(f64::NAN), "Not a number"));
+        write(&path, &[
+            Hdu { name: "TOTAL", shape: vec![2, 3], data: image.clone(), cards },
+            Hdu { name: "CUBE", shape: vec![4, 2, 3], data: cube.clone(), cards: Vec::new() },
+        ]).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(bytes.len() % BLOCK, 0);
+        let hdus = read(&bytes);
+        assert_eq!(hdus.len(), 2);
+        let (primary, extension) = (&hdus[0], &hdus[1]);
+        assert!(primary.0[0].starts_with("SIMPLE  =                    T"));
+        assert!(extension.0[0].starts_with("XTENSION= 'IMAGE   '"));
+        for (cards, shape) in [(&primary.0, ["3", "2", ""]), (&extension.0, ["3", "2", "4"])] {
+            assert!(cards.iter().all(|c| c.len() == CARD));
+            assert!(cards.iter().any(|c| c.starts_with("BITPIX  =                  -64")));
+            // The fastest-varying axis comes first
+            for (axis, length) in shape.iter().enumerate().filter(|(_, l)| !l.is_empty()) {
+                let key = format!("NAXIS{}", axis + 1);
+                assert!(cards.iter().any(|c| c[..8].trim_end() == key && c[10..30].trim() == *length), "{} is not {}", key, length);
+            }
+        }
+        assert_eq!(primary.1, image);
+        assert_eq!(extension.1, cube);
+        assert!(primary.0.iter().any(|c| c.starts_with("CDELT1  =") && c[10..30].trim().parse::<f64>() == Ok(0.25)));
+        assert!(primary.0.iter().any(|c| c.starts_with("COMMENT ")));
+        assert!(primary.0.iter().any(|c| c.starts_with("HIERARCH DISK_RATIO = 'NaN     '")));
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::fmt::Write;
+use ndarray::{Array3, Array2, Axis};
+
+use crate::observer::PhotonData;
+
+// Pictures made straight from the accumulated arrays: linear HDR OpenEXR, and tone-mapped PNG
+// following what imager/image.py does with the .npy output.
+
+/// Weight of the X-ray image relative to the optical one in composites
+pub const X_RAY_WEIGHT: f64 = 12.0;
+/// Columns either side of the centre that are replaced by their neighbours, hiding the artefact
+/// from rays passing along the pole. Zero to keep them.
+pub const REMOVE_POLE: usize = 1;
+/// Temperatures in the colour table written with each render, in kelvin
+const COLOUR_TABLE_MIN_TEMP: f64 = 1000.0;
+const COLOUR_TABLE_MAX_TEMP: f64 = 20000.0;
+const COLOUR_TABLE_STEP: f64 = 100.0;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stretch {
+    Linear,
+    /// log(1 + a x) / log(1 + a)
+    Log(f64),
+    /// asinh(x / s) / asinh(1 / s)
+    Asinh(f64),
+}
+
+/// How linear intensities are turned into pixel values.
+#[derive(Debug, Clone, Copy)]
+pub struct ToneMap {
+    /// Percentile of the pixel values mapped to white before exposure is applied
+    pub white_percentile: f64,
+    pub exposure: f64,
+    pub stretch: Stretch,
+    pub gamma: f64,
+    pub sixteen_bit: bool,
+}
+
+impl Default for ToneMap {
+    fn default() -> Self {
+        Self {
+            white_percentile: 90.0,
+            exposure: 1.0,
+            stretch: Stretch::Linear,
+            gamma: 1.0,
+            sixteen_bit: false,
+        }
+    }
+}
+
+impl ToneMap {
+    /// Map an image with values in [0, 1] after white point and exposure to [0, 1].
+    fn apply(&self, value: f64) -> f64 {
+        let value = value.max(0.0);
+        let value = match self.stretch {
+            Stretch::Linear => value,
+            Stretch::Log(a) => (1.0 + a * value).ln() / (1.0 + a).ln(),
+            Stretch::Asinh(s) => (value / s).asinh() / (1.0 / s).asinh(),
+        };
+        value.clamp(0.0, 1.0).powf(1.0 / self.gamma)
+    }
+}
+
+/// The colour of a blackbody at each temperature, as drawn in the images, one row per
+/// `COLOUR_TABLE_STEP` kelvin with the columns temperature_k, red, green and blue. This is what
+/// imager/image.py draws its colour bars from.
+pub fn colour_table() -> String {
+    let mut table = "temperature_k,red,green,blue\n".to_owned();
+    let steps = ((COLOUR_TABLE_MAX_TEMP - COLOUR_TABLE_MIN_TEMP) / COLOUR_TABLE_STEP).round() as usize;
+    for k in 0..=steps {
+        let temp = COLOUR_TABLE_MIN_TEMP + k as f64 * COLOUR_TABLE_STEP;
+        let (r, g, b) = PhotonData::temp_to_color(temp);
+        writeln!(table, "{},{},{},{}", temp, r, g, b).unwrap();
+    }
+    table
+}
+
+/// Divide a (layer, row, column) array of sums by the number of samples in each pixel. Pixels
+/// with no samples are 0; the counts say which they are.
+pub fn normalise(sums: &Array3<f64>, counts: &Array2<f64>) -> Array3<f64> {
+    let mut out = Array3::zeros(sums.dim());
+    for ((c, i, j), value) in out.indexed_iter_mut() {
+        if counts[(i, j)] > 0.0 {
+            *value = sums[(c, i, j)] / counts[(i, j)];
+        }
+    }
+    out
+}
+
+/// Put the X-ray channels in the order used for display: hard in blue, soft in red, and the
+/// middle band at half weight in green.
+pub fn to_xray(xray: &Array3<f64>) -> Array3<f64> {
+    let mut out = Array3::zeros(xray.dim());
+    out.index_axis_mut(Axis(0), 0).assign(&xray.index_axis(Axis(0), 1));
+    out.index_axis_mut(Axis(0), 1).assign(&(&xray.index_axis(Axis(0), 2) / 2.0));
+    out.index_axis_mut(Axis(0), 2).assign(&xray.index_axis(Axis(0), 0));
+    out
+}
+
+/// Optical and X-ray light in one picture, like the `-tog` images.
+pub fn composite(optical: &Array3<f64>, xray: &Array3<f64>) -> Array3<f64> {
+    optical + &(to_xray(xray) * X_RAY_WEIGHT)
+}
+
+/// Whether an image has any light in it at all.
+pub fn has_light(color: &Array3<f64>) -> bool {
+    color.iter().any(|v| v.is_finite() && *v > 0.0)
+}
+
+fn percentile(values: &Array3<f64>, p: f64) -> f64 {
+    let mut sorted: Vec<f64> = values.iter().cloned().filter(|v| v.is_finite()).collect();
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let index = (p / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+    sorted[index.min(sorted.len() - 1)]
+}
+
+/// Replace the columns along the pole with the average of the columns either side.
+fn remove_pole(color: &mut Array3<f64>) {
+    let width = color.dim().2;
+    if REMOVE_POLE == 0 || width < 2 * REMOVE_POLE + 3 {
+        return;
+    }
+    let middle = width / 2;
+    let left = color.index_axis(Axis(2), middle - REMOVE_POLE - 1).to_owned();
+    let right = color.index_axis(Axis(2), middle + REMOVE_POLE + 1).to_owned();
+    let average = (&left + &right) / 2.0;
+    for j in middle - REMOVE_POLE..=middle + REMOVE_POLE {
+        color.index_axis_mut(Axis(2), j).assign(&average);
+    }
+}
+
+/// Tone map a (channel, row, column) image and write it as an RGB PNG (or greyscale for one
+/// channel). Row 0 is the bottom of the picture.
+pub fn write_png(path: &str, color: &Array3<f64>, tone_map: &ToneMap) -> std::io::Result<()> {
+    let (channels, height, width) = color.dim();
+    let mut color = color.clone();
+    remove_pole(&mut color);
+    let white = percentile(&color, tone_map.white_percentile);
+    let scale = if white > 0.0 { tone_map.exposure / white } else { 0.0 };
+
+    let max_level = if tone_map.sixteen_bit { u16::MAX as f64 } else { u8::MAX as f64 };
+    let mut bytes = Vec::with_capacity(channels * height * width * 2);
+    for i in (0..height).rev() {
+        for j in 0..width {
+            for c in 0..channels {
+                let value = color[(c, i, j)];
+                let level = if value.is_finite() {
+                    (tone_map.apply(value * scale) * max_level).round()
+                } else {
+                    0.0
+                };
+                if tone_map.sixteen_bit {
+                    bytes.extend_from_slice(&(level as u16).to_be_bytes());
+                } else {
+                    bytes.push(level as u8);
+                }
+            }
+        }
+    }
+
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width as u32, height as u32);
+    encoder.set_color(if channels == 1 { png::ColorType::Grayscale } else { png::ColorType::Rgb });
+    encoder.set_depth(if tone_map.sixteen_bit { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&bytes)?;
+    Ok(())
+}
+
+/// Write a (3, row, column) image as linear 32-bit float RGB OpenEXR, with no tone mapping.
+pub fn write_exr(path: &str, color: &Array3<f64>) -> std::io::Result<()> {
+    let (_, height, width) = color.dim();
+    let sample = |c, x, y| {
+        let value = color[(c, height - 1 - y, x)];
+        if value.is_finite() { value as f32 } else { 0.0 }
+    };
+    exr::prelude::write_rgb_file(path, width, height, |x, y| {
+        (sample(0, x, y), sample(1, x, y), sample(2, x, y))
+    }).map_err(std::io::Error::other)
+}
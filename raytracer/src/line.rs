@@ -0,0 +1,361 @@
+use std::fs;
+use std::fmt::Write as _;
+use ndarray::Array2;
+use ndarray_npy::write_npy;
+use rayon::prelude::*;
+
+use crate::util::*;
+use crate::observer::{Observer, Simple, PIXEL_WIDTH, WIDTH, HEIGHT};
+use crate::metrics::{Metric, SPACETIME_EDGE};
+use crate::source::AccretionDisk;
+use crate::disk;
+use crate::error::{Error, Result};
+
+// Profiles of a narrow emission line from the disk, such as iron Kα, seen by a distant camera.
+// A square grid of rays is fired at the disk from a camera at rest near the edge of the scene.
+// Each time a ray crosses the disk between the inner and outer radius it picks up photons at
+// energy g E_0, where g is the shift to a camera at infinity, in proportion to
+// g^3 ε(r) A(μ): photon number intensity goes as g^3, ε is the radial emissivity and A the
+// angular law, with μ the cosine of the emission angle in the gas frame. The rays are evenly
+// spaced on the camera's image plane, so each stands for a solid angle in proportion to
+// (1 + x^2 + y^2)^(-3/2). The corona is left out, so the profile is of the light that comes
+// straight from the disk.
+//
+// For Schwarzschild the traced profile is checked against the weak-field one, which has the
+// exact shift of circular orbits, sqrt(1 - 3M/r) / (1 + Ω r sin φ sin i), but light that
+// travels in straight lines. The two agree where light bending is small: far from the hole and
+// at low inclination.
+
+const DISTANCE: f64 = 0.95 * SPACETIME_EDGE; // Radius of the camera
+const FIELD_MARGIN: f64 = 1.1; // The grid reaches this much beyond the outer edge of the disk
+const MAX_SHIFT: f64 = 1.6; // Highest g binned
+const SCHWARZSCHILD_ISCO: f64 = 3.0;
+const ANALYTIC_RADII: usize = 4000;
+const ANALYTIC_AZIMUTHS: usize = 4000;
+pub const IRON_K_ALPHA: f64 = 6.4; // keV
+
+/// How the line's emission falls with radius.
+#[derive(Clone, Copy, Debug)]
+pub enum Emissivity {
+    /// ε ∝ r^-index
+    PowerLaw { index: f64 },
+    /// ε ∝ r^-inner inside `radius` and r^-outer outside, continuous at the break
+    Broken { inner: f64, radius: f64, outer: f64 },
+}
+
+impl Emissivity {
+    pub fn at(&self, r: f64) -> f64 {
+        match *self {
+            Emissivity::PowerLaw { index } => r.powf(-index),
+            Emissivity::Broken { inner, radius, .. } if r < radius => r.powf(-inner),
+            Emissivity::Broken { inner, radius, outer } => radius.powf(outer - inner) * r.powf(-outer),
+        }
+    }
+}
+
+/// How the line's emission depends on the angle to the disk normal, in the frame of the gas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AngularLaw {
+    Isotropic,
+    /// 1 + 2.06 μ (Laor 1991)
+    LimbDarkened,
+    /// ln(1 + 1/μ) (Haardt 1993)
+    LimbBrightened,
+}
+
+impl AngularLaw {
+    pub fn named(name: &str) -> Result<Self> {
+        match name {
+            "isotropic" => Ok(AngularLaw::Isotropic),
+            "limb-darkened" => Ok(AngularLaw::LimbDarkened),
+            "limb-brightened" => Ok(AngularLaw::LimbBrightened),
+            _ => Err(Error::InvalidScene(format!("unknown angular law {}: use isotropic, limb-darkened or limb-brightened", name))),
+        }
+    }
+
+    pub fn at(&self, mu: f64) -> f64 {
+        match self {
+            AngularLaw::Isotropic => 1.0,
+            AngularLaw::LimbDarkened => 1.0 + 2.06 * mu,
+            AngularLaw::LimbBrightened => (1.0 + 1.0 / mu).ln(),
+        }
+    }
+}
+
+/// A line and the disk and camera it is seen with.
+#[derive(Clone, Debug)]
+pub struct Line {
+    /// Energy in the rest frame of the gas, in keV
+    pub energy: f64,
+    pub emissivity: Emissivity,
+    pub angular: AngularLaw,
+    /// Angle between the camera and the spin axis, in radians
+    pub inclination: f64,
+    /// Radius inside which the disk does not emit the line. Defaults to the ISCO.
+    pub inner: Option<f64>,
+    pub outer: f64,
+    /// Rays along each side of the grid
+    pub rays: usize,
+    /// Energy bins between 0 and `MAX_SHIFT` times the line energy
+    pub bins: usize,
+}
+
+impl Default for Line {
+    fn default() -> Self {
+        Self {
+            energy: IRON_K_ALPHA,
+            emissivity: Emissivity::PowerLaw { index: 3.0 },
+            angular: AngularLaw::Isotropic,
+            inclination: 30f64.to_radians(),
+            inner: None,
+            outer: 0.4 * SPACETIME_EDGE,
+            rays: 400,
+            bins: 320,
+        }
+    }
+}
+
+impl Line {
+    /// The energy bin that `energy` keV falls in, if any.
+    fn bin(&self, energy: f64) -> Option<usize> {
+        let k = (energy / (MAX_SHIFT * self.energy) * self.bins as f64).floor();
+        (k >= 0.0 && k < self.bins as f64).then_some(k as usize)
+    }
+}
+
+/// A line profile: photons per keV in each energy bin, normalised to one photon in all.
+pub struct Profile {
+    /// Bin edges in keV
+    pub edges: Vec<f64>,
+    pub flux: Vec<f64>,
+}
+
+impl Profile {
+    fn new(line: &Line, counts: Vec<f64>) -> Self {
+        let width = MAX_SHIFT * line.energy / line.bins as f64;
+        let total: f64 = counts.iter().sum();
+        Self {
+            edges: (0..=line.bins).map(|k| k as f64 * width).collect(),
+            flux: counts.iter().map(|c| c / (total * width)).collect(),
+        }
+    }
+
+    /// Centre of the brightest bin, in keV.
+    pub fn peak(&self) -> f64 {
+        let k = (0..self.flux.len()).fold(0, |best, k| if self.flux[k] > self.flux[best] { k } else { best });
+        0.5 * (self.edges[k] + self.edges[k + 1])
+    }
+
+    /// Mean photon energy in keV.
+    pub fn mean(&self) -> f64 {
+        self.flux.iter().enumerate()
+            .map(|(k, f)| f * (self.edges[k + 1] - self.edges[k]) * 0.5 * (self.edges[k] + self.edges[k + 1]))
+            .sum()
+    }
+
+    /// Fraction of the photons that would have to move to turn this profile into `other`, from
+    /// 0 for the same profile to 1 for profiles that do not overlap.
+    pub fn difference(&self, other: &Profile) -> f64 {
+        self.flux.iter().zip(&other.flux).enumerate()
+            .map(|(k, (a, b))| 0.5 * (a - b).abs() * (self.edges[k + 1] - self.edges[k]))
+            .sum()
+    }
+}
+
+/// Trace the profile of `line` from the disk of `source`. Each ray draws its random numbers from
+/// `seed` and its index, so a profile is reproducible. Returns the profile and the number of
+/// rays that saw the line.
+pub fn profile<M: Metric>(metric: M, source: &AccretionDisk, line: &Line, max_iterations: usize, dtau: f64, seed: u64) -> Result<(Profile, usize)> {
+    if !(line.energy.is_finite() && line.energy > 0.0) {
+        return Err(Error::InvalidScene(format!("line energy must be positive, not {}", line.energy)));
+    }
+    if !(line.inclination > 0.0 && line.inclination < std::f64::consts::FRAC_PI_2) {
+        return Err(Error::InvalidScene(format!("inclination must be between 0 and 90 degrees, not {}", line.inclination.to_degrees())));
+    }
+    let inner = match line.inner {
+        Some(inner) => inner,
+        None => disk::isco(&metric)?,
+    };
+    if !(inner > metric.get_horizon() && inner < line.outer && FIELD_MARGIN * line.outer < DISTANCE) {
+        return Err(Error::InvalidScene(format!(
+            "line needs the horizon < inner radius < outer radius < {}, not {} to {}", DISTANCE / FIELD_MARGIN, inner, line.outer
+        )));
+    }
+    if line.rays < 2 || line.bins == 0 {
+        return Err(Error::InvalidScene("line needs at least 2 rays a side and 1 bin".to_owned()));
+    }
+
+    let source = source.clone().without_corona();
+    let observer = Simple::new([DISTANCE, line.inclination, 0.0], [-line.inclination.sin(), 0.0, -line.inclination.cos()], metric);
+    // Shifts are recorded for the camera, which sees everything blueshifted by its own u^t
+    let camera_time = 1.0 / (-metric.get_metric([0.0, DISTANCE, line.inclination, 0.0])[0]).sqrt();
+    // Tangent of the angle the disk's outer edge is seen at
+    let half_width = FIELD_MARGIN * line.outer / (DISTANCE * DISTANCE - line.outer * line.outer).sqrt();
+    let offset = |k: usize| half_width * (2.0 * (k as f64 + 0.5) / line.rays as f64 - 1.0);
+
+    let hits: Vec<Vec<(usize, f64)>> = (0..line.rays * line.rays).into_par_iter().map(|k| {
+        let (x, y) = (offset(k % line.rays), offset(k / line.rays));
+        let point = ((HEIGHT / 2) as f64 + y / PIXEL_WIDTH, (WIDTH / 2) as f64 + x / PIXEL_WIDTH);
+        let rng = fastrand::Rng::with_seed(mix_seed(seed, k as u64));
+        let data = observer.photon_through(point, rng).run(max_iterations, dtau, &metric, &source);
+        if data.failure.is_some_and(|f| f.quarantined()) {
+            return Vec::new();
+        }
+        let solid_angle = (1.0 + x * x + y * y).powf(-1.5);
+        data.crossings.iter()
+            .filter(|c| c.radius >= inner && c.radius <= line.outer)
+            .filter_map(|c| {
+                let shift = c.shift / camera_time;
+                let weight = solid_angle * c.transmission * shift.powi(3) * line.emissivity.at(c.radius) * line.angular.at(c.cosine);
+                Some((line.bin(shift * line.energy)?, weight)).filter(|_| weight.is_finite() && weight > 0.0)
+            })
+            .collect()
+    }).collect();
+
+    // Summed in ray order, so the profile does not depend on the number of threads
+    let mut counts = vec![0.0; line.bins];
+    for (k, weight) in hits.iter().flatten() {
+        counts[*k] += weight;
+    }
+    let seen = hits.iter().filter(|h| !h.is_empty()).count();
+    if seen == 0 {
+        return Err(Error::Numerical("no ray saw the line-emitting part of the disk".to_owned()));
+    }
+    Ok((Profile::new(line, counts), seen))
+}
+
+/// The weak-field profile of `line` around a Schwarzschild hole.
+pub fn schwarzschild(line: &Line) -> Profile {
+    let mass = 0.5;
+    let inner = line.inner.unwrap_or(SCHWARZSCHILD_ISCO);
+    let (sin_i, cos_i) = line.inclination.sin_cos();
+    let log_step = (line.outer / inner).ln() / ANALYTIC_RADII as f64;
+    let mut counts = vec![0.0; line.bins];
+    for m in 0..ANALYTIC_RADII {
+        let r = inner * ((m as f64 + 0.5) * log_step).exp();
+        let orbital_speed = r * (mass / (r * r * r)).sqrt();
+        // Projected area of the ring, r dr cos i, with dr = r d(ln r)
+        let area = r * r * log_step * cos_i;
+        for n in 0..ANALYTIC_AZIMUTHS {
+            let phi = 2.0 * std::f64::consts::PI * (n as f64 + 0.5) / ANALYTIC_AZIMUTHS as f64;
+            let shift = (1.0 - 3.0 * mass / r).sqrt() / (1.0 + orbital_speed * phi.sin() * sin_i);
+            // A boost within the disk plane keeps the momentum along the normal
+            let mu = (shift * cos_i).min(1.0);
+            if let Some(k) = line.bin(shift * line.energy) {
+                counts[k] += area * shift.powi(3) * line.emissivity.at(r) * line.angular.at(mu);
+            }
+        }
+    }
+    Profile::new(line, counts)
+}
+
+/// Write `<base>-line.csv` and `<base>-line.npy`, one row per energy bin with the columns
+/// energy_low_kev, energy_high_kev and flux, and the weak-field profile if there is one.
+pub fn write(base: &str, profile: &Profile, analytic: Option<&Profile>) -> Result<()> {
+    let columns = if analytic.is_some() { 4 } else { 3 };
+    let mut table = Array2::zeros((profile.flux.len(), columns));
+    let mut csv = format!("energy_low_kev,energy_high_kev,flux{}\n", if analytic.is_some() { ",weak_field" } else { "" });
+    for (k, flux) in profile.flux.iter().enumerate() {
+        let mut values = vec![profile.edges[k], profile.edges[k + 1], *flux];
+        values.extend(analytic.map(|a| a.flux[k]));
+        for (column, value) in values.iter().enumerate() {
+            table[(k, column)] = *value;
+        }
+        let row: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        writeln!(csv, "{}", row.join(",")).unwrap();
+    }
+    fs::write(format!("{}-line.csv", base), csv)?;
+    write_npy(format!("{}-line.npy", base), &table)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Schwarzschild;
+
+    const MASS: f64 = 0.5;
+
+    fn centre(profile: &Profile, k: usize) -> f64 {
+        0.5 * (profile.edges[k] + profile.edges[k + 1])
+    }
+
+    /// The brightest bins below and above `split`, as shifts.
+    fn peaks(profile: &Profile, line: &Line, split: f64) -> (f64, f64) {
+        let brightest = |below: bool| (0..profile.flux.len())
+            .filter(|&k| (centre(profile, k) < split * line.energy) == below)
+            .fold(None, |best: Option<usize>, k| if best.is_none_or(|b| profile.flux[k] > profile.flux[b]) { Some(k) } else { best })
+            .unwrap();
+        (centre(profile, brightest(true)) / line.energy, centre(profile, brightest(false)) / line.energy)
+    }
+
+    /// Photons above shift `split` over those below it, sharing out the bin it falls in.
+    fn blue_over_red(profile: &Profile, line: &Line, split: f64) -> f64 {
+        let (mut red, mut blue) = (0.0, 0.0);
+        for (k, flux) in profile.flux.iter().enumerate() {
+            let (low, high) = (profile.edges[k], profile.edges[k + 1]);
+            let below = ((split * line.energy - low) / (high - low)).clamp(0.0, 1.0);
+            red += flux * below;
+            blue += flux * (1.0 - below);
+        }
+        blue / red
+    }
+
+    /// Shift of gas on a circular orbit at `r` seen face on, and its orbital speed projected
+    /// onto the line of sight.
+    fn ring(r: f64, inclination: f64) -> (f64, f64) {
+        ((1.0 - 3.0 * MASS / r).sqrt(), (MASS / r).sqrt() * inclination.sin())
+    }
+
+    fn traced(line: &Line) -> Profile {
+        profile(Schwarzschild::new(), &AccretionDisk::corona(), line, 1_000_000, 2e-2, 0).unwrap().0
+    }
+
+    #[test]
+    fn weak_field_profile_is_that_of_a_ring() {
+        let line = Line { energy: 1.0, inner: Some(30.0), outer: 30.1, ..Line::default() };
+        let (g0, b) = ring(30.05, line.inclination);
+        let profile = schwarzschild(&line);
+        let width = MAX_SHIFT / line.bins as f64;
+        let (red, blue) = peaks(&profile, &line, g0);
+        assert!((red - g0 / (1.0 + b)).abs() < width, "red peak at {}", red);
+        assert!((blue - g0 / (1.0 - b)).abs() < width, "blue peak at {}", blue);
+        // Photons go as g^3 (1 + b sin φ)^-3 around the ring, integrated by series over each half
+        let half = |s: f64| std::f64::consts::PI * (1.0 + 3.0 * b * b + 45.0 / 8.0 * b.powi(4)) + s * (6.0 * b + 40.0 / 3.0 * b.powi(3));
+        let ratio = blue_over_red(&profile, &line, g0);
+        assert!((ratio / (half(1.0) / half(-1.0)) - 1.0).abs() < 1e-2, "blue over red {}", ratio);
+        // The mean of g over (1 + b sin φ)^-3
+        let mean = g0 * (1.0 + 1.5 * b * b) / ((1.0 + 0.5 * b * b) * (1.0 - b * b));
+        assert!((profile.mean() / mean - 1.0).abs() < 1e-3, "mean {} not {}", profile.mean(), mean);
+    }
+
+    #[test]
+    fn traced_profile_matches_the_weak_field_far_out() {
+        let line = Line { energy: 1.0, inner: Some(25.0), outer: 35.0, rays: 30, bins: 160, ..Line::default() };
+        let (g0, _) = ring(30.0, line.inclination);
+        let (profile, weak) = (traced(&line), schwarzschild(&line));
+        let width = MAX_SHIFT / line.bins as f64;
+        let (red, blue) = peaks(&profile, &line, g0);
+        let (weak_red, weak_blue) = peaks(&weak, &line, g0);
+        assert!((red - weak_red).abs() < 2.0 * width, "red peak at {} not {}", red, weak_red);
+        assert!((blue - weak_blue).abs() < 2.0 * width, "blue peak at {} not {}", blue, weak_blue);
+        let (ratio, weak_ratio) = (blue_over_red(&profile, &line, g0), blue_over_red(&weak, &line, g0));
+        assert!((ratio / weak_ratio - 1.0).abs() < 0.05, "blue over red {} not {}", ratio, weak_ratio);
+        assert!((profile.mean() / weak.mean() - 1.0).abs() < 2e-3, "mean {} not {}", profile.mean(), weak.mean());
+    }
+
+    #[test]
+    fn traced_peaks_close_up_at_low_inclination() {
+        let line = Line { energy: 1.0, inclination: 15f64.to_radians(), inner: Some(5.8), outer: 6.2, rays: 24, ..Line::default() };
+        let (g0, b) = ring(6.0, line.inclination);
+        let profile = traced(&line);
+        let width = MAX_SHIFT / line.bins as f64;
+        // Light bending widens the profile a little even this close to face on, but the peaks
+        // stay near g0 / (1 ± b) and the mean near that of the weak-field ring
+        let (red, blue) = peaks(&profile, &line, g0);
+        assert!((red - g0 / (1.0 + b)).abs() < 0.2 * b * g0 + width, "red peak at {}", red);
+        assert!((blue - g0 / (1.0 - b)).abs() < 0.2 * b * g0 + width, "blue peak at {}", blue);
+        let mean = g0 * (1.0 + 1.5 * b * b) / ((1.0 + 0.5 * b * b) * (1.0 - b * b));
+        assert!((profile.mean() / mean - 1.0).abs() < 5e-3, "mean {} not {}", profile.mean(), mean);
+    }
+}
@@ -1,83 +1,519 @@
 #![allow(dead_code)]
 #![allow(non_snake_case)]
 
+mod error;
 mod metrics;
 mod observer;
 mod util;
 mod engine;
 mod source;
+mod preview;
+mod shard;
+mod fits;
+mod image;
+mod skybox;
+mod catalogue;
+mod trace;
+mod particle;
+mod disk;
+mod spectrum;
+mod bandpass;
+mod line;
+mod corona;
+mod compton;
 
-use observer::Simple;
+use observer::{Observer, Simple};
 use engine::Engine;
+use image::ToneMap;
+use error::{Error, Result};
+use skybox::Skybox;
+use metrics::Metric;
+use source::{AccretionDisk, Redshift, Emission, Scattering};
+use compton::Electrons;
+use disk::{PageThorne, Plunge};
+use spectrum::EnergyGrid;
+use bandpass::Bandpass;
+use trace::Rays;
+use line::{Line, Emissivity, AngularLaw};
+use corona::{LampPost, CoronaModel};
+use particle::{Particle, OrbitalElements};
 
+#[allow(clippy::approx_constant)]
 const THETA: f64 = 3.14 / 2.0 - 0.3f64;
 const START_POS: [f64; 3] = [10.0, THETA, 0.0];
 const MAX_ITER: usize = 1_000_000;
+const CHECKPOINT_INTERVAL: usize = 0x100000; // Photons between checkpoints
+const PREVIEW_INTERVAL: usize = 0x40000; // Photons between preview images
+const STAR_COUNT: usize = 20_000;
+const CHECKER_DIVISIONS: usize = 18;
+const CATALOGUE_COUNT: usize = 200; // Stars in a random catalogue
+const FAN_RAYS: usize = 32; // Rays traced by default
+const TRACE_STRIDE: usize = 100; // Steps between recorded positions of a traced ray
+const ORBIT_DTAU: f64 = 1e-3; // Step of a particle, as a fraction of r^(3/2)
+const ORBIT_STRIDE: usize = 10;
+const PLUNGE_INDEX: f64 = 3.0; // Flux of plunging gas goes as r^-PLUNGE_INDEX by default
+const LAMP_INDEX: f64 = 2.0; // Photon index of a lamp-post corona by default
+const LAMP_RAYS: usize = 20_000; // Rays traced from a lamp-post corona onto the disk
+const KERR_SPIN: f64 = 0.8; // a/M, between -1 and 1
+// Sgr A* and the star S2 (GRAVITY Collaboration 2020)
+const SGR_A_MASS: f64 = 4.261e6; // Solar masses
+const S2_SEMI_MAJOR: f64 = 1031.6; // AU
+const S2_ECCENTRICITY: f64 = 0.884649;
 
-fn flat() {
+/// Settings given on the command line
+#[derive(Default)]
+struct Options {
+    seed: Option<u64>,
+    shard: Option<(usize, usize)>,
+    sky: Option<String>,
+    catalogue: Option<String>,
+    /// Trace these rays and write their paths instead of rendering
+    trace: Option<Rays>,
+    /// Trace the profile of this line instead of rendering
+    line: Option<Line>,
+    stride: Option<usize>,
+    /// Use the old product of gravitational and Doppler shifts for the disk's redshift
+    approximate_redshift: bool,
+    /// Use the old isotropic scattering with a fixed gain in energy in the corona
+    approximate_scattering: bool,
+    /// Distribution of the corona's electrons for Klein–Nishina scattering
+    electrons: Option<Electrons>,
+    /// Replace the scene's disk with a Page–Thorne disk around a hole of this many solar masses,
+    /// fed at this many solar masses per year
+    page_thorne: Option<(f64, f64)>,
+    colour_correction: Option<f64>,
+    /// Flux at the ISCO, relative to the brightest part of the disk, and power-law index of the
+    /// gas plunging inside it
+    plunge: Option<(f64, f64)>,
+    /// Height and photon index of a lamp-post corona whose reflection off the disk replaces the
+    /// disk's own light
+    lamp_post: Option<(f64, f64)>,
+    /// Shape of the corona that scatters the disk's light
+    corona: Option<CoronaModel>,
+    /// Accumulate a spectrum per pixel in these energy bins
+    spectra: Option<EnergyGrid>,
+    /// Built-in filters or files of transmission curves to see the light through
+    filters: Vec<String>,
+}
+
+fn make_engine<O: Observer>(observer: O, file_name: &str, options: &Options) -> Result<Engine<O>> {
+    let mut engine = Engine::new(observer, file_name.to_owned())
+        .with_checkpoints(CHECKPOINT_INTERVAL)
+        .with_previews(PREVIEW_INTERVAL)
+        .with_images(ToneMap::default());
+    if let Some(seed) = options.seed {
+        engine = engine.with_seed(seed);
+    }
+    if let Some((index, count)) = options.shard {
+        engine = engine.with_shard(index, count);
+    }
+    if let Some(sky) = &options.sky {
+        engine = engine.with_skybox(match sky.as_str() {
+            "stars" => Skybox::stars(STAR_COUNT, 0),
+            "checker" => Skybox::checkerboard(CHECKER_DIVISIONS),
+            path => Skybox::texture(path)?,
+        });
+    }
+    if let Some(catalogue) = &options.catalogue {
+        engine = engine.with_catalogue(load_catalogue(catalogue)?);
+    }
+    if let Some(grid) = &options.spectra {
+        engine = engine.with_spectra(grid.clone());
+    }
+    if !options.filters.is_empty() {
+        let filters = options.filters.iter().map(|name| Bandpass::named(name)).collect::<Result<Vec<_>>>()?;
+        for (k, filter) in filters.iter().enumerate() {
+            if filters[..k].iter().any(|f| f.name() == filter.name()) {
+                return Err(invalid(&format!("filter {} is given twice", filter.name())));
+            }
+        }
+        engine = engine.with_filters(filters);
+    }
+    engine.resume()?;
+    Ok(engine)
+}
+
+/// Render the scene, or trace the rays asked for with `trace`.
+fn render<M: Metric>(observer: Simple<M>, metric: M, source: AccretionDisk, dtau: f64, file_name: &str, options: &Options) -> Result<usize> {
+    let mut source = if options.approximate_redshift { source.with_redshift(Redshift::Approximate) } else { source };
+    if options.approximate_scattering {
+        source = source.with_scattering(Scattering::Approximate);
+    } else if let Some(electrons) = options.electrons {
+        source = source.with_scattering(Scattering::KleinNishina(electrons));
+    }
+    if let Some((mass, accretion_rate)) = options.page_thorne {
+        let disk = PageThorne::new(&metric, mass, accretion_rate)?
+            .with_colour_correction(options.colour_correction.unwrap_or(1.0));
+        let (hottest_radius, hottest) = disk.hottest();
+        println!(
+            "Page-Thorne disk: ISCO at r = {:.4}, efficiency {:.4}, hottest {:.4e} K at r = {:.4}",
+            disk.isco(), disk.efficiency(), hottest, hottest_radius
+        );
+        source = source.with_emission(Emission::PageThorne(disk));
+    }
+    if let Some(corona) = &options.corona {
+        source = source.with_corona(corona.clone());
+    }
+    if let Some((height, photon_index)) = options.lamp_post {
+        let lamp = LampPost::new(&metric, &source, height, photon_index, LAMP_RAYS, MAX_ITER, dtau, options.seed.unwrap_or(0))?;
+        let (hit, escaped, lost) = lamp.fractions();
+        println!(
+            "Lamp post at h = {}: {:.2}% of photons hit the disk, {:.2}% escape, {:.2}% are lost. {}",
+            lamp.height(), 100.0 * hit, 100.0 * escaped, 100.0 * lost,
+            lamp.reflection_fraction().map_or("No photons escape, so there is no reflection fraction".to_owned(), |f| format!("Reflection fraction {:.4}", f))
+        );
+        lamp.write(&format!("../data/{}", file_name))?;
+        source = source.with_emission(Emission::Reflection(lamp)).without_corona();
+    }
+    if let Some((edge, index)) = options.plunge {
+        let plunge = Plunge::new(&metric, edge, index)?;
+        println!("Gas plunges inside the ISCO at r = {:.4}", plunge.isco());
+        source = source.with_plunge(plunge);
+    }
+    if let Some(rays) = &options.trace {
+        let seed = options.seed.unwrap_or(0);
+        let paths = trace::trace(&observer, &metric, &source, MAX_ITER, dtau, rays, options.stride.unwrap_or(TRACE_STRIDE), seed)?;
+        trace::write(&format!("../data/{}", file_name), &paths)?;
+        for (k, path) in paths.iter().enumerate() {
+            println!("Ray {} through {:?}: {} points, {}", k, path.point, path.positions.len(), path.termination.name());
+        }
+        return Ok(paths.len());
+    }
+    if let Some(line) = &options.line {
+        let (profile, seen) = line::profile(metric, &source, line, MAX_ITER, dtau, options.seed.unwrap_or(0))?;
+        println!("{} of {} rays saw the line. Peak at {:.4} keV, mean {:.4} keV", seen, line.rays * line.rays, profile.peak(), profile.mean());
+        let analytic = (metric.name() == "Schwarzschild").then(|| line::schwarzschild(line));
+        if let Some(analytic) = &analytic {
+            println!(
+                "Weak-field profile: peak at {:.4} keV, mean {:.4} keV, {:.2}% of photons differ",
+                analytic.peak(), analytic.mean(), 100.0 * profile.difference(analytic)
+            );
+        }
+        line::write(&format!("../data/{}", file_name), &profile, analytic.as_ref())?;
+        return Ok(line.rays * line.rays);
+    }
+    let mut engine = make_engine(observer, file_name, options)?;
+    engine.run(MAX_ITER, dtau, metric, source)
+}
+
+fn flat(options: &Options) -> Result<usize> {
     let metric = metrics::Minkowski::new(0.0);
     let source = source::AccretionDisk::flat();
 
     let observer = Simple::new(START_POS, [-THETA.sin(), 0.0, -THETA.cos()], metric);
-    let mut engine = Engine::new(observer, "flat".to_owned());
-    engine.run(MAX_ITER, 5e-3, metric, source);
+    render(observer, metric, source, 5e-3, "flat", options)
 }
 
-fn mink() {
+fn mink(options: &Options) -> Result<usize> {
     let metric = metrics::Minkowski::new(1.0);
     let source = source::AccretionDisk::thick();
 
     let observer = Simple::new(START_POS, [-THETA.sin(), 0.0, -THETA.cos()], metric);
-    let mut engine = Engine::new(observer, "minkowski".to_owned());
-    engine.run(MAX_ITER, 5e-3, metric, source);
+    render(observer, metric, source, 5e-3, "minkowski", options)
 }
 
-fn thick() {
+fn thick(options: &Options) -> Result<usize> {
     let metric = metrics::Schwarzschild::new();
     let source = source::AccretionDisk::thick();
 
-    
     let observer = Simple::new(START_POS, [-THETA.sin(), 0.0, -THETA.cos()], metric);
-    let mut engine = Engine::new(observer, "thick".to_owned());
-    let photon_count = engine.run(MAX_ITER, 1e-3, metric, source);
-    println!("{} photons run successfully", photon_count);
+    render(observer, metric, source, 1e-3, "thick", options)
 }
 
-fn thin() {
+fn thin(options: &Options) -> Result<usize> {
     let metric = metrics::Schwarzschild::new();
     let source = source::AccretionDisk::thin();
     
     let observer = Simple::new(START_POS, [-THETA.sin(), 0.0, -THETA.cos()], metric);
-    let mut engine = Engine::new(observer, "thin".to_owned());
-    let photon_count = engine.run(MAX_ITER, 2e-3, metric, source);
-    println!("{} photons run successfully", photon_count);
+    render(observer, metric, source, 2e-3, "thin", options)
 }
 
-fn sch() {
+fn sch(options: &Options) -> Result<usize> {
     let metric = metrics::Schwarzschild::new();
     let source = source::AccretionDisk::corona();
 
     let observer = Simple::new(START_POS, [-THETA.sin(), 0.0, -THETA.cos()], metric);
-    let mut engine = Engine::new(observer, "schwarzschild".to_owned());
-    let photon_count = engine.run(MAX_ITER, 2e-3, metric, source);
-    println!("{} photons run successfully", photon_count);
+    render(observer, metric, source, 2e-3, "schwarzschild", options)
 }
 
-fn kerr() {
-    let metric = metrics::Kerr::new (0.8);
+fn kerr(options: &Options) -> Result<usize> {
+    let metric = metrics::Kerr::new(KERR_SPIN)?;
     let source = source::AccretionDisk::corona();
 
     let observer = Simple::new(START_POS, [-THETA.sin(), 0.0, -THETA.cos()], metric);
-    let mut engine = Engine::new(observer, "kerr".to_owned());
-    let photon_count = engine.run(MAX_ITER, 2e-3, metric, source);
-    println!("{} photons run successfully", photon_count);
+    render(observer, metric, source, 2e-3, "kerr", options)
 }
 
 fn main() {
-    // flat();
-    // mink();
-    // thick();
-    // thin();
-    // sch();
-    kerr();
-}
\ No newline at end of file
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    // Usage: raytracer [SCENE] [--seed SEED] [--shard INDEX/COUNT] [--sky stars|checker|IMAGE]
+    //                  [--catalogue random|FILE] [--approximate-redshift] [--approximate-scattering]
+    //                  [--electrons thermal[,KEV]|power-law,INDEX,MIN,MAX]
+    //                  [--mass MSUN --accretion-rate MSUN_PER_YEAR [--colour-correction F]]
+    //                  [--plunge EDGE[,INDEX]] [--lamp-post HEIGHT[,INDEX]]
+    //                  [--corona original|slab,HEIGHT,OUTER|sphere,RADIUS|wedge,DEG,OUTER|column,BASE,TOP,RADIUS[,DEPTH]|file:PATH]
+    //                  [--spectra MIN_EV,MAX_EV,BINS] [--filter all|NAME|FILE[,...]]...
+    //        raytracer merge SCENE
+    //        raytracer lens SCENE random|FILE
+    //        raytracer line [SCENE] [--inclination DEG] [--line-energy KEV] [--emissivity INDEX|INNER,BREAK,OUTER]
+    //                  [--angular isotropic|limb-darkened|limb-brightened] [--inner R] [--outer R] [--rays N] [--bins N]
+    //        raytracer trace [SCENE] [--fan COUNT] [--pixel ROW,COLUMN]... [--stride STEPS] [--seed SEED]
+    //        raytracer orbit s2 | METRIC SEMI_MAJOR ECCENTRICITY [--inclination DEG] [--node DEG]
+    //                  [--periapsis DEG] [--anomaly DEG] [--orbits COUNT] [--mass MSUN]
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|a| a.as_str()) == Some("merge") {
+        let name = args.get(1).ok_or_else(|| invalid("merge needs the name of the scene, e.g. kerr"))?;
+        let photon_count = shard::merge(engine::DATA_DIRECTORY, name, &ToneMap::default())?;
+        println!("{} photons merged", photon_count);
+        return Ok(());
+    }
+    if args.first().map(|a| a.as_str()) == Some("lens") {
+        let (name, catalogue) = match (args.get(1), args.get(2)) {
+            (Some(name), Some(catalogue)) => (name, catalogue),
+            _ => return Err(invalid("lens needs the name of a rendered scene and a catalogue, e.g. kerr random")),
+        };
+        catalogue::lens_saved(name, &load_catalogue(catalogue)?, &ToneMap::default())?;
+        return Ok(());
+    }
+    if args.first().map(|a| a.as_str()) == Some("orbit") {
+        return orbit(&args[1..]);
+    }
+
+    let mut scene = "kerr".to_owned();
+    let mut options = Options::default();
+    let tracing = args.first().map(|a| a.as_str()) == Some("trace");
+    let lining = args.first().map(|a| a.as_str()) == Some("line");
+    let mut line = Line::default();
+    let mut line_flags = false;
+    let mut points = Vec::new();
+    let mut fan = None;
+    let (mut mass, mut accretion_rate) = (None, None);
+    let mut args = args.into_iter().skip((tracing || lining) as usize);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                options.seed = Some(args.next().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("--seed needs an integer"))?);
+            },
+            "--shard" => {
+                let shard = args.next().and_then(|s| {
+                    let (index, count) = s.split_once('/')?;
+                    Some((index.parse().ok()?, count.parse().ok()?))
+                });
+                options.shard = Some(shard.ok_or_else(|| invalid("--shard needs INDEX/COUNT, e.g. 0/4"))?);
+            },
+            "--sky" => {
+                options.sky = Some(args.next().ok_or_else(|| invalid("--sky needs stars, checker or the path of an image"))?);
+            },
+            "--catalogue" => {
+                options.catalogue = Some(args.next().ok_or_else(|| invalid("--catalogue needs random or the path of a star list"))?);
+            },
+            "--approximate-redshift" => options.approximate_redshift = true,
+            "--approximate-scattering" => options.approximate_scattering = true,
+            "--electrons" => {
+                let spec = args.next().ok_or_else(|| invalid("--electrons needs thermal, thermal,KEV or power-law,INDEX,MIN,MAX"))?;
+                options.electrons = Some(Electrons::named(&spec)?);
+            },
+            "--mass" => {
+                mass = Some(args.next().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("--mass needs a number of solar masses"))?);
+            },
+            "--accretion-rate" => {
+                accretion_rate = Some(args.next().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("--accretion-rate needs solar masses per year"))?);
+            },
+            "--plunge" => {
+                let plunge = args.next().and_then(|s| match s.split_once(',') {
+                    Some((edge, index)) => Some((edge.parse().ok()?, index.parse().ok()?)),
+                    None => Some((s.parse().ok()?, PLUNGE_INDEX)),
+                });
+                options.plunge = Some(plunge.ok_or_else(|| invalid("--plunge needs EDGE or EDGE,INDEX, e.g. 0.1,3"))?);
+            },
+            "--lamp-post" => {
+                let lamp = args.next().and_then(|s| match s.split_once(',') {
+                    Some((height, index)) => Some((height.parse().ok()?, index.parse().ok()?)),
+                    None => Some((s.parse().ok()?, LAMP_INDEX)),
+                });
+                options.lamp_post = Some(lamp.ok_or_else(|| invalid("--lamp-post needs HEIGHT or HEIGHT,INDEX, e.g. 3,2"))?);
+            },
+            "--corona" => {
+                let spec = args.next().ok_or_else(|| invalid("--corona needs original, slab,HEIGHT,OUTER, sphere,RADIUS, wedge,DEGREES,OUTER, column,BASE,TOP,RADIUS or file:PATH"))?;
+                options.corona = Some(CoronaModel::named(&spec)?);
+            },
+            "--spectra" => {
+                let grid = args.next().and_then(|s| {
+                    let (min, rest) = s.split_once(',')?;
+                    let (max, bins) = rest.split_once(',')?;
+                    Some((min.parse().ok()?, max.parse().ok()?, bins.parse().ok()?))
+                });
+                let (min, max, bins) = grid.ok_or_else(|| invalid("--spectra needs MIN_EV,MAX_EV,BINS, e.g. 0.01,1e6,160"))?;
+                options.spectra = Some(EnergyGrid::new(min, max, bins)?);
+            },
+            "--filter" => {
+                let names = args.next().ok_or_else(|| invalid("--filter needs all, a filter name such as V or 2-10keV, or a file"))?;
+                for name in names.split(',') {
+                    match name {
+                        "all" => options.filters.extend(bandpass::builtin_names().into_iter().map(|n| n.to_owned())),
+                        name => options.filters.push(name.to_owned()),
+                    }
+                }
+            },
+            "--colour-correction" => {
+                options.colour_correction = Some(args.next().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("--colour-correction needs a number"))?);
+            },
+            "--fan" => {
+                fan = Some(args.next().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("--fan needs a number of rays"))?);
+            },
+            "--pixel" => {
+                let point = args.next().and_then(|s| {
+                    let (row, column) = s.split_once(',')?;
+                    Some((row.parse().ok()?, column.parse().ok()?))
+                });
+                points.push(point.ok_or_else(|| invalid("--pixel needs ROW,COLUMN, e.g. 144,256"))?);
+            },
+            "--inclination" | "--line-energy" | "--emissivity" | "--angular" | "--inner" | "--outer" | "--rays" | "--bins" => {
+                let value = args.next().ok_or_else(|| invalid(&format!("{} needs a value", arg)))?;
+                let number = |what: &str| value.parse::<f64>().map_err(|_| invalid(&format!("{} needs {}", arg, what)));
+                match arg.as_str() {
+                    "--inclination" => line.inclination = number("degrees")?.to_radians(),
+                    "--line-energy" => line.energy = number("keV")?,
+                    "--emissivity" => line.emissivity = match value.split(',').map(|v| v.parse()).collect::<std::result::Result<Vec<f64>, _>>() {
+                        Ok(v) if v.len() == 1 => Emissivity::PowerLaw { index: v[0] },
+                        Ok(v) if v.len() == 3 => Emissivity::Broken { inner: v[0], radius: v[1], outer: v[2] },
+                        _ => return Err(invalid("--emissivity needs INDEX or INNER,BREAK,OUTER, e.g. 3 or 6,4,3")),
+                    },
+                    "--angular" => line.angular = AngularLaw::named(&value)?,
+                    "--inner" => line.inner = Some(number("a radius")?),
+                    "--outer" => line.outer = number("a radius")?,
+                    "--rays" => line.rays = number("a number of rays")? as usize,
+                    _ => line.bins = number("a number of bins")? as usize,
+                }
+                line_flags = true;
+            },
+            "--stride" => {
+                options.stride = Some(args.next().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("--stride needs a number of steps"))?);
+            },
+            _ => scene = arg,
+        }
+    }
+    if tracing {
+        options.trace = Some(match (fan, points.is_empty()) {
+            (Some(_), false) => return Err(invalid("trace takes either --fan or --pixel, not both")),
+            (None, false) => Rays::Points(points),
+            (fan, true) => Rays::Fan { count: fan.unwrap_or(FAN_RAYS) },
+        });
+    } else if fan.is_some() || !points.is_empty() || options.stride.is_some() {
+        return Err(invalid("--fan, --pixel and --stride only apply to trace"));
+    }
+    if (tracing || lining) && (options.spectra.is_some() || !options.filters.is_empty() || options.sky.is_some() || options.catalogue.is_some()) {
+        return Err(invalid("--spectra, --filter, --sky and --catalogue only apply to renders"));
+    }
+    if lining {
+        options.line = Some(line);
+    } else if line_flags {
+        return Err(invalid("--inclination, --line-energy, --emissivity, --angular, --inner, --outer, --rays and --bins only apply to line"));
+    }
+    options.page_thorne = match (mass, accretion_rate) {
+        (Some(mass), Some(accretion_rate)) => Some((mass, accretion_rate)),
+        (None, None) if options.colour_correction.is_none() => None,
+        _ => return Err(invalid("a Page-Thorne disk needs both --mass and --accretion-rate")),
+    };
+    if options.lamp_post.is_some() && options.page_thorne.is_some() {
+        return Err(invalid("--lamp-post replaces the disk's own light, so it cannot go with a Page-Thorne disk"));
+    }
+    if lining && options.lamp_post.is_some() {
+        return Err(invalid("--lamp-post does not apply to line, which takes --emissivity instead"));
+    }
+    if options.corona.is_some() && (lining || options.lamp_post.is_some()) {
+        return Err(invalid("--corona does not apply to line or --lamp-post, which leave the corona out"));
+    }
+    if options.approximate_scattering && options.electrons.is_some() {
+        return Err(invalid("--electrons sets up Klein-Nishina scattering, so it cannot go with --approximate-scattering"));
+    }
+    if options.shard.is_some() && options.seed.is_none() {
+        return Err(invalid("sharded renders need --seed so that every shard draws the same random numbers"));
+    }
+
+    let photon_count = match scene.as_str() {
+        "flat" => flat(&options),
+        "minkowski" => mink(&options),
+        "thick" => thick(&options),
+        "thin" => thin(&options),
+        "schwarzschild" => sch(&options),
+        "kerr" => kerr(&options),
+        _ => Err(invalid(&format!("unknown scene {}", scene))),
+    }?;
+    if tracing || lining {
+        println!("{} rays traced", photon_count);
+    } else {
+        println!("{} photons run successfully", photon_count);
+    }
+    Ok(())
+}
+
+/// Follow a massive particle from orbital elements given on the command line.
+fn orbit(args: &[String]) -> Result<()> {
+    let number = |s: Option<&String>, what: &str| -> Result<f64> {
+        s.and_then(|s| s.parse().ok()).ok_or_else(|| invalid(&format!("orbit needs {}", what)))
+    };
+    let (name, metric, mut elements, mut mass, rest) = if args.first().map(|a| a.as_str()) == Some("s2") {
+        let elements = OrbitalElements::new(particle::au_to_schwarzschild(S2_SEMI_MAJOR, SGR_A_MASS), S2_ECCENTRICITY);
+        ("s2".to_owned(), "schwarzschild", elements, Some(SGR_A_MASS), &args[1..])
+    } else {
+        let metric = args.first().ok_or_else(|| invalid("orbit needs s2 or a metric: minkowski, schwarzschild or kerr"))?;
+        let elements = OrbitalElements::new(number(args.get(1), "a semi-major axis")?, number(args.get(2), "an eccentricity")?);
+        (format!("orbit-{}", metric), metric.as_str(), elements, None, args.get(3..).unwrap_or(&[]))
+    };
+    let mut orbits = 1;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--inclination" => elements.inclination = number(rest.next(), "--inclination in degrees")?.to_radians(),
+            "--node" => elements.ascending_node = number(rest.next(), "--node in degrees")?.to_radians(),
+            "--periapsis" => elements.periapsis_argument = number(rest.next(), "--periapsis in degrees")?.to_radians(),
+            "--anomaly" => elements.true_anomaly = number(rest.next(), "--anomaly in degrees")?.to_radians(),
+            "--orbits" => orbits = number(rest.next(), "--orbits")? as usize,
+            "--mass" => mass = Some(number(rest.next(), "--mass in solar masses")?),
+            _ => return Err(invalid(&format!("unknown orbit option {}", arg))),
+        }
+    }
+    match metric {
+        "minkowski" => follow(metrics::Minkowski::new(0.0), &name, &elements, orbits, mass),
+        "schwarzschild" => follow(metrics::Schwarzschild::new(), &name, &elements, orbits, mass),
+        "kerr" => follow(metrics::Kerr::new(KERR_SPIN)?, &name, &elements, orbits, mass),
+        _ => Err(invalid(&format!("unknown metric {}", metric))),
+    }
+}
+
+fn follow<M: Metric>(metric: M, name: &str, elements: &OrbitalElements, orbits: usize, mass: Option<f64>) -> Result<()> {
+    let orbit = Particle::from_elements(elements, &metric)?.run(&metric, ORBIT_DTAU, orbits, ORBIT_STRIDE);
+    let base = format!("../data/{}", name);
+    orbit.write(&base)?;
+    let summary = orbit.summary(elements, mass);
+    engine::write_manifest(&format!("{}-orbit.json", base), &summary)?;
+    println!("Ended with {} after {} orbits", orbit.termination.name(), summary["orbits"]);
+    if let Some((period, proper)) = orbit.period() {
+        println!("Radial period {:.6e} (proper time {:.6e}) in R_S/c", period, proper);
+    }
+    if let Some(precession) = orbit.precession() {
+        println!(
+            "Periapsis precession {:.6e} rad ({:.3} arcmin) per orbit, {:.6e} rad to first order for Schwarzschild",
+            precession, precession.to_degrees() * 60.0, elements.schwarzschild_precession()
+        );
+    }
+    Ok(())
+}
+
+fn load_catalogue(catalogue: &str) -> Result<Vec<catalogue::Star>> {
+    match catalogue {
+        "random" => Ok(catalogue::random(CATALOGUE_COUNT, 0)),
+        path => catalogue::load(path),
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidScene(message.to_owned())
+}
@@ -1,8 +1,9 @@
 use crate::util::{Vec4, Matrix4};
+use crate::error::{Error, Result};
 
 // https://arxiv.org/pdf/0904.4184.pdf
 
-const SPACETIME_EDGE: f64 = 100.0;
+pub const SPACETIME_EDGE: f64 = 100.0;
 
 pub enum State {
     Dead,
@@ -14,11 +15,17 @@ pub struct Christoffel {
     pub numbers: [f64; 64],
 }
 
-pub trait Metric: Copy + Clone + Send + 'static {
+pub trait Metric: Copy + Clone + Send + Sync + std::fmt::Debug + 'static {
     fn get_state(&self, pos: Vec4) -> State;
     fn christoffel(&self, pos: Vec4) -> Christoffel;
     fn get_metric(&self, pos: Vec4) -> Matrix4;
     fn get_horizon(&self) -> f64;
+    fn name(&self) -> &'static str;
+    /// Parameters of the spacetime, for recording alongside the output.
+    fn parameters(&self) -> Vec<(&'static str, f64)>;
+    /// Whether the spacetime looks the same in every direction from the centre, so that an
+    /// orbit in any plane is an equatorial one turned over.
+    fn spherical(&self) -> bool;
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -41,15 +48,22 @@ impl MorrisThorne {
 }
 #[derive(Debug, Copy, Clone)]
 pub struct Kerr {
+    /// Angular momentum per unit mass J / Mc, in Schwarzschild radii, so at most M = 1/2
     a: f64,
     horizon: f64
 }
 impl Kerr {
-    pub fn new(a: f64) -> Self {
-        Self {
-            a,
-            horizon: 1.0 / 2.0 + (1.0 - a * a).sqrt() / 2.0
+    /// A hole spinning towards increasing φ with dimensionless spin a/M = `spin`, between -1
+    /// and 1. Negative spins turn the other way.
+    pub fn new(spin: f64) -> Result<Self> {
+        if !(-1.0..=1.0).contains(&spin) {
+            return Err(Error::InvalidScene(format!("Kerr spin a/M must be between -1 and 1, not {}", spin)));
         }
+        // M = 1/2 in Schwarzschild radii, and the horizon is at M + sqrt(M^2 - a^2)
+        Ok(Self {
+            a: spin / 2.0,
+            horizon: 1.0 / 2.0 + (1.0 - spin * spin).sqrt() / 2.0
+        })
     }
 }
 
@@ -67,10 +81,10 @@ impl Christoffel {
 
     pub fn accel(&self, vel: Vec4) -> (Vec4, Vec4) {
         let mut sum = [0.0; 4];
-        for mu in 0..4 {
+        for (mu, s) in sum.iter_mut().enumerate() {
             for alpha in 0..4 {
                 for beta in 0..4 {
-                    sum[mu] -= self.get(mu, alpha, beta) * vel[alpha] * vel[beta];
+                    *s -= self.get(mu, alpha, beta) * vel[alpha] * vel[beta];
                 }
             }
         }
@@ -80,6 +94,9 @@ impl Christoffel {
 
 impl Metric for Minkowski {
     fn get_horizon(&self) -> f64 { 0.0 }
+    fn name(&self) -> &'static str { "Minkowski" }
+    fn parameters(&self) -> Vec<(&'static str, f64)> { vec![("radius", self.radius)] }
+    fn spherical(&self) -> bool { true }
     fn get_state(&self, pos: Vec4) -> State {
         if pos[0].abs() > 1e6 || pos[1].abs() < self.radius {
             return State::Dead;
@@ -130,6 +147,9 @@ impl Metric for Minkowski {
 
 impl Metric for Schwarzschild {
     fn get_horizon(&self) -> f64 { 1.0 }
+    fn name(&self) -> &'static str { "Schwarzschild" }
+    fn parameters(&self) -> Vec<(&'static str, f64)> { vec![] }
+    fn spherical(&self) -> bool { true }
     fn get_state(&self, pos: Vec4) -> State {
         if pos[0].abs() > 1e6 {
             return State::Dead;
@@ -185,6 +205,9 @@ impl Metric for Schwarzschild {
 
 impl Metric for MorrisThorne {
     fn get_horizon(&self) -> f64 { self.b0 }
+    fn name(&self) -> &'static str { "MorrisThorne" }
+    fn parameters(&self) -> Vec<(&'static str, f64)> { vec![("b0", self.b0)] }
+    fn spherical(&self) -> bool { true }
 
     fn get_state(&self, pos: Vec4) -> State {
         if pos[0].abs() > 1e6 {
@@ -236,7 +259,10 @@ impl Metric for MorrisThorne {
 }
 
 impl Metric for Kerr {
-    fn get_horizon(&self) -> f64 { 1.0 }
+    fn get_horizon(&self) -> f64 { self.horizon }
+    fn name(&self) -> &'static str { "Kerr" }
+    fn parameters(&self) -> Vec<(&'static str, f64)> { vec![("spin", 2.0 * self.a)] }
+    fn spherical(&self) -> bool { self.a == 0.0 }
 
     fn get_state(&self, pos: Vec4) -> State {
         if pos[0].abs() > 1e6 {
@@ -264,7 +290,7 @@ impl Metric for Kerr {
         Christoffel::new([
             0.0,
             1.0 * (r2 + a2) * adiff / (2.0 * sigma2 * delta),
-            -1.0 * a2 * pos[1] * ct * st / sigma2,
+            -a2 * pos[1] * ct * st / sigma2,
             0.0,
 
             0.0,
@@ -274,8 +300,8 @@ impl Metric for Kerr {
 
             0.0,
             0.0,
-            1.0 * self.a * a2 * pos[1] * st * st * st * ct / sigma2,
             0.0,
+            1.0 * self.a * a2 * pos[1] * st * st * st * ct / sigma2,
 
             0.0,
             0.0,
@@ -304,7 +330,7 @@ impl Metric for Kerr {
             delta * st * st / (2.0 * sigma2 * sigma) * (-2.0 * pos[1] * sigma2 + 1.0 * a2 * st * st * adiff),
 
             // theta
-            -1.0 * a2 * pos[1] * ct * st / (sigma * sigma2),
+            -a2 * pos[1] * ct * st / (sigma * sigma2),
             0.0,
             0.0,
             1.0 * self.a * pos[1] * (r2 + a2) * st * ct / (sigma * sigma2),
@@ -327,7 +353,7 @@ impl Metric for Kerr {
             // phi
             0.0,
             1.0 * self.a * adiff / (2.0 * sigma2 * delta),
-            -1.0 * self.a * pos[1] * ct / sigma2,
+            -self.a * pos[1] * ct / st / sigma2,
             0.0,
 
             0.0,
@@ -355,10 +381,90 @@ impl Metric for Kerr {
         let sigma = r2 + a2 * ct * ct;
         let delta = r2 - 1.0 * pos[1] + a2;
         [
-            -(1.0 - 1.0 * pos[1] / sigma), 0.0, 0.0, 1.0 * self.a * pos[1] * st * st / sigma,
+            -(1.0 - 1.0 * pos[1] / sigma), 0.0, 0.0, -self.a * pos[1] * st * st / sigma,
             0.0, sigma / delta, 0.0, 0.0,
             0.0, 0.0, sigma, 0.0,
-            1.0 * self.a * pos[1] * st * st / sigma, 0.0, 0.0, (r2 + a2 + 1.0*pos[1] * a2*st*st / sigma) * st * st
+            -self.a * pos[1] * st * st / sigma, 0.0, 0.0, (r2 + a2 + 1.0*pos[1] * a2*st*st / sigma) * st * st
         ]
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inverse of a symmetric 4x4 matrix by Gauss–Jordan elimination.
+    fn inverse(m: &Matrix4) -> Matrix4 {
+        let mut a = *m;
+        let mut inv: Matrix4 = std::array::from_fn(|k| if k % 5 == 0 { 1.0 } else { 0.0 });
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&i, &j| a[i * 4 + col].abs().total_cmp(&a[j * 4 + col].abs())).unwrap();
+            for k in 0..4 {
+                a.swap(col * 4 + k, pivot * 4 + k);
+                inv.swap(col * 4 + k, pivot * 4 + k);
+            }
+            let scale = a[col * 4 + col];
+            for k in 0..4 {
+                a[col * 4 + k] /= scale;
+                inv[col * 4 + k] /= scale;
+            }
+            for row in (0..4).filter(|&row| row != col) {
+                let factor = a[row * 4 + col];
+                for k in 0..4 {
+                    a[row * 4 + k] -= factor * a[col * 4 + k];
+                    inv[row * 4 + k] -= factor * inv[col * 4 + k];
+                }
+            }
+        }
+        inv
+    }
+
+    /// Γ^μ_αβ = g^μν (∂_α g_νβ + ∂_β g_να - ∂_ν g_αβ) / 2 at index μ·16 + α·4 + β, with the
+    /// derivatives by central differences of `get_metric`.
+    fn connection<M: Metric>(metric: &M, pos: Vec4) -> [f64; 64] {
+        let h = 1e-5;
+        let derivatives: [Matrix4; 4] = std::array::from_fn(|nu| {
+            let (mut above, mut below) = (pos, pos);
+            above[nu] += h;
+            below[nu] -= h;
+            let (above, below) = (metric.get_metric(above), metric.get_metric(below));
+            std::array::from_fn(|k| (above[k] - below[k]) / (2.0 * h))
+        });
+        let upper = inverse(&metric.get_metric(pos));
+        std::array::from_fn(|index| {
+            let (mu, alpha, beta) = (index / 16, index / 4 % 4, index % 4);
+            (0..4).map(|nu| {
+                let lowered = derivatives[alpha][nu + 4 * beta] + derivatives[beta][nu + 4 * alpha] - derivatives[nu][alpha + 4 * beta];
+                0.5 * upper[mu + 4 * nu] * lowered
+            }).sum()
+        })
+    }
+
+    fn assert_connection_matches<M: Metric>(metric: &M) {
+        for pos in [[0.0, 3.0, 0.7, 0.2], [0.0, 1.8, 2.1, 4.0], [0.0, 12.0, 1.4, 1.0]] {
+            let (given, expected) = (metric.christoffel(pos), connection(metric, pos));
+            for (index, expected) in expected.into_iter().enumerate() {
+                let (mu, alpha, beta) = (index / 16, index / 4 % 4, index % 4);
+                let given = given.get(mu, alpha, beta);
+                assert!(
+                    (given - expected).abs() < 1e-6 * (1.0 + expected.abs()),
+                    "{:?} at {:?}: Γ^{}_{}{} is {}, not {}", metric, pos, mu, alpha, beta, given, expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn connection_matches_the_metric() {
+        assert_connection_matches(&Schwarzschild::new());
+        for spin in [0.8, -0.5, 1.0] {
+            assert_connection_matches(&Kerr::new(spin).unwrap());
+        }
+    }
+
+    #[test]
+    fn kerr_spin_is_at_most_one() {
+        assert!(Kerr::new(1.2).is_err());
+        assert!((Kerr::new(0.8).unwrap().get_horizon() - 0.8).abs() < 1e-12);
+    }
+}
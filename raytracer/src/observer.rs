@@ -2,7 +2,8 @@ use std::mem::MaybeUninit;
 
 use crate::util::*;
 use crate::metrics::{Metric, State};
-use crate::source::{AccretionDisk, RESCALE_FOR_XRAY};
+use crate::source::AccretionDisk;
+use crate::spectrum;
 
 const SIZE: usize = 32; // 32
 pub const PHOTON_BATCH_SIZE: usize = 0x100;
@@ -14,18 +15,36 @@ const LENGTH_SCALE: f64 = 0.9; // Decrease in size ratio for each rectangle of t
 
 pub const WIDTH: usize = 16 * SIZE;
 pub const HEIGHT: usize = 9 * SIZE;
-const MAX_COUNTS: usize = WIDTH * HEIGHT * NUM_SLICES * NUM_COVERS_PER;
 const OFFSET_CHECK: usize = 0x10;
 const TEMP_RECORD: usize = 8;
 const EPSILON: f64 = 1e-3; // Addition to position to prevent singularities for very close to event horizon.
 const POLE_PROTECTION: f64 = 3.0; // Controls the delta t factor near theta = 0
 const IMAGE_WIDTH: f64 = 1.8; // 1.8
-const FALLOFF_SIG: f64 = 1000.0;
-const PIXEL_WIDTH: f64 = IMAGE_WIDTH / WIDTH as f64;
+pub const PIXEL_WIDTH: f64 = IMAGE_WIDTH / WIDTH as f64;
+const OPAQUE_DEPTH: f64 = 1e-12; // Transmission below which nothing further along the path can be seen
+const CONSTRAINT_TOLERANCE: f64 = 0.1; // Largest g(v, v), relative to the size of its terms, that is still trusted
 
 pub trait Observer {
-    fn update(&mut self, photon_data: &Vec<PhotonData>) -> u32;
-    fn next_photons(&mut self) -> [Option<Photon>; PHOTON_BATCH_SIZE];
+    fn update(&mut self, photon_data: &[PhotonData]) -> u32;
+    /// Hand out the next batch of photons. Each photon draws its random numbers from its own
+    /// stream, seeded from `seed` and the photon's index.
+    fn next_photons(&mut self, seed: u64) -> [Option<Photon>; PHOTON_BATCH_SIZE];
+    /// Number of photons handed out so far. Used to checkpoint and resume a run.
+    fn progress(&self) -> usize;
+    fn set_progress(&mut self, num_counts: usize);
+    /// Total number of photons this observer hands out over a full render.
+    fn num_photons(&self) -> usize;
+    /// Settings of the observer that affect the image, for hashing and bookkeeping.
+    fn describe(&self) -> String;
+    /// Settings of the observer as named numbers, for recording alongside the output.
+    fn parameters(&self) -> Vec<(&'static str, f64)>;
+    /// Radial coordinate of the observer.
+    fn distance(&self) -> f64;
+    /// Angle between the centres of neighbouring pixels at the middle of the image, in radians.
+    fn pixel_scale(&self) -> f64;
+    /// A photon through the point (row, column) of the image, in pixels. Used to trace single
+    /// rays rather than render.
+    fn photon_through(&self, point: (f64, f64), rng: fastrand::Rng) -> Photon;
 }
 
 pub struct Simple<M: Metric> {
@@ -59,7 +78,7 @@ impl<M: Metric> Simple<M> {
         get_vel_from_metric(v3, &self.metric.get_metric(self.pos))
     }
 
-    fn get_theta_phi_from_count(&self, mut count: usize) -> Option<((f64, f64), (usize, usize))> {
+    fn get_theta_phi_from_count(&self, mut count: usize, rng: &fastrand::Rng) -> Option<((f64, f64), (usize, usize))> {
         let mut width = WIDTH;
         let mut height = HEIGHT;
         let mut index = 0;
@@ -79,28 +98,98 @@ impl<M: Metric> Simple<M> {
             let i = count / width;
             let j = count % width;
             return Some(((
-                PIXEL_WIDTH * (i as f64 - (height / 2) as f64 + 0.001 * rand::random::<f64>()),
-                PIXEL_WIDTH * (j as f64 - (width / 2) as f64 + 0.001 * rand::random::<f64>())
+                PIXEL_WIDTH * (i as f64 - (height / 2) as f64 + 0.001 * rng.f64()),
+                PIXEL_WIDTH * (j as f64 - (width / 2) as f64 + 0.001 * rng.f64())
             ), (i + (HEIGHT - height) / 2, j + (WIDTH - width) / 2)));
         }
     }
 }
 
 impl<M: Metric> Observer for Simple<M> {
-    fn update(&mut self, _photon_data: &Vec<PhotonData>) -> u32 {
-        ((self.num_counts * 100) / MAX_COUNTS) as u32
+    fn update(&mut self, _photon_data: &[PhotonData]) -> u32 {
+        ((self.num_counts * 100) / self.num_photons()) as u32
     }
 
-    fn next_photons(&mut self) -> [Option<Photon>; PHOTON_BATCH_SIZE] {
+    fn progress(&self) -> usize {
+        self.num_counts
+    }
+
+    fn set_progress(&mut self, num_counts: usize) {
+        self.num_counts = num_counts;
+    }
+
+    fn num_photons(&self) -> usize {
+        // Same shrinking rectangles as get_theta_phi_from_count
+        let mut width = WIDTH;
+        let mut height = HEIGHT;
+        let mut total = 0;
+        for _ in 0..NUM_SLICES {
+            total += width * height;
+            width = (width as f64 * LENGTH_SCALE) as usize;
+            height = (height as f64 * LENGTH_SCALE) as usize;
+            if height <= 2 {
+                break;
+            }
+        }
+        total * NUM_COVERS_PER
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![
+            ("r", self.pos[1]),
+            ("theta", self.pos[2]),
+            ("phi", self.pos[3]),
+            ("inclination_deg", self.pos[2].to_degrees()),
+            ("image_width", IMAGE_WIDTH),
+            ("width", WIDTH as f64),
+            ("height", HEIGHT as f64),
+            ("covers_per_pixel", NUM_COVERS_PER as f64),
+            ("num_slices", NUM_SLICES as f64),
+            ("length_scale", LENGTH_SCALE),
+        ]
+    }
+
+    fn distance(&self) -> f64 {
+        self.pos[1]
+    }
+
+    fn pixel_scale(&self) -> f64 {
+        // Pixels are evenly spaced on the plane one unit along the look direction
+        PIXEL_WIDTH.atan()
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "Simple {{ pos: {:?}, look: {:?}, metric: {:?}, image_width: {}, covers_per: {}, slices: {}, length_scale: {} }}",
+            self.pos, self.look, self.metric, IMAGE_WIDTH, NUM_COVERS_PER, NUM_SLICES, LENGTH_SCALE
+        )
+    }
+
+    fn photon_through(&self, point: (f64, f64), rng: fastrand::Rng) -> Photon {
+        let angles = (
+            PIXEL_WIDTH * (point.0 - (HEIGHT / 2) as f64),
+            PIXEL_WIDTH * (point.1 - (WIDTH / 2) as f64),
+        );
+        let pixel = (
+            (point.0.round().max(0.0) as usize).min(HEIGHT - 1),
+            (point.1.round().max(0.0) as usize).min(WIDTH - 1),
+        );
+        Photon::new(self.pos, pixel, self.theta_phi_to_vel(self.pos, angles), rng)
+    }
+
+    fn next_photons(&mut self, seed: u64) -> [Option<Photon>; PHOTON_BATCH_SIZE] {
         let mut array: [MaybeUninit<Option<Photon>>; PHOTON_BATCH_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
 
         for (i, element) in array.iter_mut().enumerate() {
-            *element = match self.get_theta_phi_from_count(self.num_counts + i) {
+            let index = self.num_counts + i;
+            let rng = fastrand::Rng::with_seed(mix_seed(seed, index as u64));
+            *element = match self.get_theta_phi_from_count(index, &rng) {
                 Some((theta_phi, pixel)) => MaybeUninit::new(
                     Some(Photon::new(
                         self.pos,
                         pixel,
                         self.theta_phi_to_vel(self.pos, theta_phi),
+                        rng,
                     ))
                 ),
                 None => MaybeUninit::new(None)
@@ -117,9 +206,86 @@ pub struct Photon {
     vel: Vec4,
     pixel: (usize, usize),
     temps: [(f64, f64); TEMP_RECORD], // Temp, luminosity
+    crossings: [Crossing; TEMP_RECORD], // Where each temperature was picked up
     temp_index: usize,
     depth: f64,
-    compton_scatter: Option<f64>, // Compton shift
+    compton_scatter: Option<(f64, f64)>, // Compton shift and the weight of the scattered light
+    rng: fastrand::Rng,
+}
+
+/// A place where a ray crossed the disk, and how the light from there reaches the camera.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Crossing {
+    pub radius: f64,
+    /// Frequency at the camera over frequency in the gas
+    pub shift: f64,
+    /// Cosine of the angle between the ray and the disk normal, in the frame of the gas
+    pub cosine: f64,
+    /// Fraction of the light from the crossing that gets through the disk in front of it
+    pub transmission: f64,
+}
+
+/// Why a photon stopped being traced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Termination {
+    /// Fell into the hole
+    Horizon,
+    /// Left the scene
+    Escape,
+    /// Crossed the disk more times than are recorded
+    DiskCrossings,
+    /// Hit a part of the disk that hides everything behind it
+    DiskOpaque,
+    /// Ran out of steps
+    MaxIterations,
+    /// Failed a health check and was quarantined
+    Invalid,
+}
+
+impl Termination {
+    pub const ALL: [Termination; 6] = [
+        Termination::Horizon, Termination::Escape, Termination::DiskCrossings, Termination::DiskOpaque,
+        Termination::MaxIterations, Termination::Invalid,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Termination::Horizon => "horizon",
+            Termination::Escape => "escape",
+            Termination::DiskCrossings => "disk_crossings",
+            Termination::DiskOpaque => "disk_opaque",
+            Termination::MaxIterations => "max_iterations",
+            Termination::Invalid => "invalid",
+        }
+    }
+}
+
+/// Something wrong with a photon's path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Position or velocity became NaN or infinite
+    Nan,
+    /// The velocity drifted too far from null before it was corrected
+    Constraint,
+    /// Ran out of steps before reaching the horizon or escaping
+    MaxIterations,
+}
+
+impl Failure {
+    pub const ALL: [Failure; 3] = [Failure::Nan, Failure::Constraint, Failure::MaxIterations];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Failure::Nan => "nan",
+            Failure::Constraint => "constraint",
+            Failure::MaxIterations => "max_iterations",
+        }
+    }
+
+    /// Whether the photon's light is untrustworthy and left out of the image.
+    pub fn quarantined(&self) -> bool {
+        matches!(self, Failure::Nan | Failure::Constraint)
+    }
 }
 
 #[derive(Clone)]
@@ -127,177 +293,73 @@ pub struct PhotonData {
     pub optical_color: (f64, f64, f64),
     pub xray_color: (f64, f64, f64),
     pub pixel: (usize, usize),
+    pub termination: Termination,
+    pub failure: Option<Failure>,
+    /// Unit vector, in the frame of the hole, of the direction an escaped ray left the scene in,
+    /// i.e. the direction on the sky its light came from
+    pub escape_direction: Option<Vec3>,
+    /// Total affine parameter the ray was traced for
+    pub affine_length: f64,
+    /// Fraction of the light from behind the end of the path, i.e. the background sky for
+    /// escaped rays, that reaches the camera. Zero for rays scattered by the corona.
+    pub transmission: f64,
+    /// Temperature in eV at the camera and bolometric intensity of each piece of light the ray
+    /// picked up, for spectra. Scattered light is at the energy it leaves the corona with.
+    pub emission: Vec<(f64, f64)>,
+    /// Whether the ray was scattered by the corona, so its light is X-ray light
+    pub scattered: bool,
+    /// The disk crossings that `emission` came from, in the same order
+    pub crossings: Vec<Crossing>,
 }
 
 impl PhotonData {
-    fn temp_to_color(temp: f64) -> (f64, f64, f64) {
-        const TEMP_TO_COLOR: [(u8, u8, u8); 111] = [
-            (255, 56, 0),
-            (255, 71, 0),
-            (255, 83, 0),
-            (255, 93, 0),
-            (255, 101, 0),
-            (255, 109, 0),
-            (255, 115, 0),
-            (255, 121, 0),
-            (255, 126, 0),
-            (255, 131, 0),
-            (255, 138, 18),
-            (255, 142, 33),
-            (255, 147, 44),
-            (255, 152, 54),
-            (255, 157, 63),
-            (255, 161, 72),
-            (255, 165, 79),
-            (255, 169, 87),
-            (255, 173, 94),
-            (255, 177, 101),
-            (255, 180, 107),
-            (255, 184, 114),
-            (255, 187, 120),
-            (255, 190, 126),
-            (255, 193, 132),
-            (255, 196, 137),
-            (255, 199, 143),
-            (255, 201, 148),
-            (255, 204, 153),
-            (255, 206, 159),
-            (255, 209, 163),
-            (255, 211, 168),
-            (255, 213, 173),
-            (255, 215, 177),
-            (255, 217, 182),
-            (255, 219, 186),
-            (255, 221, 190),
-            (255, 223, 194),
-            (255, 225, 198),
-            (255, 227, 202),
-            (255, 228, 206),
-            (255, 230, 210),
-            (255, 232, 213),
-            (255, 233, 217),
-            (255, 235, 220),
-            (255, 236, 224),
-            (255, 238, 227),
-            (255, 239, 230),
-            (255, 240, 233),
-            (255, 242, 236),
-            (255, 243, 239),
-            (255, 244, 242),
-            (255, 245, 245),
-            (255, 246, 247),
-            (255, 248, 251),
-            (255, 249, 253),
-            (254, 249, 255),
-            (252, 247, 255),
-            (249, 246, 255),
-            (247, 245, 255),
-            (245, 243, 255),
-            (243, 242, 255),
-            (240, 241, 255),
-            (239, 240, 255),
-            (237, 239, 255),
-            (235, 238, 255),
-            (233, 237, 255),
-            (231, 236, 255),
-            (230, 235, 255),
-            (228, 234, 255),
-            (227, 233, 255),
-            (225, 232, 255),
-            (224, 231, 255),
-            (222, 230, 255),
-            (221, 230, 255),
-            (220, 229, 255),
-            (218, 229, 255),
-            (217, 227, 255),
-            (216, 227, 255),
-            (215, 226, 255),
-            (214, 225, 255),
-            (212, 225, 255),
-            (211, 224, 255),
-            (210, 223, 255),
-            (209, 223, 255),
-            (208, 222, 255),
-            (207, 221, 255),
-            (207, 221, 255),
-            (206, 220, 255),
-            (205, 220, 255),
-            (207, 218, 255),
-            (207, 218, 255),
-            (206, 217, 255),
-            (205, 217, 255),
-            (204, 216, 255),
-            (204, 216, 255),
-            (203, 215, 255),
-            (202, 215, 255),
-            (202, 214, 255),
-            (201, 214, 255),
-            (200, 213, 255),
-            (200, 213, 255),
-            (199, 212, 255),
-            (198, 212, 255),
-            (198, 212, 255),
-            (197, 211, 255),
-            (197, 211, 255),
-            (197, 210, 255),
-            (196, 210, 255),
-            (195, 210, 255),
-            (195, 209, 255)
-        ];
-
-        let float_index = temp / 100.0 - 10.0;
-        if float_index < 0.0 {
-            let falloff = (-float_index * float_index / FALLOFF_SIG).exp();
-            return (
-                TEMP_TO_COLOR[0].0 as f64 / 255.0 * falloff,
-                TEMP_TO_COLOR[0].1 as f64 / 255.0 * falloff,
-                TEMP_TO_COLOR[0].2 as f64 / 255.0 * falloff
-            );
-        } else if float_index > TEMP_TO_COLOR.len() as f64 - 1.0 {
-            let falloff = (-(float_index - TEMP_TO_COLOR.len() as f64 - 1.0).powi(2) / FALLOFF_SIG).exp();
-            return (
-                TEMP_TO_COLOR[TEMP_TO_COLOR.len()- 1].0 as f64 / 255.0 * falloff,
-                TEMP_TO_COLOR[TEMP_TO_COLOR.len()- 1].1 as f64 / 255.0 * falloff,
-                TEMP_TO_COLOR[TEMP_TO_COLOR.len()- 1].2 as f64 / 255.0 * falloff
-            );
+    /// RGB colour, each channel in [0, 1] and the brightest 1, of a blackbody at `temp` kelvin.
+    pub fn temp_to_color(temp: f64) -> (f64, f64, f64) {
+        let (r, g, b) = spectrum::blackbody_rgb(temp);
+        let brightest = r.max(g).max(b);
+        if brightest > 0.0 {
+            (r / brightest, g / brightest, b / brightest)
+        } else {
+            (0.0, 0.0, 0.0)
         }
-        let int_index = float_index as usize;
-        let frac = float_index - int_index as f64;
-        let out = (
-            (TEMP_TO_COLOR[int_index + 1].0 as f64 * frac + TEMP_TO_COLOR[int_index].0 as f64 * (1.0 - frac)) / 255.0,
-            (TEMP_TO_COLOR[int_index + 1].1 as f64 * frac + TEMP_TO_COLOR[int_index].1 as f64 * (1.0 - frac)) / 255.0,
-            (TEMP_TO_COLOR[int_index + 1].2 as f64 * frac + TEMP_TO_COLOR[int_index].2 as f64 * (1.0 - frac)) / 255.0,
-        );
-        out
     }
 }
 
 impl Photon {
-    pub fn new(pos: Vec4, pixel: (usize, usize), vel: Vec4) -> Self {
+    pub fn new(pos: Vec4, pixel: (usize, usize), vel: Vec4, rng: fastrand::Rng) -> Self {
         Self {
             pos,
             vel,
             pixel,
             temps: [(0.0, 0.0); TEMP_RECORD],
+            crossings: [Crossing::default(); TEMP_RECORD],
             temp_index: 0,
             depth: 1.0,
             compton_scatter: None,
+            rng,
         }
     }
 
-    fn get_data(&self) -> PhotonData {
+    /// What the photon saw. Scattered light is as bright as it physically is in `emission`, and
+    /// divided by the corona's mean gain in energy in the X-ray image.
+    fn get_data(&self, termination: Termination, failure: Option<Failure>, affine_length: f64, source: &AccretionDisk) -> PhotonData {
+        let xray_gain = if self.compton_scatter.is_some() { source.xray_gain() } else { 1.0 };
         let mut optical_color = (0.0, 0.0, 0.0);
         let mut xray_color = (0.0, 0.0, 0.0);
+        let mut emission = Vec::with_capacity(self.temp_index);
         for temp_index in 0..self.temp_index {
             let (temp, lum) = self.temps[temp_index];
-            let temp_color = PhotonData::temp_to_color(temp / 8.62e-5); // Convert to kelvin
+            // Light per unit bolometric intensity, which is what `lum` measures
+            let temp_color = spectrum::blackbody_rgb(temp / spectrum::BOLTZMANN_EV); // Convert to kelvin
             match self.compton_scatter {
-                Some(lum_shift) => {
+                Some((lum_shift, weight)) => {
+                    let lum = lum * lum_shift * weight;
                     xray_color = (
-                        xray_color.0 + temp_color.0 * lum * lum_shift / RESCALE_FOR_XRAY,
-                        xray_color.1 + temp_color.1 * lum * lum_shift / RESCALE_FOR_XRAY,
-                        xray_color.2 + temp_color.2 * lum * lum_shift / RESCALE_FOR_XRAY
+                        xray_color.0 + temp_color.0 * lum / xray_gain,
+                        xray_color.1 + temp_color.1 * lum / xray_gain,
+                        xray_color.2 + temp_color.2 * lum / xray_gain
                     );
+                    emission.push((temp * lum_shift, lum));
                 }
                 None => {
                     optical_color = (
@@ -305,6 +367,7 @@ impl Photon {
                         optical_color.1 + temp_color.1 * lum,
                         optical_color.2 + temp_color.2 * lum
                     );
+                    emission.push((temp, lum));
                 }
             }
         }
@@ -313,12 +376,46 @@ impl Photon {
             optical_color,
             xray_color,
             pixel: self.pixel,
+            termination,
+            failure,
+            escape_direction: match termination {
+                // The ray is traced backwards, so it travels along -vel
+                Termination::Escape => Some(normalize(spher_to_cart_vel(
+                    [self.pos[1], self.pos[2], self.pos[3]],
+                    [-self.vel[1], -self.vel[2], -self.vel[3]],
+                ))),
+                _ => None,
+            },
+            affine_length,
+            transmission: if self.compton_scatter.is_some() { 0.0 } else { self.depth },
+            emission,
+            scattered: self.compton_scatter.is_some(),
+            crossings: self.crossings[..self.temp_index].to_vec(),
         }
     }
 
-    pub fn run<M: Metric>(mut self, max_iterations: usize, dtau: f64, metric: &M, source: &AccretionDisk, rng: &fastrand::Rng) -> PhotonData {
+    pub fn run<M: Metric>(self, max_iterations: usize, dtau: f64, metric: &M, source: &AccretionDisk) -> PhotonData {
+        self.run_recording(max_iterations, dtau, metric, source, None)
+    }
+
+    /// Like `run`, but also return the position (t, r, θ, φ) at the start, every `stride` steps
+    /// and at the end of the path, each once. Positions that are not finite are left out.
+    pub fn trace<M: Metric>(self, max_iterations: usize, dtau: f64, metric: &M, source: &AccretionDisk, stride: usize) -> (PhotonData, Vec<Vec4>) {
+        let mut path = Vec::new();
+        let data = self.run_recording(max_iterations, dtau, metric, source, Some((stride.max(1), &mut path)));
+        (data, path)
+    }
+
+    fn run_recording<M: Metric>(mut self, max_iterations: usize, dtau: f64, metric: &M, source: &AccretionDisk, mut path: Option<(usize, &mut Vec<Vec4>)>) -> PhotonData {
+        if let Some((_, path)) = &mut path {
+            path.push(self.pos);
+        }
+        // The camera is at rest where the photon starts
+        let observer_time = 1.0 / (-metric.get_metric(self.pos)[0]).sqrt();
         let mut iteration = 0;
-        loop {
+        let mut failure = None;
+        let mut affine_length = 0.0;
+        let termination = loop {
             let angle_dist = std::f64::consts::PI / 2.0 - (std::f64::consts::PI / 2.0 - self.pos[2]).abs();
             let use_dtau = dtau * f64::min(
                 self.pos[1] - metric.get_horizon() + EPSILON,
@@ -328,30 +425,52 @@ impl Photon {
             // Use subtraction because we're back-propagating.
             self.pos = sub4(self.pos, mul4(dp1, use_dtau));
             self.vel = sub4(self.vel, mul4(dv1, use_dtau));
+            affine_length += use_dtau;
+            if self.pos.iter().chain(self.vel.iter()).any(|x| !x.is_finite()) {
+                failure = Some(Failure::Nan);
+                break Termination::Invalid;
+            }
 
             let new_above = self.pos[2] < std::f64::consts::PI / 2.0;
             if old_above ^ new_above {
                 // Crossed the disk
-                let redshift = (-metric.get_metric(self.pos)[0]).sqrt();
-                let (temp, lum, depth_inc) = source.disk_collision(self.pos, self.vel, redshift);
-                self.temps[self.temp_index] = (temp, lum * self.depth);
-                self.depth *= depth_inc;
-                self.temp_index += 1;
-                if (self.temp_index) >= TEMP_RECORD {
-                    break;
+                let hit = source.disk_collision(self.pos, self.vel, metric, observer_time).and_then(|collision| Some((
+                    collision,
+                    source.frequency_shift(self.pos, self.vel, metric, observer_time)?,
+                    source.emission_cosine(self.pos, self.vel, metric)?,
+                )));
+                if let Some(((temp, lum, depth_inc), shift, cosine)) = hit {
+                    self.temps[self.temp_index] = (temp, lum * self.depth);
+                    self.crossings[self.temp_index] = Crossing {
+                        radius: self.pos[1],
+                        shift,
+                        cosine,
+                        transmission: self.depth,
+                    };
+                    self.depth *= depth_inc;
+                    self.temp_index += 1;
+                    if (self.temp_index) >= TEMP_RECORD {
+                        break Termination::DiskCrossings;
+                    }
+                    // Nothing further back can be seen, unless the corona can still scatter the
+                    // ray, which starts the light over
+                    if self.depth < OPAQUE_DEPTH && (self.compton_scatter.is_some() || !source.has_corona()) {
+                        break Termination::DiskOpaque;
+                    }
                 }
             }
 
             if iteration % CORONA_INTERACTION == 0 && self.compton_scatter.is_none() {
                 let collision_prob = source.corona_prob(self.pos);
-                if rng.f64() < collision_prob * (dp1[0].abs() * use_dtau * CORONA_INTERACTION as f64) {
-                    // Compton interacted!
-                    let (energy_factor, new_vel) = source.corona_collide(self.pos, self.vel, &metric.get_metric(self.pos), &rng);
-                    // Get rid of data accumulated so far
-                    self.temp_index = 0;
-                    self.vel = new_vel;
-                    self.compton_scatter = Some(energy_factor);
-                    self.depth = 1.0;
+                if self.rng.f64() < collision_prob * (dp1[0].abs() * use_dtau * CORONA_INTERACTION as f64) {
+                    if let Some((energy_factor, weight, new_vel)) = source.corona_collide(self.pos, self.vel, &metric.get_metric(self.pos), &self.rng) {
+                        // Compton interacted!
+                        // Get rid of data accumulated so far
+                        self.temp_index = 0;
+                        self.vel = new_vel;
+                        self.compton_scatter = Some((energy_factor, weight));
+                        self.depth = 1.0;
+                    }
                 }
             }
 
@@ -361,43 +480,85 @@ impl Photon {
                 1
             };
             if iteration % offset_check == 0 {
+                // Photons freeze onto the horizon in these coordinates and lose precision there,
+                // but they are about to be stopped anyway
+                let near_horizon = self.pos[1] - metric.get_horizon() < EPSILON;
                 let metric = metric.get_metric(self.pos);
                 let linear_offset = dot4(self.vel, matvecmul(&metric, self.vel));
+                // Relative to the terms of g(v, v), which vary by orders of magnitude along a path
+                let scale: f64 = (0..16).map(|k| (metric[k] * self.vel[k % 4] * self.vel[k / 4]).abs()).sum();
+                if !near_horizon && (linear_offset.is_nan() || linear_offset.abs() > CONSTRAINT_TOLERANCE * scale) {
+                    failure = Some(Failure::Constraint);
+                    break Termination::Invalid;
+                }
                 let quad_offset = dot4(self.vel, matvecmul(&matsquare(&metric), self.vel));
                 self.vel = add4(self.vel, mul4(matvecmul(&metric, self.vel), -0.5 * linear_offset / quad_offset));
                 
                 let new_offset = dot4(self.vel, matvecmul(&metric, self.vel));
                 self.vel[0] = (-new_offset / metric[0] + self.vel[0] * self.vel[0]).sqrt();
-
-                // #[cfg(debug_assertions)]
-                // {
-                //     if self.pos[0].is_nan() {
-                //         break;
-                //     }
-                //     if linear_offset > 0.1 {
-                //         println!("WARNING: large offset of {} detected, {}", linear_offset, new_offset);
-                //     }
-                // }
             }
 
             // Check location
             match metric.get_state(self.pos) {
                 State::Dead => {
-                    break;
+                    break Termination::Horizon;
                 },
                 State::Escape => {
-                    break
+                    break Termination::Escape;
                 },
                 State::Running => (),
             };
             if iteration >= max_iterations {
-                break;
+                failure = Some(Failure::MaxIterations);
+                break Termination::MaxIterations;
             }
 
             iteration += 1;
+            if let Some((stride, path)) = &mut path {
+                if iteration % *stride == 0 {
+                    path.push(self.pos);
+                }
+            }
+        };
+        if let Some((_, path)) = &mut path {
+            // The end is kept unless the last stride already recorded it
+            if path.last() != Some(&self.pos) {
+                path.push(self.pos);
+            }
+            path.retain(|p| p.iter().all(|x| x.is_finite()));
         }
 
         // Convert to photon data
-        self.get_data()
+        self.get_data(termination, failure, affine_length, source)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Schwarzschild;
+
+    /// Trace one photon from the camera position of the example renders with velocity `vel`.
+    fn trace(vel: Vec4) -> PhotonData {
+        Photon::new([0.0, 10.0, 1.27, 0.0], (0, 0), vel, fastrand::Rng::with_seed(1))
+            .run(1000, 0.1, &Schwarzschild::new(), &AccretionDisk::corona())
+    }
+
+    #[test]
+    fn nan_photon_is_quarantined() {
+        let data = trace([1.0, f64::NAN, 0.0, 0.0]);
+        assert_eq!(data.termination, Termination::Invalid);
+        assert_eq!(data.failure, Some(Failure::Nan));
+        assert!(data.failure.unwrap().quarantined());
+        assert!(data.emission.is_empty());
+    }
+
+    #[test]
+    fn photon_off_the_null_cone_is_quarantined() {
+        // g(v, v) is about 27 at r = 10, against terms of about 29
+        let data = trace([1.0, -5.0, 0.0, 0.0]);
+        assert_eq!(data.termination, Termination::Invalid);
+        assert_eq!(data.failure, Some(Failure::Constraint));
+        assert!(data.failure.unwrap().quarantined());
+        assert!(data.emission.is_empty());
+    }
+}
@@ -0,0 +1,428 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::fmt::Write as _;
+use ndarray::Array2;
+use ndarray_npy::write_npy;
+
+use crate::util::*;
+use crate::metrics::Metric;
+use crate::observer::Termination;
+use crate::error::{Error, Result};
+
+// Massive particles on timelike geodesics, g(u, u) = -1, with u = dx/dτ and τ the proper time.
+// Lengths are in Schwarzschild radii as everywhere else, so GM/c^2 = 1/2. Orbits are integrated
+// forwards in time with fourth order Runge-Kutta, unlike photons, since periapsis precession
+// builds up over many orbits and needs the accuracy.
+
+/// GM/c^2 in Schwarzschild radii
+pub const MASS: f64 = 0.5;
+/// Schwarzschild radius of one solar mass, in metres
+pub const SOLAR_SCHWARZSCHILD_RADIUS: f64 = 2953.25;
+pub const AU: f64 = 1.495978707e11; // Metres
+const SPEED_OF_LIGHT: f64 = 299792458.0;
+const YEAR: f64 = 3.15576e7; // Julian year in seconds
+const MAX_STEPS: usize = 100_000_000;
+const HORIZON_MARGIN: f64 = 1e-3; // Particles this close (relative) to the horizon have fallen in
+const POLE_PROTECTION: f64 = 4.0; // Shrinks the step near θ = 0 and π, where φ changes quickly
+const MIN_POLE_FACTOR: f64 = 1e-3;
+const ESCAPE_FACTOR: f64 = 100.0; // Unbound particles this many times further out than they started have escaped
+const MAX_RADIUS_JUMP: f64 = 0.5; // Largest relative change in r over one step before the integration is deemed broken
+const POLAR_LIMIT: f64 = 1e-9; // Orbits with |cos i| below this pass through the pole of the coordinates
+const EQUATORIAL_LIMIT: f64 = 1e-9; // Orbits with |sin i| below this lie in the equator
+const NEWTON_ITERATIONS: usize = 50;
+const NEWTON_TOLERANCE: f64 = 1e-12; // Relative, a little above rounding
+const CIRCULAR_STEP: f64 = 1e-6; // Relative step in r for the slope of the potential of a circular orbit
+
+/// Keplerian elements of an orbit. Angles are in radians and the reference plane is the
+/// equator, z along the spin axis.
+#[derive(Debug, Clone, Copy)]
+pub struct OrbitalElements {
+    /// In Schwarzschild radii. Periapsis and apoapsis are a (1 - e) and a (1 + e).
+    pub semi_major: f64,
+    pub eccentricity: f64,
+    pub inclination: f64,
+    pub ascending_node: f64,
+    pub periapsis_argument: f64,
+    /// Where the particle starts, measured from periapsis
+    pub true_anomaly: f64,
+}
+
+impl OrbitalElements {
+    /// An equatorial orbit starting at periapsis.
+    pub fn new(semi_major: f64, eccentricity: f64) -> Self {
+        Self { semi_major, eccentricity, inclination: 0.0, ascending_node: 0.0, periapsis_argument: 0.0, true_anomaly: 0.0 }
+    }
+
+    /// Periapsis precession per orbit predicted to first order for a Schwarzschild hole,
+    /// 6πM / (a (1 - e^2)), in radians.
+    pub fn schwarzschild_precession(&self) -> f64 {
+        6.0 * PI * MASS / (self.semi_major * (1.0 - self.eccentricity * self.eccentricity))
+    }
+}
+
+pub struct Particle {
+    pos: Vec4, // t, r, theta, phi
+    vel: Vec4,
+    proper_time: f64,
+}
+
+/// A periapsis passage, interpolated between steps.
+#[derive(Debug, Clone, Copy)]
+pub struct Periapsis {
+    pub proper_time: f64,
+    pub time: f64,
+    pub radius: f64,
+    /// Total angle swept around the initial orbital axis since the start
+    pub angle: f64,
+}
+
+pub struct Orbit {
+    /// Proper time and position (t, r, θ, φ), every `stride` steps plus the start and the end
+    pub samples: Vec<(f64, Vec4)>,
+    pub periapses: Vec<Periapsis>,
+    /// Horizon for a plunge, Escape for an unbound particle, MaxIterations if the orbits were
+    /// completed (or the step limit was reached), Invalid if the integration broke down
+    pub termination: Termination,
+}
+
+impl Particle {
+    /// A particle at `pos` with spatial velocity `spatial` = (dr/dτ, dθ/dτ, dφ/dτ). The time
+    /// component is chosen so that g(u, u) = -1.
+    pub fn new<M: Metric>(pos: Vec4, spatial: Vec3, metric: &M) -> Result<Self> {
+        let vel = normalise(pos, spatial, metric)
+            .ok_or_else(|| Error::InvalidScene(format!("velocity {:?} at {:?} is not timelike", spatial, pos)))?;
+        Ok(Self { pos, vel, proper_time: 0.0 })
+    }
+
+    /// A particle on the orbit with `elements`. The energy and angular momentum are those of
+    /// the geodesic of `metric` that turns at the periapsis and apoapsis a (1 - e) and
+    /// a (1 + e), so the orbit has exactly the turning points asked for. Orbits out of the
+    /// equator need a spherical metric, where they are equatorial orbits turned over.
+    pub fn from_elements<M: Metric>(elements: &OrbitalElements, metric: &M) -> Result<Self> {
+        let e = elements.eccentricity;
+        if !(0.0..1.0).contains(&e) {
+            return Err(Error::InvalidScene(format!("eccentricity must be in [0, 1), not {}", e)));
+        }
+        if elements.inclination.cos().abs() < POLAR_LIMIT {
+            // φ is undefined on the axis, and the equations of motion divide by sin θ
+            return Err(Error::InvalidScene("orbits through the poles cannot be followed; tilt the orbit slightly, e.g. inclination 89.99".to_owned()));
+        }
+        if !metric.spherical() && elements.inclination.sin().abs() > EQUATORIAL_LIMIT {
+            // Off the equator the orbit also depends on the Carter constant
+            return Err(Error::InvalidScene(format!("orbits in {} must lie in the equator, with inclination 0 or 180", metric.name())));
+        }
+        // Prograde or retrograde, for metrics where it matters
+        let sense = if metric.spherical() { 1.0 } else { elements.inclination.cos().signum() };
+        let periapsis = elements.semi_major * (1.0 - e);
+        let apoapsis = elements.semi_major * (1.0 + e);
+        let (energy, angular) = conserved(metric, periapsis, apoapsis, sense)
+            .ok_or_else(|| Error::InvalidScene(format!("no bound orbit with a = {} and e = {} in {}", elements.semi_major, e, metric.name())))?;
+
+        let chi = elements.true_anomaly;
+        let r = elements.semi_major * (1.0 - e * e) / (1.0 + e * chi.cos());
+        let (inverse, g_rr) = equatorial_inverse(metric, r);
+        let radial2 = -potential(inverse, energy, angular) / g_rr;
+        let radial = radial2.max(0.0).sqrt() * if chi.sin() < 0.0 { -1.0 } else { 1.0 };
+        // dφ/dτ in the orbital plane, from u_t = -E and u_φ = L
+        let angular_speed = -inverse[1] * energy + inverse[2] * angular;
+
+        // Radial and tangential directions in the orbital plane, rotated into place
+        let (i, node, u) = (elements.inclination, elements.ascending_node, elements.periapsis_argument + chi);
+        let radial_dir = [
+            node.cos() * u.cos() - node.sin() * u.sin() * i.cos(),
+            node.sin() * u.cos() + node.cos() * u.sin() * i.cos(),
+            u.sin() * i.sin(),
+        ];
+        let tangent_dir = [
+            -node.cos() * u.sin() - node.sin() * u.cos() * i.cos(),
+            -node.sin() * u.sin() + node.cos() * u.cos() * i.cos(),
+            u.cos() * i.sin(),
+        ];
+        let cart_vel = add3(mul3(radial_dir, radial), mul3(tangent_dir, sense * r * angular_speed));
+        let theta = radial_dir[2].clamp(-1.0, 1.0).acos();
+        let phi = radial_dir[1].atan2(radial_dir[0]);
+        let pos = [0.0, r, theta, phi];
+        Self::new(pos, cart_to_spher_vel([r, theta, phi], cart_vel), metric)
+    }
+
+    pub fn position(&self) -> Vec4 {
+        self.pos
+    }
+
+    pub fn velocity(&self) -> Vec4 {
+        self.vel
+    }
+
+    /// Energy per unit mass at infinity, -u_t. Conserved in all the metrics here.
+    pub fn energy<M: Metric>(&self, metric: &M) -> f64 {
+        -matvecmul(&metric.get_metric(self.pos), self.vel)[0]
+    }
+
+    fn step<M: Metric>(&mut self, metric: &M, h: f64) {
+        let f = |pos: Vec4, vel: Vec4| metric.christoffel(pos).accel(vel);
+        let (k1p, k1v) = f(self.pos, self.vel);
+        let (k2p, k2v) = f(add4(self.pos, mul4(k1p, h / 2.0)), add4(self.vel, mul4(k1v, h / 2.0)));
+        let (k3p, k3v) = f(add4(self.pos, mul4(k2p, h / 2.0)), add4(self.vel, mul4(k2v, h / 2.0)));
+        let (k4p, k4v) = f(add4(self.pos, mul4(k3p, h)), add4(self.vel, mul4(k3v, h)));
+        let sum = |a: Vec4, b: Vec4, c: Vec4, d: Vec4| add4(add4(a, mul4(b, 2.0)), add4(mul4(c, 2.0), d));
+        self.pos = add4(self.pos, mul4(sum(k1p, k2p, k3p, k4p), h / 6.0));
+        self.vel = add4(self.vel, mul4(sum(k1v, k2v, k3v, k4v), h / 6.0));
+        self.proper_time += h;
+        // Keep the velocity on the mass shell
+        if let Some(vel) = normalise(self.pos, [self.vel[1], self.vel[2], self.vel[3]], metric) {
+            self.vel = vel;
+        }
+    }
+
+    /// Integrate until `orbits` radial periods have been completed, the particle falls into
+    /// the hole or escapes. Steps are `dtau` r^(3/2) in proper time, a fixed fraction of the
+    /// local orbital time. Every `stride`th position is kept.
+    pub fn run<M: Metric>(mut self, metric: &M, dtau: f64, orbits: usize, stride: usize) -> Orbit {
+        let stride = stride.max(1);
+        let start_radius = self.pos[1];
+        let mut samples = vec![(self.proper_time, self.pos)];
+        let mut periapses = Vec::new();
+        let mut angle = 0.0;
+        let mut position = to_cartesian(self.pos);
+        let axis = normalize(cross(position, spher_to_cart_vel([self.pos[1], self.pos[2], self.pos[3]], [self.vel[1], self.vel[2], self.vel[3]])));
+        let mut step = 0;
+        let termination = loop {
+            let old = (self.proper_time, self.pos, self.vel);
+            let pole = (POLE_PROTECTION * self.pos[2].sin().abs()).clamp(MIN_POLE_FACTOR, 1.0);
+            self.step(metric, dtau * self.pos[1].powf(1.5) * pole);
+            step += 1;
+            if self.pos.iter().chain(self.vel.iter()).any(|x| !x.is_finite())
+                || (self.pos[1] - old.1[1]).abs() > MAX_RADIUS_JUMP * old.1[1] {
+                break Termination::Invalid;
+            }
+
+            let new_position = to_cartesian(self.pos);
+            let swept = dot3(cross(position, new_position), axis).atan2(dot3(position, new_position));
+            angle += swept;
+            position = new_position;
+            if old.2[1] < 0.0 && self.vel[1] >= 0.0 {
+                let f = -old.2[1] / (self.vel[1] - old.2[1]);
+                let lerp = |a: f64, b: f64| a + f * (b - a);
+                periapses.push(Periapsis {
+                    proper_time: lerp(old.0, self.proper_time),
+                    time: lerp(old.1[0], self.pos[0]),
+                    radius: lerp(old.1[1], self.pos[1]),
+                    angle: angle - (1.0 - f) * swept,
+                });
+            }
+            if step % stride == 0 {
+                samples.push((self.proper_time, self.pos));
+            }
+
+            if self.pos[1] < metric.get_horizon() * (1.0 + HORIZON_MARGIN) {
+                break Termination::Horizon;
+            }
+            if self.pos[1] > ESCAPE_FACTOR * start_radius && self.vel[1] > 0.0 && self.energy(metric) >= 1.0 {
+                break Termination::Escape;
+            }
+            if periapses.len() > orbits || step >= MAX_STEPS {
+                break Termination::MaxIterations;
+            }
+        };
+        if samples.last().map(|s| s.0) != Some(self.proper_time) && self.pos.iter().all(|x| x.is_finite()) {
+            samples.push((self.proper_time, self.pos));
+        }
+        Orbit { samples, periapses, termination }
+    }
+}
+
+impl Orbit {
+    /// Mean time between periapses, in coordinate time and in proper time.
+    pub fn period(&self) -> Option<(f64, f64)> {
+        let (first, last) = (self.periapses.first()?, self.periapses.last()?);
+        let n = (self.periapses.len() - 1) as f64;
+        (n > 0.0).then(|| ((last.time - first.time) / n, (last.proper_time - first.proper_time) / n))
+    }
+
+    /// Mean advance of the periapsis per radial period, in radians.
+    pub fn precession(&self) -> Option<f64> {
+        let (first, last) = (self.periapses.first()?, self.periapses.last()?);
+        let n = (self.periapses.len() - 1) as f64;
+        (n > 0.0).then(|| (last.angle - first.angle) / n - 2.0 * PI)
+    }
+
+    /// Write `<base>-orbit.csv` and `<base>-orbit.npy`, one row per sample with the columns τ,
+    /// t, r, θ, φ, x, y, z.
+    pub fn write(&self, base: &str) -> Result<()> {
+        let mut table = Array2::zeros((self.samples.len(), 8));
+        let mut csv = "tau,t,r,theta,phi,x,y,z\n".to_owned();
+        for (row, (tau, p)) in self.samples.iter().enumerate() {
+            let c = to_cartesian(*p);
+            let values = [*tau, p[0], p[1], p[2], p[3], c[0], c[1], c[2]];
+            for (column, value) in values.iter().enumerate() {
+                table[(row, column)] = *value;
+            }
+            writeln!(csv, "{},{},{},{},{},{},{},{}", tau, p[0], p[1], p[2], p[3], c[0], c[1], c[2]).unwrap();
+        }
+        fs::write(format!("{}-orbit.csv", base), csv)?;
+        write_npy(format!("{}-orbit.npy", base), &table)?;
+        Ok(())
+    }
+
+    /// Periods, precession and periapses as JSON. With the mass of the hole in solar masses,
+    /// periods are also given in years.
+    pub fn summary(&self, elements: &OrbitalElements, mass: Option<f64>) -> serde_json::Value {
+        let period = self.period();
+        let seconds = mass.map(|m| m * SOLAR_SCHWARZSCHILD_RADIUS / SPEED_OF_LIGHT);
+        serde_json::json!({
+            "elements": {
+                "semi_major": elements.semi_major,
+                "eccentricity": elements.eccentricity,
+                "inclination_deg": elements.inclination.to_degrees(),
+                "ascending_node_deg": elements.ascending_node.to_degrees(),
+                "periapsis_argument_deg": elements.periapsis_argument.to_degrees(),
+                "true_anomaly_deg": elements.true_anomaly.to_degrees(),
+            },
+            "mass_msun": mass,
+            "termination": self.termination.name(),
+            "orbits": self.periapses.len().saturating_sub(1),
+            "period": period.map(|p| p.0),
+            "proper_period": period.map(|p| p.1),
+            "period_years": period.and_then(|p| seconds.map(|s| p.0 * s / YEAR)),
+            "precession_rad": self.precession(),
+            "precession_arcmin": self.precession().map(|p| p.to_degrees() * 60.0),
+            "schwarzschild_precession_rad": elements.schwarzschild_precession(),
+            "periapses": self.periapses.iter().map(|p| serde_json::json!({
+                "proper_time": p.proper_time, "time": p.time, "radius": p.radius, "angle": p.angle,
+            })).collect::<Vec<_>>(),
+            "end_proper_time": self.samples.last().map(|s| s.0),
+        })
+    }
+}
+
+/// Semi-major axis in Schwarzschild radii of an orbit of `au` astronomical units around a hole
+/// of `mass` solar masses.
+pub fn au_to_schwarzschild(au: f64, mass: f64) -> f64 {
+    au * AU / (mass * SOLAR_SCHWARZSCHILD_RADIUS)
+}
+
+/// The time component of the velocity that puts it on the mass shell, g(u, u) = -1, going
+/// forwards in time. None if the spatial part is already too fast.
+fn normalise<M: Metric>(pos: Vec4, spatial: Vec3, metric: &M) -> Option<Vec4> {
+    let g = metric.get_metric(pos);
+    let v = [0.0, spatial[0], spatial[1], spatial[2]];
+    // g_tt (u^t)^2 + 2 b u^t + c = 0
+    let b = matvecmul(&g, v)[0];
+    let c = dot4(v, matvecmul(&g, v)) + 1.0;
+    let discriminant = b * b - g[0] * c;
+    if discriminant.is_nan() || discriminant < 0.0 || g[0] >= 0.0 {
+        return None;
+    }
+    Some([(-b - discriminant.sqrt()) / g[0], spatial[0], spatial[1], spatial[2]])
+}
+
+/// (g^tt, g^tφ, g^φφ) and g_rr in the equator at radius `r`.
+fn equatorial_inverse<M: Metric>(metric: &M, r: f64) -> ([f64; 3], f64) {
+    let g = metric.get_metric([0.0, r, PI / 2.0, 0.0]);
+    let det = g[0] * g[15] - g[3] * g[3];
+    ([g[15] / det, -g[3] / det, g[0] / det], g[5])
+}
+
+/// g^μν u_μ u_ν + 1 for u_t = -E, u_φ = L and no motion in θ, which is -g_rr (u^r)^2: zero at
+/// turning points and negative where the particle can be.
+fn potential(inverse: [f64; 3], energy: f64, angular: f64) -> f64 {
+    inverse[0] * energy * energy - 2.0 * inverse[1] * energy * angular + inverse[2] * angular * angular + 1.0
+}
+
+/// Energy and angular momentum per unit mass of the equatorial geodesic of `metric` with
+/// turning points at `periapsis` and `apoapsis`, going round in the direction `sense`. The
+/// potential vanishes at both, or for a circular orbit vanishes with zero slope. Solved by
+/// Newton's method from the Schwarzschild values (Cutler, Kennefick and Poisson 1994).
+fn conserved<M: Metric>(metric: &M, periapsis: f64, apoapsis: f64, sense: f64) -> Option<(f64, f64)> {
+    let circular = apoapsis - periapsis < CIRCULAR_STEP * periapsis;
+    let (low, high) = if circular {
+        (periapsis * (1.0 - CIRCULAR_STEP), periapsis * (1.0 + CIRCULAR_STEP))
+    } else {
+        (periapsis, apoapsis)
+    };
+    let (at_periapsis, _) = equatorial_inverse(metric, periapsis);
+    let (at_low, _) = equatorial_inverse(metric, low);
+    let (at_high, _) = equatorial_inverse(metric, high);
+    // Value and gradient in (E, L) of the potential
+    let value = |inverse: [f64; 3], x: (f64, f64)| (
+        potential(inverse, x.0, x.1),
+        [2.0 * (inverse[0] * x.0 - inverse[1] * x.1), 2.0 * (inverse[2] * x.1 - inverse[1] * x.0)],
+    );
+
+    let e = (apoapsis - periapsis) / (apoapsis + periapsis);
+    // Semi-latus rectum in units of M, kept off the Schwarzschild separatrix for the first guess
+    let p = (2.0 * periapsis * apoapsis / (apoapsis + periapsis) / MASS).max(3.5 + e * e);
+    let mut x = (
+        (((p - 2.0).powi(2) - 4.0 * e * e) / (p * (p - 3.0 - e * e))).sqrt(),
+        sense * MASS * p / (p - 3.0 - e * e).sqrt(),
+    );
+    for _ in 0..NEWTON_ITERATIONS {
+        let (f1, d1) = value(at_periapsis, x);
+        let ((low_f, low_d), (high_f, high_d)) = (value(at_low, x), value(at_high, x));
+        // The slope of the potential between the turning points
+        let (f2, d2) = ((high_f - low_f) / (high - low), [(high_d[0] - low_d[0]) / (high - low), (high_d[1] - low_d[1]) / (high - low)]);
+        let det = d1[0] * d2[1] - d1[1] * d2[0];
+        let step = ((f1 * d2[1] - f2 * d1[1]) / det, (d1[0] * f2 - d2[0] * f1) / det);
+        x = (x.0 - step.0, x.1 - step.1);
+        if !(x.0.is_finite() && x.1.is_finite()) {
+            return None;
+        }
+        if step.0.abs() + step.1.abs() < NEWTON_TOLERANCE * (x.0.abs() + x.1.abs()) {
+            // A bound orbit, going the right way, that can move between the turning points
+            let (middle, _) = equatorial_inverse(metric, (periapsis + apoapsis) / 2.0);
+            let allowed = circular || potential(middle, x.0, x.1) < 0.0;
+            return (x.0 > 0.0 && x.0 < 1.0 && x.1 * sense > 0.0 && allowed).then_some(x);
+        }
+    }
+    None
+}
+
+/// x = r sin θ cos φ, y = r sin θ sin φ, z = r cos θ.
+fn to_cartesian(p: Vec4) -> Vec3 {
+    [p[1] * p[2].sin() * p[3].cos(), p[1] * p[2].sin() * p[3].sin(), p[1] * p[2].cos()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Kerr, Schwarzschild};
+
+    /// Energy and angular momentum of a Schwarzschild orbit (Cutler, Kennefick and Poisson 1994).
+    fn schwarzschild_conserved(semi_major: f64, e: f64) -> (f64, f64) {
+        let p = semi_major * (1.0 - e * e) / MASS;
+        let energy = (((p - 2.0).powi(2) - 4.0 * e * e) / (p * (p - 3.0 - e * e))).sqrt();
+        (energy, MASS * p / (p - 3.0 - e * e).sqrt())
+    }
+
+    #[test]
+    fn conserved_quantities_match_schwarzschild() {
+        for (semi_major, e) in [(20.0, 0.3), (8.0, 0.1), (1000.0, 0.9), (12.0, 0.0)] {
+            let (energy, angular) = conserved(&Schwarzschild::new(), semi_major * (1.0 - e), semi_major * (1.0 + e), 1.0).unwrap_or_else(|| panic!("a = {}, e = {}", semi_major, e));
+            let (expected_energy, expected_angular) = schwarzschild_conserved(semi_major, e);
+            assert!((energy - expected_energy).abs() < 1e-9, "a = {}, e = {}: E = {}, not {}", semi_major, e, energy, expected_energy);
+            assert!((angular / expected_angular - 1.0).abs() < 1e-6, "a = {}, e = {}: L = {}, not {}", semi_major, e, angular, expected_angular);
+        }
+    }
+
+    #[test]
+    fn periapsis_precession_matches_schwarzschild() {
+        let metric = Schwarzschild::new();
+        for (semi_major, e) in [(200.0, 0.5), (500.0, 0.2)] {
+            let elements = OrbitalElements { inclination: 0.4, ascending_node: 1.0, ..OrbitalElements::new(semi_major, e) };
+            let precession = Particle::from_elements(&elements, &metric).unwrap().run(&metric, 1e-3, 3, 100).precession().unwrap();
+            // First order, 6πM / p, plus the second order term (3π/2)(18 + e^2)(M / p)^2
+            let first = elements.schwarzschild_precession();
+            let p = semi_major * (1.0 - e * e) / MASS;
+            let expected = first + 1.5 * PI * (18.0 + e * e) / (p * p);
+            assert!((precession / expected - 1.0).abs() < 1e-3, "a = {}, e = {}: {} rad per orbit, not {}", semi_major, e, precession, expected);
+            assert!((precession / first - 1.0).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn kerr_orbits_off_the_equator_are_rejected() {
+        let elements = OrbitalElements { inclination: 0.3, ..OrbitalElements::new(20.0, 0.2) };
+        assert!(Particle::from_elements(&elements, &Kerr::new(0.5).unwrap()).is_err());
+        assert!(Particle::from_elements(&OrbitalElements::new(20.0, 0.2), &Kerr::new(0.5).unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io::BufWriter;
+use ndarray::{Array3, Array2};
+
+// Quick-look images written while a render is still running, as 8-bit PNGs.
+
+/// Write the counts-normalised colour image as an RGB PNG. Pixels with no samples yet are black.
+/// The brightest channel of any pixel is mapped to white.
+pub fn write_color(path: &str, color: &Array3<f64>, counts: &Array2<f64>) -> std::io::Result<()> {
+    let (height, width) = counts.dim();
+    let mut max_val = 0.0f64;
+    for i in 0..height {
+        for j in 0..width {
+            if counts[(i, j)] > 0.0 {
+                for c in 0..3 {
+                    max_val = max_val.max(color[(c, i, j)] / counts[(i, j)]);
+                }
+            }
+        }
+    }
+
+    let mut bytes = Vec::with_capacity(3 * height * width);
+    // Row 0 is the bottom of the image
+    for i in (0..height).rev() {
+        for j in 0..width {
+            for c in 0..3 {
+                let value = if counts[(i, j)] > 0.0 && max_val > 0.0 {
+                    color[(c, i, j)] / counts[(i, j)] / max_val
+                } else {
+                    0.0
+                };
+                bytes.push(to_byte(value));
+            }
+        }
+    }
+    write_bytes(path, width, height, png::ColorType::Rgb, &bytes)
+}
+
+/// Write the number of samples per pixel as a greyscale PNG, scaled so the most-sampled pixel is white.
+pub fn write_counts(path: &str, counts: &Array2<f64>) -> std::io::Result<()> {
+    let (height, width) = counts.dim();
+    let max_val = counts.iter().cloned().fold(0.0, f64::max);
+
+    let mut bytes = Vec::with_capacity(height * width);
+    for i in (0..height).rev() {
+        for j in 0..width {
+            let value = if max_val > 0.0 { counts[(i, j)] / max_val } else { 0.0 };
+            bytes.push(to_byte(value));
+        }
+    }
+    write_bytes(path, width, height, png::ColorType::Grayscale, &bytes)
+}
+
+fn write_bytes(path: &str, width: usize, height: usize, color: png::ColorType, bytes: &[u8]) -> std::io::Result<()> {
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width as u32, height as u32);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(bytes)?;
+    Ok(())
+}
+
+fn to_byte(value: f64) -> u8 {
+    if value.is_nan() {
+        return 0;
+    }
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
@@ -0,0 +1,233 @@
+use std::fs;
+
+use crate::engine::{self, field, Accumulator};
+use crate::error::{Error, Result};
+use crate::fits::Card;
+use crate::image::ToneMap;
+
+struct Partial {
+    name: String,
+    image: Accumulator,
+    start: usize,
+    end: usize,
+    total: usize,
+    seed: String,
+    scene_hash: String,
+    cards: Vec<Card>,
+    manifest: serde_json::Value,
+}
+
+/// Combine the partial directories written by `Engine::with_shard` for `file_name` into the final
+/// images in `directory`. All shards must come from the same scene and seed, have their
+/// manifests, and have photon ranges that do not overlap. Missing ranges are reported and
+/// recorded in the manifest but do not stop the merge. Images are tone mapped with `tone_map`.
+/// Returns the number of photons.
+pub fn merge(directory: &str, file_name: &str, tone_map: &ToneMap) -> Result<usize> {
+    let prefix = format!("{}-shard", file_name);
+    let mut partials = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry_name = match entry?.file_name().into_string() {
+            Ok(entry_name) => entry_name,
+            Err(_) => continue,
+        };
+        // Only match the directories <file_name>-shard<k>of<n>, not checkpoints of shards
+        let shard = match entry_name.strip_prefix(&prefix) {
+            Some(s) => s,
+            None => continue,
+        };
+        let valid = shard.split_once("of").is_some_and(|(k, n)| {
+            k.parse::<usize>().is_ok() && n.parse::<usize>().is_ok()
+        });
+        if !valid {
+            continue;
+        }
+
+        let name = format!("{}/{}{}", directory, prefix, shard);
+        let (image, metadata) = match Accumulator::read_raw(&name)? {
+            Some(raw) => raw,
+            None => continue,
+        };
+        let mut cards: Vec<(usize, Card)> = metadata.iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix("card.")?.parse().ok()?, Card::from_raw(value))))
+            .collect();
+        cards.sort_by_key(|(i, _)| *i);
+        let manifest = fs::read_to_string(format!("{}.json", name)).ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .ok_or_else(|| Error::Merge(format!("{}.json is missing or unreadable: copy each shard's manifest with its directory", name)))?;
+        partials.push(Partial {
+            image,
+            start: field(&metadata, "start", &name)?,
+            end: field(&metadata, "end", &name)?,
+            total: field(&metadata, "total", &name)?,
+            seed: field(&metadata, "seed", &name)?,
+            scene_hash: field(&metadata, "scene_hash", &name)?,
+            cards: cards.into_iter().map(|(_, card)| card).collect(),
+            manifest,
+            name,
+        });
+    }
+    if partials.is_empty() {
+        return Err(Error::Merge(format!("no shards found for {}", file_name)));
+    }
+    partials.sort_by_key(|p| p.start);
+
+    let first = &partials[0];
+    for p in &partials {
+        if p.scene_hash != first.scene_hash {
+            return Err(Error::Merge(format!("{} was rendered from a different scene than {}", p.name, first.name)));
+        }
+        if p.total != first.total {
+            return Err(Error::Merge(format!("{} has a different photon count than {}", p.name, first.name)));
+        }
+        if p.seed != first.seed {
+            return Err(Error::Merge(format!("{} was rendered with a different seed than {}", p.name, first.name)));
+        }
+    }
+    for pair in partials.windows(2) {
+        if pair[1].start < pair[0].end {
+            return Err(Error::Merge(format!("{} and {} overlap", pair[0].name, pair[1].name)));
+        }
+    }
+    let missing = missing_ranges(&partials);
+    for (start, end) in &missing {
+        println!("WARNING: photons {} to {} are missing", start, end);
+    }
+
+    let mut image = Accumulator::new();
+    for p in &partials {
+        image.merge_from(&p.image);
+    }
+    let base = format!("{}/{}", directory, file_name);
+    image.save(&base)?;
+    image.save_fits(&format!("{}.fits", base), &first.cards)?;
+    image.save_images(&base, tone_map);
+    write_manifest(&base, &partials, &missing, &image)?;
+    println!("Merged {} shards", partials.len());
+    image.print_summary();
+
+    Ok(image.photon_count)
+}
+
+/// Photon ranges that no shard covers, with the shards sorted by where they start.
+fn missing_ranges(partials: &[Partial]) -> Vec<(usize, usize)> {
+    let mut missing = Vec::new();
+    let mut covered = 0;
+    for p in partials {
+        if p.start > covered {
+            missing.push((covered, p.start));
+        }
+        covered = p.end;
+    }
+    if covered < partials[0].total {
+        missing.push((covered, partials[0].total));
+    }
+    missing
+}
+
+/// Write `<base>.json` for the merged render: the first shard's manifest with the photon
+/// count, termination counts and wall time summed over all shards, the photon ranges that are
+/// `missing`, and each shard's own manifest.
+fn write_manifest(base: &str, partials: &[Partial], missing: &[(usize, usize)], image: &Accumulator) -> Result<()> {
+    let shards: Vec<serde_json::Value> = partials.iter().map(|p| p.manifest.clone()).collect();
+    let mut manifest = shards[0].clone();
+    let wall_time: f64 = shards.iter().filter_map(|m| m["wall_time_seconds"].as_f64()).sum();
+    manifest["wall_time_seconds"] = wall_time.into();
+    manifest["photon_count"] = image.photon_count.into();
+    manifest["terminations"] = image.termination_stats();
+    manifest["failures"] = image.failure_stats();
+    manifest["photon_range"] = serde_json::json!([0, partials[0].total]);
+    manifest["missing_photons"] = serde_json::json!(missing);
+    manifest["shard"] = serde_json::Value::Null;
+    manifest["shards"] = shards.into();
+    engine::write_manifest(&format!("{}.json", base), &manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory under the system's temporary one, empty.
+    fn directory(name: &str) -> String {
+        let directory = std::env::temp_dir().join(format!("raytracer-merge-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory.to_string_lossy().into_owned()
+    }
+
+    /// Write the partial directory and manifest of shard `index` of `count` covering photons
+    /// `start` to `end` of 100, as `Engine::with_shard` would.
+    fn shard(directory: &str, (index, count): (usize, usize), (start, end): (usize, usize), seed: u64, scene_hash: &str) {
+        let mut image = Accumulator::new();
+        image.photon_count = end - start;
+        let name = engine::shard_name(directory, "test", index, count);
+        let metadata = format!(
+            "shard={}/{}\nstart={}\nend={}\ntotal=100\nphoton_count={}\nseed={}\nscene_hash={}\ncard.0=OBJECT  = 'test'\n",
+            index, count, start, end, end - start, seed, scene_hash
+        );
+        image.write_raw(&name, &metadata).unwrap();
+        engine::write_manifest(&format!("{}.json", name), &serde_json::json!({ "seed": seed, "wall_time_seconds": 1.0 })).unwrap();
+    }
+
+    fn merged(directory: &str) -> Result<usize> {
+        merge(directory, "test", &ToneMap::default())
+    }
+
+    fn assert_refused(directory: &str, what: &str) {
+        match merged(directory) {
+            Err(Error::Merge(message)) => assert!(message.contains(what), "{}", message),
+            other => panic!("expected a refusal naming {}, got {:?}", what, other.map_err(|e| e.to_string())),
+        }
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn shards_are_merged_with_their_manifests() {
+        let dir = directory("whole");
+        shard(&dir, (0, 2), (0, 50), 1, "ab");
+        shard(&dir, (1, 2), (50, 100), 1, "ab");
+        assert_eq!(merged(&dir).unwrap(), 100);
+        let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(format!("{}/test.json", dir)).unwrap()).unwrap();
+        assert_eq!(manifest["photon_count"], 100);
+        assert_eq!(manifest["wall_time_seconds"], 2.0);
+        assert_eq!(manifest["shards"].as_array().map(Vec::len), Some(2));
+        assert_eq!(manifest["missing_photons"], serde_json::json!([]));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn missing_ranges_are_recorded() {
+        let dir = directory("missing");
+        shard(&dir, (1, 4), (25, 50), 1, "ab");
+        shard(&dir, (3, 4), (75, 100), 1, "ab");
+        assert_eq!(merged(&dir).unwrap(), 50);
+        let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(format!("{}/test.json", dir)).unwrap()).unwrap();
+        assert_eq!(manifest["missing_photons"], serde_json::json!([[0, 25], [50, 75]]));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn mismatched_shards_are_refused() {
+        let dir = directory("seed");
+        shard(&dir, (0, 2), (0, 50), 1, "ab");
+        shard(&dir, (1, 2), (50, 100), 2, "ab");
+        assert_refused(&dir, "different seed");
+
+        let dir = directory("scene");
+        shard(&dir, (0, 2), (0, 50), 1, "ab");
+        shard(&dir, (1, 2), (50, 100), 1, "cd");
+        assert_refused(&dir, "different scene");
+
+        let dir = directory("overlap");
+        shard(&dir, (0, 2), (0, 50), 1, "ab");
+        shard(&dir, (1, 3), (33, 66), 1, "ab");
+        assert_refused(&dir, "overlap");
+
+        let dir = directory("manifest");
+        shard(&dir, (0, 2), (0, 50), 1, "ab");
+        shard(&dir, (1, 2), (50, 100), 1, "ab");
+        fs::remove_file(format!("{}.json", engine::shard_name(&dir, "test", 1, 2))).unwrap();
+        assert_refused(&dir, "manifest");
+
+        assert_refused(&directory("none"), "no shards");
+    }
+}
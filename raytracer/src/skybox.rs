@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::f64::consts::PI;
+
+use crate::util::*;
+use crate::observer::PhotonData;
+use crate::error::{Error, Result};
+
+// Backgrounds on the celestial sphere, seen by rays that escape. Directions are unit vectors in
+// the frame of the hole, with z along the spin axis.
+
+const STAR_MAP_WIDTH: usize = 2048;
+const STAR_MAP_HEIGHT: usize = STAR_MAP_WIDTH / 2;
+const STAR_RADIUS: f64 = 1.5; // Angular radius of a star, in texels at the equator
+const STAR_FLUX_INDEX: f64 = 1.5; // Stars brighter than F number in proportion to F^-index
+pub const STAR_MIN_TEMP: f64 = 3000.0; // Kelvin
+pub const STAR_MAX_TEMP: f64 = 12000.0;
+
+/// An equirectangular image of the sky: columns run over azimuth from 0 to 2π, and rows over
+/// polar angle from 0 (the top row) to π. Values are linear RGB.
+pub struct Texture {
+    width: usize,
+    height: usize,
+    data: Vec<[f32; 3]>,
+}
+
+impl Texture {
+    fn new(width: usize, height: usize) -> Self {
+        Self { width, height, data: vec![[0.0; 3]; width * height] }
+    }
+
+    /// Load an equirectangular PNG (sRGB, 8 or 16 bit) or OpenEXR (linear) file.
+    pub fn load(path: &str) -> Result<Self> {
+        if path.to_lowercase().ends_with(".exr") {
+            Self::load_exr(path)
+        } else {
+            Self::load_png(path)
+        }
+    }
+
+    fn load_png(path: &str) -> Result<Self> {
+        let mut decoder = png::Decoder::new(File::open(path)?);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let format_error = |e: png::DecodingError| Error::Format(format!("{}: {}", path, e));
+        let mut reader = decoder.read_info().map_err(format_error)?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(format_error)?;
+        // Which sample of a pixel each of red, green and blue comes from. Grey images repeat
+        // their one channel, and alpha is skipped.
+        let sources = match info.color_type {
+            png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => [0, 0, 0],
+            png::ColorType::Rgb | png::ColorType::Rgba => [0, 1, 2],
+            png::ColorType::Indexed => return Err(Error::Format(format!("{}: palette was not expanded", path))),
+        };
+        let channels = info.color_type.samples();
+        let bytes = if info.bit_depth == png::BitDepth::Sixteen { 2 } else { 1 };
+        let mut texture = Self::new(info.width as usize, info.height as usize);
+        for (k, texel) in texture.data.iter_mut().enumerate() {
+            for (value, source) in texel.iter_mut().zip(sources) {
+                let offset = (k * channels + source) * bytes;
+                let level = if bytes == 2 {
+                    u16::from_be_bytes([buffer[offset], buffer[offset + 1]]) as f32 / u16::MAX as f32
+                } else {
+                    buffer[offset] as f32 / u8::MAX as f32
+                };
+                *value = srgb_to_linear(level);
+            }
+        }
+        Ok(texture)
+    }
+
+    fn load_exr(path: &str) -> Result<Self> {
+        let image = exr::prelude::read_first_rgba_layer_from_file(
+            path,
+            |resolution, _| Self::new(resolution.width(), resolution.height()),
+            |texture: &mut Self, position, (r, g, b, _): (f32, f32, f32, f32)| {
+                let width = texture.width;
+                texture.data[position.y() * width + position.x()] = [r, g, b];
+            },
+        ).map_err(|e| Error::Format(format!("{}: {}", path, e)))?;
+        Ok(image.layer_data.channel_data.pixels)
+    }
+
+    /// Bilinearly interpolated colour in `direction`.
+    pub fn sample(&self, direction: Vec3) -> (f64, f64, f64) {
+        let (theta, phi) = direction_to_angles(direction);
+        let x = phi / (2.0 * PI) * self.width as f64 - 0.5;
+        let y = (theta / PI * self.height as f64 - 0.5).clamp(0.0, (self.height - 1) as f64);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let column = |x: f64| (x as i64).rem_euclid(self.width as i64) as usize;
+        let row = |y: f64| (y as usize).min(self.height - 1);
+        let mut color = [0.0; 3];
+        for (dx, dy, weight) in [(0.0, 0.0, (1.0 - fx) * (1.0 - fy)), (1.0, 0.0, fx * (1.0 - fy)), (0.0, 1.0, (1.0 - fx) * fy), (1.0, 1.0, fx * fy)] {
+            let texel = self.data[row(y0 + dy) * self.width + column(x0 + dx)];
+            for c in 0..3 {
+                color[c] += weight * texel[c] as f64;
+            }
+        }
+        (color[0], color[1], color[2])
+    }
+}
+
+/// What escaped rays see.
+pub enum Skybox {
+    /// An equirectangular image loaded from a file
+    Texture { path: String, texture: Texture },
+    /// Randomly placed stars with a power law of brightness
+    Stars { count: usize, seed: u64, texture: Texture },
+    /// Alternating white and grey squares, `divisions` to each half turn of θ and φ, for
+    /// checking deflection
+    Checkerboard { divisions: usize },
+}
+
+impl Skybox {
+    pub fn texture(path: &str) -> Result<Self> {
+        Ok(Skybox::Texture { path: path.to_owned(), texture: Texture::load(path)? })
+    }
+
+    /// `count` stars, placed and coloured from `seed`.
+    pub fn stars(count: usize, seed: u64) -> Self {
+        let rng = fastrand::Rng::with_seed(seed);
+        let mut texture = Texture::new(STAR_MAP_WIDTH, STAR_MAP_HEIGHT);
+        let texel_angle = 2.0 * PI / STAR_MAP_WIDTH as f64;
+        let radius = STAR_RADIUS * texel_angle;
+        for _ in 0..count {
+            // Uniform on the sphere
+            let z = 2.0 * rng.f64() - 1.0;
+            let phi = 2.0 * PI * rng.f64();
+            let center = [(1.0 - z * z).sqrt() * phi.cos(), (1.0 - z * z).sqrt() * phi.sin(), z];
+            let flux = (1.0 - rng.f64()).powf(-1.0 / STAR_FLUX_INDEX);
+            let color = PhotonData::temp_to_color(STAR_MIN_TEMP + (STAR_MAX_TEMP - STAR_MIN_TEMP) * rng.f64());
+
+            // Spread the star over the texels within a few radii, whose width shrinks towards
+            // the poles
+            let theta = z.clamp(-1.0, 1.0).acos();
+            let row_min = ((theta - 3.0 * radius) / texel_angle).floor().max(0.0) as usize;
+            let row_max = (((theta + 3.0 * radius) / texel_angle).ceil() as usize).min(STAR_MAP_HEIGHT - 1);
+            let sin_theta = theta.sin().max((3.0 * radius).sin());
+            let columns = ((3.0 * radius / sin_theta / texel_angle).ceil() as i64).min(STAR_MAP_WIDTH as i64 / 2);
+            let center_column = (phi / texel_angle) as i64;
+            for i in row_min..=row_max {
+                for j in center_column - columns..=center_column + columns {
+                    let j = j.rem_euclid(STAR_MAP_WIDTH as i64) as usize;
+                    let direction = angles_to_direction((i as f64 + 0.5) * texel_angle, (j as f64 + 0.5) * texel_angle);
+                    let angle = dot3(direction, center).clamp(-1.0, 1.0).acos();
+                    let weight = (flux * (-0.5 * (angle / radius).powi(2)).exp()) as f32;
+                    let texel = &mut texture.data[i * STAR_MAP_WIDTH + j];
+                    texel[0] += weight * color.0 as f32;
+                    texel[1] += weight * color.1 as f32;
+                    texel[2] += weight * color.2 as f32;
+                }
+            }
+        }
+        Skybox::Stars { count, seed, texture }
+    }
+
+    pub fn checkerboard(divisions: usize) -> Self {
+        Skybox::Checkerboard { divisions: divisions.max(1) }
+    }
+
+    /// Colour of the sky in `direction`.
+    pub fn sample(&self, direction: Vec3) -> (f64, f64, f64) {
+        match self {
+            Skybox::Texture { texture, .. } | Skybox::Stars { texture, .. } => texture.sample(direction),
+            Skybox::Checkerboard { divisions } => {
+                let (theta, phi) = direction_to_angles(direction);
+                let square = (theta / PI * *divisions as f64) as usize + (phi / PI * *divisions as f64) as usize;
+                if square.is_multiple_of(2) { (1.0, 1.0, 1.0) } else { (0.2, 0.2, 0.2) }
+            },
+        }
+    }
+
+    /// Settings of the background that affect the image, for hashing and bookkeeping.
+    pub fn describe(&self) -> String {
+        match self {
+            Skybox::Texture { path, .. } => format!("Texture {{ path: {} }}", path),
+            Skybox::Stars { count, seed, .. } => format!("Stars {{ count: {}, seed: {} }}", count, seed),
+            Skybox::Checkerboard { divisions } => format!("Checkerboard {{ divisions: {} }}", divisions),
+        }
+    }
+}
+
+/// Polar angle in [0, π] and azimuth in [0, 2π) of a unit vector.
+fn direction_to_angles(direction: Vec3) -> (f64, f64) {
+    let theta = direction[2].clamp(-1.0, 1.0).acos();
+    let phi = direction[1].atan2(direction[0]).rem_euclid(2.0 * PI);
+    (theta, phi)
+}
+
+fn angles_to_direction(theta: f64, phi: f64) -> Vec3 {
+    [theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()]
+}
+
+fn srgb_to_linear(level: f32) -> f32 {
+    if level <= 0.04045 {
+        level / 12.92
+    } else {
+        ((level + 0.055) / 1.055).powf(2.4)
+    }
+}
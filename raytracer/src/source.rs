@@ -1,5 +1,10 @@
 use crate::util::*;
 use crate::observer::IS_KERR;
+use crate::metrics::Metric;
+use crate::disk::{self, PageThorne, Plunge};
+use crate::corona::{LampPost, CoronaModel, CoronaTable};
+use crate::compton::{self, Electrons};
+use crate::error::{Error, Result};
 
 // Fix R_S at 1.
 const ALPHA: f64 = 500.0;
@@ -8,17 +13,64 @@ const REDSHIFT: f64 = 3.5;
 const CORONA_DENSITY_SCALE: f64 = 5.0e-26;
 const CORONA_PROTON_GAMMA_MINUS_ONE: f64 = 0.1;
 
-const CORONA_DENSITY_HEIGHT: f64 = 2.25 / CORONA_PROTON_GAMMA_MINUS_ONE;
-const CORONA_ELECTRON_GAMMA: f64 = 1836.0 * CORONA_PROTON_GAMMA_MINUS_ONE;
+pub const CORONA_DENSITY_HEIGHT: f64 = 2.25 / CORONA_PROTON_GAMMA_MINUS_ONE;
+pub const CORONA_ELECTRON_GAMMA: f64 = 1836.0 * CORONA_PROTON_GAMMA_MINUS_ONE;
+// Θ = kT / m_e c^2 of the corona, for which the mean Lorentz factor of the electrons is about
+// CORONA_ELECTRON_GAMMA
+pub const CORONA_ELECTRON_TEMPERATURE: f64 = CORONA_ELECTRON_GAMMA / 3.0;
+pub const CORONA_OPACITY: f64 = 2.16e8 * MASS * CORONA_DENSITY_SCALE; // Scatterings per R_S at unit density
 pub const RESCALE_FOR_XRAY: f64 = CORONA_ELECTRON_GAMMA * CORONA_ELECTRON_GAMMA;
 
-#[derive(Clone)]
+/// How the shift in frequency between the disk and the camera is found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Redshift {
+    /// g = (p·u_obs) / (p·u_em), with the gas on circular geodesics and the camera at rest
+    Geodesic,
+    /// The original approximation: Newtonian orbital speed, the special relativistic Doppler
+    /// shift and sqrt(-g_tt), multiplied together. Kept for comparison.
+    Approximate,
+}
+
+/// How the corona scatters light.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scattering {
+    /// Klein–Nishina scattering off electrons drawn from this distribution
+    KleinNishina(Electrons),
+    /// The original approximation: a new direction from a sum of uniform numbers in coordinate
+    /// space, with the energy raised by RESCALE_FOR_XRAY. Kept for comparison.
+    Approximate,
+}
+
+/// How bright and hot the disk is at each radius.
+#[derive(Clone, Debug)]
+pub enum Emission {
+    /// temp_scale / sqrt(r) and lum_scale / r^2, dropping linearly to the horizon inside r = 3
+    /// unless `IS_KERR` or the disk has a `Plunge`. Arbitrary units, as in the original renders,
+    /// except that the luminosity at the camera is g^4 times that emitted.
+    Scaled,
+    /// The Page–Thorne flux, with nothing inside the ISCO unless the disk has a `Plunge`. The
+    /// luminosity is the bolometric intensity at the camera, g^4 F / π in erg s^-1 cm^-2 sr^-1.
+    PageThorne(PageThorne),
+    /// Light from a lamp-post corona reflected off the disk: lum_scale times the lamp's
+    /// illumination, which replaces r^-2, with the temperatures of `Scaled`. Nothing inside the
+    /// ISCO unless the disk has a `Plunge`. The light is still the disk's thermal blackbody, only
+    /// as bright as the illumination, not a reflection spectrum.
+    Reflection(LampPost),
+}
+
+#[derive(Clone, Debug)]
 pub struct AccretionDisk {
     temp_scale: f64,
     ang_vel_at_horizon: f64,
     tau_scale: f64,
     lum_scale: f64,
     corona_scale: f64,
+    corona: CoronaModel,
+    scattering: Scattering,
+    redshift: Redshift,
+    emission: Emission,
+    /// Gas falling in from the ISCO, which replaces the disk inside it
+    plunge: Option<Plunge>,
 }
 
 impl AccretionDisk {
@@ -29,6 +81,11 @@ impl AccretionDisk {
             tau_scale: 3.7e-3 * ALPHA * MASS.powf(-1.0/8.0) * (2.0 * std::f64::consts::PI).sqrt(),
             lum_scale: 1.14e26 / MASS,
             corona_scale: 1.0,
+            corona: CoronaModel::Original,
+            scattering: Scattering::KleinNishina(Electrons::Thermal { temperature: None }),
+            redshift: Redshift::Geodesic,
+            emission: Emission::Scaled,
+            plunge: None,
         }
     }
     pub fn flat() -> Self {
@@ -38,6 +95,11 @@ impl AccretionDisk {
             tau_scale: 3.7e-3 * ALPHA * MASS.powf(-1.0/8.0) * (2.0 * std::f64::consts::PI).sqrt(),
             lum_scale: 1.14e26 / MASS,
             corona_scale: 0.0,
+            corona: CoronaModel::Original,
+            scattering: Scattering::KleinNishina(Electrons::Thermal { temperature: None }),
+            redshift: Redshift::Geodesic,
+            emission: Emission::Scaled,
+            plunge: None,
         }
     }
     pub fn thick() -> Self {
@@ -47,6 +109,11 @@ impl AccretionDisk {
             tau_scale: 3.7e-3 * ALPHA * MASS.powf(-1.0/8.0) * (2.0 * std::f64::consts::PI).sqrt() * 100.0,
             lum_scale: 1.14e26 / MASS,
             corona_scale: 0.0,
+            corona: CoronaModel::Original,
+            scattering: Scattering::KleinNishina(Electrons::Thermal { temperature: None }),
+            redshift: Redshift::Geodesic,
+            emission: Emission::Scaled,
+            plunge: None,
         }
     }
     pub fn thin() -> Self {
@@ -56,41 +123,220 @@ impl AccretionDisk {
             tau_scale: 3.7e-3 * ALPHA * MASS.powf(-1.0/8.0) * (2.0 * std::f64::consts::PI).sqrt(),
             lum_scale: 1.14e26 / MASS,
             corona_scale: 0.0,
+            corona: CoronaModel::Original,
+            scattering: Scattering::KleinNishina(Electrons::Thermal { temperature: None }),
+            redshift: Redshift::Geodesic,
+            emission: Emission::Scaled,
+            plunge: None,
+        }
+    }
+
+    /// The same disk with no corona to scatter its light.
+    pub fn without_corona(mut self) -> Self {
+        self.corona_scale = 0.0;
+        self
+    }
+
+    /// The same disk with a corona of the shape `corona`, which is turned on if it was off.
+    pub fn with_corona(mut self, corona: CoronaModel) -> Self {
+        self.corona_scale = 1.0;
+        self.corona = corona;
+        self
+    }
+
+    pub fn with_scattering(mut self, scattering: Scattering) -> Self {
+        self.scattering = scattering;
+        self
+    }
+
+    pub fn with_redshift(mut self, redshift: Redshift) -> Self {
+        self.redshift = redshift;
+        self
+    }
+
+    pub fn with_emission(mut self, emission: Emission) -> Self {
+        self.emission = emission;
+        self
+    }
+
+    pub fn with_plunge(mut self, plunge: Plunge) -> Self {
+        self.plunge = Some(plunge);
+        self
+    }
+
+    /// Parameters of the disk and corona, including the physical constants above, for recording
+    /// alongside the output.
+    pub fn parameters(&self) -> Vec<(&'static str, f64)> {
+        let mut parameters = vec![
+            ("temp_scale", self.temp_scale),
+            ("ang_vel_at_horizon", self.ang_vel_at_horizon),
+            ("tau_scale", self.tau_scale),
+            ("lum_scale", self.lum_scale),
+            ("corona_scale", self.corona_scale),
+            ("approximate_redshift", (self.redshift == Redshift::Approximate) as u8 as f64),
+            ("alpha", ALPHA),
+            ("mass", MASS),
+            ("redshift", REDSHIFT),
+            ("corona_density_scale", CORONA_DENSITY_SCALE),
+            ("corona_proton_gamma_minus_one", CORONA_PROTON_GAMMA_MINUS_ONE),
+            ("corona_density_height", CORONA_DENSITY_HEIGHT),
+            ("corona_electron_gamma", CORONA_ELECTRON_GAMMA),
+            ("rescale_for_xray", RESCALE_FOR_XRAY),
+            ("xray_gain", self.xray_gain()),
+            ("corona_electron_temperature", CORONA_ELECTRON_TEMPERATURE),
+            ("approximate_scattering", (self.scattering == Scattering::Approximate) as u8 as f64),
+        ];
+        if let Scattering::KleinNishina(electrons) = &self.scattering {
+            parameters.extend(electrons.parameters());
         }
+        parameters.extend(self.corona.parameters());
+        match &self.emission {
+            Emission::PageThorne(disk) => parameters.extend(disk.parameters()),
+            Emission::Reflection(lamp) => parameters.extend(lamp.parameters()),
+            Emission::Scaled => {},
+        }
+        if let Some(plunge) = &self.plunge {
+            parameters.extend(plunge.parameters());
+        }
+        parameters
     }
 
-    // Get the temperature, luminosity, and depth value after this point.
-    pub fn disk_collision(&self, pos: Vec4, vel: Vec4, grav_redshift: f64) -> (f64, f64, f64) {
-        let disk_vel = self.ang_vel_at_horizon / pos[1].sqrt();
-        let disk_vel = [-pos[3].sin() * disk_vel, pos[3].cos() * disk_vel, 0.0];
+    // Get the temperature, luminosity, and depth value after this point, or None where there is
+    // no disk. `observer_time` is u^t of the camera, which is at rest.
+    pub fn disk_collision<M: Metric>(&self, pos: Vec4, vel: Vec4, metric: &M, observer_time: f64) -> Option<(f64, f64, f64)> {
+        let plunge = self.plunge.as_ref().filter(|p| p.contains(pos[1]));
+        match (&self.emission, plunge) {
+            (Emission::PageThorne(disk), None) => { disk.flux(pos[1])?; },
+            (Emission::Reflection(lamp), None) => { lamp.emissivity(pos[1])?; },
+            _ => {},
+        }
         let light_3vel = spher_to_cart_vel(
             [pos[1], pos[2], pos[3]],
             [vel[1] / vel[0], vel[2] / vel[0], vel[3] / vel[0]]
         );
-        let vel_redshift = (1.0 + dot3(disk_vel, light_3vel) / (1.0 - dot3(disk_vel, light_3vel))).sqrt();
         let depth = self.tau_scale * (pos[1]).powf(1.25) / light_3vel[2].abs();
+        let shift = self.frequency_shift(pos, vel, metric, observer_time)?;
 
-        let temp = self.temp_scale / pos[1].sqrt() * grav_redshift * vel_redshift / REDSHIFT;
-        let lum = if pos[1] < 3.0 && !IS_KERR {
-            // Inside ISCO
-            // Linear drop from self.lum_scale/9 to 0 at EH
-            self.lum_scale / 18.0 * (pos[1] - 1.0)
+        let (temp, lum) = match (&self.emission, plunge) {
+            (Emission::Scaled, None) => {
+                let temp = self.temp_scale / pos[1].sqrt() * shift / REDSHIFT;
+                let lum = if pos[1] < 3.0 && !IS_KERR {
+                    // Inside ISCO
+                    // Linear drop from self.lum_scale/9 to 0 at EH
+                    self.lum_scale / 18.0 * (pos[1] - 1.0)
+                } else {
+                    // Outside ISCO
+                    self.lum_scale / (pos[1] * pos[1])
+                };
+                (temp, shift.powi(4) * lum)
+            },
+            (Emission::Scaled, Some(plunge)) => (
+                self.temp_scale / pos[1].sqrt() * shift / REDSHIFT,
+                shift.powi(4) * plunge.flux(pos[1], self.lum_scale / (plunge.isco() * plunge.isco())),
+            ),
+            (Emission::PageThorne(disk), None) => (
+                disk.temperature(pos[1])? * shift,
+                shift.powi(4) * disk.flux(pos[1])? / std::f64::consts::PI,
+            ),
+            (Emission::PageThorne(disk), Some(plunge)) => {
+                let flux = plunge.flux(pos[1], disk.peak_flux());
+                (disk.colour_temperature(flux) * shift, shift.powi(4) * flux / std::f64::consts::PI)
+            },
+            (Emission::Reflection(lamp), None) => (
+                self.temp_scale / pos[1].sqrt() * shift / REDSHIFT,
+                shift.powi(4) * self.lum_scale * lamp.emissivity(pos[1])?,
+            ),
+            (Emission::Reflection(lamp), Some(plunge)) => (
+                self.temp_scale / pos[1].sqrt() * shift / REDSHIFT,
+                shift.powi(4) * plunge.flux(pos[1], self.lum_scale * lamp.emissivity(plunge.isco()).unwrap_or(0.0)),
+            ),
+        };
+        Some((temp, lum, (-depth).exp()))
+    }
+
+    /// Frequency at the camera over frequency in the gas, for light leaving the disk at `pos`
+    /// along the ray with velocity `vel`. `observer_time` is u^t of the camera, which is at rest.
+    /// None where the gas has no 4-velocity.
+    pub fn frequency_shift<M: Metric>(&self, pos: Vec4, vel: Vec4, metric: &M, observer_time: f64) -> Option<f64> {
+        match self.redshift {
+            Redshift::Geodesic => {
+                // p_t is conserved along the ray, so p·u_obs = p_t u_obs^t. Both products scale
+                // with the ray's momentum, which the integrator does not keep fixed.
+                let momentum = matvecmul(&metric.get_metric(pos), vel);
+                Some(momentum[0] * observer_time / dot4(momentum, self.gas_velocity(pos, metric)?))
+            },
+            Redshift::Approximate => {
+                let light_3vel = spher_to_cart_vel(
+                    [pos[1], pos[2], pos[3]],
+                    [vel[1] / vel[0], vel[2] / vel[0], vel[3] / vel[0]]
+                );
+                let disk_vel = self.ang_vel_at_horizon / pos[1].sqrt();
+                let disk_vel = [-pos[3].sin() * disk_vel, pos[3].cos() * disk_vel, 0.0];
+                let vel_redshift = (1.0 + dot3(disk_vel, light_3vel) / (1.0 - dot3(disk_vel, light_3vel))).sqrt();
+                Some((-metric.get_metric(pos)[0]).sqrt() * vel_redshift)
+            },
+        }
+    }
+
+    /// Cosine of the angle between the ray and the normal of the disk at `pos`, in the frame of
+    /// the gas. The gas moves in the equatorial plane, so the normal's share of the photon's
+    /// energy there is sqrt(g_θθ) |p^θ| / |p·u|. None where the gas has no 4-velocity.
+    pub fn emission_cosine<M: Metric>(&self, pos: Vec4, vel: Vec4, metric: &M) -> Option<f64> {
+        let g = metric.get_metric(pos);
+        let energy = dot4(matvecmul(&g, vel), self.gas_velocity(pos, metric)?).abs();
+        Some((g[10].sqrt() * vel[2].abs() / energy).min(1.0))
+    }
+
+    /// 4-velocity of the gas at `pos`: a circular geodesic in the equatorial plane towards
+    /// increasing φ, or the plunge inside the ISCO if there is one. Disks without rotation sit
+    /// at rest instead. Where neither is timelike, inside the innermost circular orbit or the
+    /// ergosphere, the gas co-rotates with the zero angular momentum observers. None at and
+    /// inside the horizon, where nothing can stay at fixed r.
+    pub fn gas_velocity<M: Metric>(&self, pos: Vec4, metric: &M) -> Option<Vec4> {
+        let plunging = self.plunge.as_ref()
+            .filter(|p| p.contains(pos[1]))
+            .and_then(|p| p.velocity(metric, pos[1]));
+        if plunging.is_some() {
+            return plunging;
+        }
+        let motion = if self.ang_vel_at_horizon == 0.0 {
+            disk::circular_motion(metric, pos[1], 0.0)
         } else {
-            // Outside ISCO
-            self.lum_scale / (pos[1] * pos[1])
+            disk::circular_orbit(metric, pos[1])
         };
-        (temp, lum, (-depth).exp())
+        let g = disk::equatorial_metric(metric, pos[1]);
+        motion.or_else(|| disk::circular_motion(metric, pos[1], -g[3] / g[15]))
+            .map(|m| m.velocity)
     }
 
-    // Get the probability of collision per distance unit
+    /// Whether light can be scattered by the corona at all.
+    pub fn has_corona(&self) -> bool {
+        self.corona_scale > 0.0
+    }
+
+    /// The table the corona was read from, if it was.
+    pub fn corona_table(&self) -> Option<&CoronaTable> {
+        self.corona.table()
+    }
+
+    // Get the probability of collision per distance unit. With Klein–Nishina scattering this is
+    // WEIGHT_BOUND times the Thomson rate, and `corona_collide` misses the electron in the right
+    // share of collisions.
     pub fn corona_prob(&self, pos: Vec4) -> f64 {
-        let scale_factor = CORONA_DENSITY_HEIGHT / pos[1];
-        let corona_density = self.corona_scale * CORONA_DENSITY_SCALE * (1.0 + scale_factor + scale_factor * scale_factor * 0.5);
-        2.16e8 * MASS * corona_density // But also energy dependence`
+        let bound = match self.scattering {
+            Scattering::KleinNishina(_) => compton::WEIGHT_BOUND,
+            Scattering::Approximate => 1.0,
+        };
+        bound * self.corona_scale * CORONA_OPACITY * self.corona.density(pos)
     }
 
-    // Get the energy shift and the new velocity.
-    pub fn corona_collide(&self, pos: Vec4, _vel: Vec4, metric: &Matrix4, rng: &fastrand::Rng) -> (f64, Vec4) {
+    // Get the energy shift, the weight of the light and the new velocity, or None if the
+    // electron is missed.
+    pub fn corona_collide(&self, pos: Vec4, vel: Vec4, metric: &Matrix4, rng: &fastrand::Rng) -> Option<(f64, f64, Vec4)> {
+        if let Scattering::KleinNishina(electrons) = &self.scattering {
+            let gamma = electrons.sample(self.corona.electron_temperature(pos), rng);
+            return compton::scatter(metric, pos, vel, gamma, |r| self.seed_temperature(r), rng);
+        }
         // let theta = cdf_theta.asin();
         // let phi = cdf_phi * std::f64::consts::PI * 2.0;
         // let new_vel_cart = [
@@ -101,28 +347,54 @@ impl AccretionDisk {
 
         let mut new_vel_cart = [0.0, 0.0, 0.0];
         const NUM_RANDS: usize = 5;
-        for i in 0..3 {
+        for component in new_vel_cart.iter_mut() {
             for _ in 0..NUM_RANDS {
-                new_vel_cart[i] += rng.f64() - 0.5;
+                *component += rng.f64() - 0.5;
             }
         }
 
         let energy = RESCALE_FOR_XRAY;
         let vel3 = cart_to_spher_vel([pos[1],pos[2],pos[3]], normalize(new_vel_cart));
         let vel4 = get_vel_from_metric(vel3, metric);
-        (energy, vel4)
+        Some((energy, 1.0, vel4))
+    }
+
+    /// Mean gain in energy of the light the corona scatters: 1 + 4/3 <γ^2 β^2> in the Thomson
+    /// limit at the corona's mean electron temperature, or RESCALE_FOR_XRAY with the original
+    /// approximation. X-ray images are divided by this.
+    pub fn xray_gain(&self) -> f64 {
+        match &self.scattering {
+            Scattering::KleinNishina(electrons) => electrons.mean_gain(self.corona.mean_electron_temperature()),
+            Scattering::Approximate => RESCALE_FOR_XRAY,
+        }
+    }
+
+    /// Temperature, in eV, of the disk's light as the gas at radius `r` gives it out, for the
+    /// light the corona scatters.
+    fn seed_temperature(&self, r: f64) -> f64 {
+        match &self.emission {
+            Emission::PageThorne(disk) => disk.temperature(r.max(disk.isco())).unwrap_or(self.temp_scale / r.sqrt()),
+            _ => self.temp_scale / r.sqrt(),
+        }
     }
 }
 
-/// Find the root of a function using Newton's method
-pub fn find_root(f: impl Fn(f64)->f64, df: impl Fn(f64)->f64, start: f64) -> f64 {
+const ROOT_MAX_ITERATIONS: usize = 100;
+
+/// Find the root of a function using Newton's method. Fails if the iteration leaves the real
+/// numbers or has not converged after `ROOT_MAX_ITERATIONS` steps.
+pub fn find_root(f: impl Fn(f64)->f64, df: impl Fn(f64)->f64, start: f64) -> Result<f64> {
     let mut x = start;
     let mut error = f(x);
-    loop {
+    for _ in 0..ROOT_MAX_ITERATIONS {
         x -= error / df(x);
         error = f(x);
+        if !x.is_finite() || !error.is_finite() {
+            return Err(Error::Numerical(format!("Newton's method from {} diverged", start)));
+        }
         if error.abs() < 0.001 {
-            return x;
+            return Ok(x);
         }
     }
+    Err(Error::Numerical(format!("Newton's method from {} did not converge", start)))
 }
\ No newline at end of file
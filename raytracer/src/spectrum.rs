@@ -0,0 +1,207 @@
+use std::sync::OnceLock;
+
+use crate::util::*;
+use crate::error::{Error, Result};
+
+// Colours of thermal light as the eye would see them. A blackbody spectrum is integrated against
+// the CIE 1931 2° colour matching functions to give XYZ tristimulus values, which are turned into
+// linear sRGB (D65 white). The matching functions are the multi-lobe Gaussian fit of Wyman, Sloan
+// & Shirley (2013), which is within a few percent of the tabulated ones.
+//
+// A blackbody at temperature T seen with frequency shift g has intensity g^3 B_ν(ν / g, T) =
+// B_ν(ν, gT) at frequency ν: it is a blackbody at gT. Colours are therefore found from the
+// observed temperature, and carry the intensity of the spectrum relative to its bolometric
+// intensity σT^4 / π, so that multiplying by the bolometric intensity gives the light in each
+// channel.
+//
+// For spectra, the same blackbody is split between log-spaced energy bins. The fraction of its
+// light in each bin comes from the integral of x^3 / (e^x - 1), which is summed as a series.
+
+const PLANCK: f64 = 6.62607015e-34; // J s
+const BOLTZMANN: f64 = 1.380649e-23; // J / K
+pub const BOLTZMANN_EV: f64 = 8.617333262e-5; // eV / K
+const SPEED_OF_LIGHT: f64 = 2.99792458e8; // m / s
+const STEFAN_BOLTZMANN: f64 = 5.670374e-8; // W m^-2 K^-4
+const WAVELENGTH_MIN: f64 = 360.0; // nm
+const WAVELENGTH_MAX: f64 = 830.0; // nm
+const WAVELENGTH_STEP: f64 = 2.0; // nm
+const SMALL_ENERGY: f64 = 0.1; // Below this many kT the blackbody integral is summed as a power series
+const SERIES_TOLERANCE: f64 = 1e-14;
+
+/// Linear sRGB from XYZ
+const XYZ_TO_RGB: [[f64; 3]; 3] = [
+    [3.2406, -1.5372, -0.4986],
+    [-0.9689, 1.8758, 0.0415],
+    [0.0557, -0.2040, 1.0570],
+];
+
+/// CIE 1931 2° colour matching functions x̄, ȳ, z̄ at `wavelength` nm.
+pub fn colour_matching(wavelength: f64) -> Vec3 {
+    // Gaussian with different widths either side of its peak
+    let lobe = |peak: f64, below: f64, above: f64| {
+        let width = if wavelength < peak { below } else { above };
+        (-0.5 * ((wavelength - peak) / width).powi(2)).exp()
+    };
+    [
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    ]
+}
+
+/// Spectral radiance B_λ of a blackbody at `temp` kelvin, at `wavelength` metres, in
+/// W m^-3 sr^-1.
+pub fn planck(wavelength: f64, temp: f64) -> f64 {
+    let exponent = PLANCK * SPEED_OF_LIGHT / (wavelength * BOLTZMANN * temp);
+    2.0 * PLANCK * SPEED_OF_LIGHT * SPEED_OF_LIGHT / wavelength.powi(5) / exponent.exp_m1()
+}
+
+/// Bolometric radiance σT^4 / π of a blackbody at `temp` kelvin, in W m^-2 sr^-1.
+pub fn bolometric(temp: f64) -> f64 {
+    STEFAN_BOLTZMANN * temp.powi(4) / std::f64::consts::PI
+}
+
+/// The wavelengths the spectra are integrated over, in nm, and the matching functions there.
+fn samples() -> &'static [(f64, Vec3)] {
+    static SAMPLES: OnceLock<Vec<(f64, Vec3)>> = OnceLock::new();
+    SAMPLES.get_or_init(|| {
+        let count = ((WAVELENGTH_MAX - WAVELENGTH_MIN) / WAVELENGTH_STEP) as usize + 1;
+        (0..count).map(|k| {
+            let wavelength = WAVELENGTH_MIN + k as f64 * WAVELENGTH_STEP;
+            (wavelength, colour_matching(wavelength))
+        }).collect()
+    })
+}
+
+/// XYZ of a blackbody at `temp` kelvin, per unit bolometric radiance.
+pub fn blackbody_xyz(temp: f64) -> Vec3 {
+    if !(temp.is_finite() && temp > 0.0) {
+        return [0.0, 0.0, 0.0];
+    }
+    let mut xyz = [0.0; 3];
+    for (wavelength, matching) in samples() {
+        let radiance = planck(wavelength * 1e-9, temp) * WAVELENGTH_STEP * 1e-9;
+        xyz = add3(xyz, mul3(*matching, radiance));
+    }
+    mul3(xyz, 1.0 / bolometric(temp))
+}
+
+/// Linear sRGB of XYZ. Colours outside the sRGB gamut are clipped at zero.
+pub fn xyz_to_rgb(xyz: Vec3) -> (f64, f64, f64) {
+    let channel = |row: [f64; 3]| dot3(row, xyz).max(0.0);
+    (channel(XYZ_TO_RGB[0]), channel(XYZ_TO_RGB[1]), channel(XYZ_TO_RGB[2]))
+}
+
+/// Linear sRGB of a blackbody at `temp` kelvin, per unit bolometric radiance. Very hot and very
+/// cold bodies are dim, since little of their light is visible.
+pub fn blackbody_rgb(temp: f64) -> (f64, f64, f64) {
+    xyz_to_rgb(blackbody_xyz(temp))
+}
+
+/// Fraction of the bolometric intensity of a blackbody at `temp` eV carried per eV of photon
+/// energy at `energy` eV.
+pub fn blackbody_density(energy: f64, temp: f64) -> f64 {
+    let x = energy / temp;
+    15.0 / std::f64::consts::PI.powi(4) * x.powi(3) / x.exp_m1() / temp
+}
+
+/// Log-spaced energy bins that spectra are accumulated in, between `min` and `max` eV.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnergyGrid {
+    pub min: f64,
+    pub max: f64,
+    pub bins: usize,
+    /// The `bins + 1` bin edges in eV, worked out once since every piece of light needs them
+    edges: Vec<f64>,
+}
+
+impl EnergyGrid {
+    pub fn new(min: f64, max: f64, bins: usize) -> Result<Self> {
+        if !(min.is_finite() && max.is_finite() && 0.0 < min && min < max) {
+            return Err(Error::InvalidScene(format!("energy grid needs 0 < min < max, not {} to {}", min, max)));
+        }
+        if bins == 0 {
+            return Err(Error::InvalidScene("energy grid needs at least one bin".to_owned()));
+        }
+        let ratio = (max / min).ln() / bins as f64;
+        let edges = (0..=bins).map(|k| min * (k as f64 * ratio).exp()).collect();
+        Ok(Self { min, max, bins, edges })
+    }
+
+    /// The grid whose bin edges are `edges`, as written by `edges`.
+    pub fn from_edges(edges: &[f64]) -> Result<Self> {
+        match edges {
+            [min, .., max] => Self::new(*min, *max, edges.len() - 1),
+            _ => Err(Error::Format(format!("energy grid needs at least two bin edges, not {}", edges.len()))),
+        }
+    }
+
+    /// The `bins + 1` bin edges in eV.
+    pub fn edges(&self) -> &[f64] {
+        &self.edges
+    }
+
+    /// Add the light of a blackbody at `temp` eV with bolometric intensity `intensity` to
+    /// `spectrum`, one entry per bin. Each bin gets the intensity between its edges, so light
+    /// outside the grid is lost.
+    pub fn add_blackbody(&self, spectrum: &mut [f64], temp: f64, intensity: f64) {
+        if !(temp.is_finite() && temp > 0.0 && intensity != 0.0) {
+            return;
+        }
+        let mut above = fraction_above(self.edges[0] / temp);
+        for (bin, edge) in spectrum.iter_mut().zip(&self.edges[1..]) {
+            let next = fraction_above(edge / temp);
+            *bin += (above - next) * intensity;
+            above = next;
+        }
+    }
+}
+
+/// Fraction of the bolometric intensity of a blackbody carried by photons with energy above
+/// x kT.
+fn fraction_above(x: f64) -> f64 {
+    let norm = 15.0 / std::f64::consts::PI.powi(4);
+    if x < SMALL_ENERGY {
+        // Integrate the series t^3 / (e^t - 1) = t^2 - t^3 / 2 + t^4 / 12 - t^6 / 720 + ...
+        return 1.0 - norm * x.powi(3) * (1.0 / 3.0 - x / 8.0 + x * x / 60.0 - x.powi(4) / 5040.0);
+    }
+    // ∫_x^∞ t^3 / (e^t - 1) dt = Σ_k e^(-kx) (x^3 / k + 3x^2 / k^2 + 6x / k^3 + 6 / k^4)
+    let decay = (-x).exp();
+    let mut weight = decay;
+    let mut sum = 0.0;
+    let mut k = 1.0;
+    while weight > SERIES_TOLERANCE * decay {
+        sum += weight * (x.powi(3) / k + 3.0 * x * x / (k * k) + 6.0 * x / k.powi(3) + 6.0 / k.powi(4));
+        weight *= decay;
+        k += 1.0;
+    }
+    norm * sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daylight_blackbody_is_nearly_white() {
+        // sRGB white is D65, which is close to a 6500 K blackbody
+        let (r, g, b) = blackbody_rgb(6500.0);
+        let brightest = r.max(g).max(b);
+        for channel in [r, g, b] {
+            assert!(channel / brightest > 0.9, "({}, {}, {}) is not white", r, g, b);
+        }
+        let (r, _, b) = blackbody_rgb(3000.0);
+        assert!(r > 2.0 * b, "3000 K should be red, not ({}, _, {})", r, b);
+    }
+
+    #[test]
+    fn fraction_above_runs_from_one_to_zero() {
+        assert_eq!(fraction_above(0.0), 1.0);
+        assert!(fraction_above(50.0) < 1e-15);
+        // The series for small energies meets the sum for large ones
+        let (below, above) = (fraction_above(SMALL_ENERGY * (1.0 - 1e-12)), fraction_above(SMALL_ENERGY));
+        assert!((below - above).abs() < 1e-12, "{} below the switch, {} above", below, above);
+        // Half the light is above about 3.5 kT
+        assert!((fraction_above(3.503) - 0.5).abs() < 1e-3, "{}", fraction_above(3.503));
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs;
+use std::fmt::Write as _;
+use ndarray::Array2;
+use ndarray_npy::write_npy;
+use rayon::prelude::*;
+
+use crate::util::*;
+use crate::observer::{Observer, Termination, WIDTH, HEIGHT};
+use crate::metrics::Metric;
+use crate::source::AccretionDisk;
+use crate::error::{Error, Result};
+
+// Single rays traced with their whole path kept, for figures of light bending around the hole.
+// Positions are recorded in the coordinates of the metric, (t, r, θ, φ), and also as Cartesian
+// x = r sin θ cos φ, y = r sin θ sin φ, z = r cos θ (Boyer–Lindquist r for Kerr), with z along
+// the spin axis. Paths run backwards in time, from the camera out into the scene.
+
+const COLUMNS: &str = "ray,t,r,theta,phi,x,y,z";
+
+/// Which rays to trace.
+pub enum Rays {
+    /// Rays through these (row, column) points of the image, in pixels
+    Points(Vec<(f64, f64)>),
+    /// `count` rays evenly spaced across the middle row of the image, through the hole
+    Fan { count: usize },
+}
+
+impl Rays {
+    fn points(&self) -> Vec<(f64, f64)> {
+        match self {
+            Rays::Points(points) => points.clone(),
+            Rays::Fan { count } => {
+                let row = (HEIGHT / 2) as f64;
+                let step = (WIDTH - 1) as f64 / (*count).max(2).saturating_sub(1) as f64;
+                (0..*count).map(|k| (row, k as f64 * step)).collect()
+            },
+        }
+    }
+}
+
+/// The path of one ray.
+pub struct Path {
+    /// (row, column) of the image the ray was fired through
+    pub point: (f64, f64),
+    pub termination: Termination,
+    /// (t, r, θ, φ) every `stride` steps, plus the start and the end, with no repeats and no
+    /// positions that are not finite
+    pub positions: Vec<Vec4>,
+}
+
+impl Path {
+    fn cartesian(&self) -> impl Iterator<Item = Vec3> + '_ {
+        self.positions.iter().map(|p| [
+            p[1] * p[2].sin() * p[3].cos(),
+            p[1] * p[2].sin() * p[3].sin(),
+            p[1] * p[2].cos(),
+        ])
+    }
+}
+
+/// Trace `rays` from `observer`, keeping every `stride`th position. Each ray draws its random
+/// numbers from `seed` and its index, so a trace is reproducible.
+#[allow(clippy::too_many_arguments)]
+pub fn trace<O: Observer + Sync, M: Metric>(
+    observer: &O, metric: &M, source: &AccretionDisk, max_iterations: usize, dtau: f64,
+    rays: &Rays, stride: usize, seed: u64,
+) -> Result<Vec<Path>> {
+    if !(dtau.is_finite() && dtau > 0.0) {
+        return Err(Error::InvalidScene(format!("dtau must be positive, not {}", dtau)));
+    }
+    let points = rays.points();
+    if points.is_empty() {
+        return Err(Error::InvalidScene("no rays to trace".to_owned()));
+    }
+    Ok(points.into_par_iter().enumerate().map(|(k, point)| {
+        let rng = fastrand::Rng::with_seed(mix_seed(seed, k as u64));
+        let (data, positions) = observer.photon_through(point, rng).trace(max_iterations, dtau, metric, source, stride);
+        Path { point, termination: data.termination, positions }
+    }).collect())
+}
+
+/// Write the paths to `<base>-paths.csv` and `<base>-paths.npy`, one row per position with the
+/// columns ray, t, r, θ, φ, x, y, z, and as polylines in `<base>-paths.vtk` (legacy VTK, for
+/// ParaView) and `<base>-paths.obj` (for Blender).
+pub fn write(base: &str, paths: &[Path]) -> Result<()> {
+    let total: usize = paths.iter().map(|p| p.positions.len()).sum();
+    let mut table = Array2::zeros((total, 8));
+    let mut csv = format!("{}\n", COLUMNS);
+    let mut row = 0;
+    for (k, path) in paths.iter().enumerate() {
+        for (p, c) in path.positions.iter().zip(path.cartesian()) {
+            let values = [k as f64, p[0], p[1], p[2], p[3], c[0], c[1], c[2]];
+            for (column, value) in values.iter().enumerate() {
+                table[(row, column)] = *value;
+            }
+            writeln!(csv, "{},{},{},{},{},{},{},{}", k, p[0], p[1], p[2], p[3], c[0], c[1], c[2]).unwrap();
+            row += 1;
+        }
+    }
+    fs::write(format!("{}-paths.csv", base), csv)?;
+    write_npy(format!("{}-paths.npy", base), &table)?;
+    fs::write(format!("{}-paths.vtk", base), vtk(paths, total))?;
+    fs::write(format!("{}-paths.obj", base), obj(paths))?;
+    Ok(())
+}
+
+/// Legacy VTK polydata: one line per ray, with r as point data and the termination and image
+/// point as cell data.
+fn vtk(paths: &[Path], total: usize) -> String {
+    let mut text = format!("# vtk DataFile Version 3.0\nraytracer geodesics\nASCII\nDATASET POLYDATA\nPOINTS {} double\n", total);
+    for path in paths {
+        for c in path.cartesian() {
+            writeln!(text, "{} {} {}", c[0], c[1], c[2]).unwrap();
+        }
+    }
+    writeln!(text, "LINES {} {}", paths.len(), total + paths.len()).unwrap();
+    let mut first = 0;
+    for path in paths {
+        let indices: Vec<String> = (first..first + path.positions.len()).map(|i| i.to_string()).collect();
+        writeln!(text, "{} {}", path.positions.len(), indices.join(" ")).unwrap();
+        first += path.positions.len();
+    }
+    writeln!(text, "CELL_DATA {}\nSCALARS termination int 1\nLOOKUP_TABLE default", paths.len()).unwrap();
+    for path in paths {
+        writeln!(text, "{}", path.termination as usize).unwrap();
+    }
+    writeln!(text, "SCALARS image_point double 2\nLOOKUP_TABLE default").unwrap();
+    for path in paths {
+        writeln!(text, "{} {}", path.point.0, path.point.1).unwrap();
+    }
+    writeln!(text, "POINT_DATA {}\nSCALARS r double 1\nLOOKUP_TABLE default", total).unwrap();
+    for path in paths {
+        for p in &path.positions {
+            writeln!(text, "{}", p[1]).unwrap();
+        }
+    }
+    text
+}
+
+/// Wavefront OBJ with one object of line elements per ray, named after how it ended.
+fn obj(paths: &[Path]) -> String {
+    let mut text = String::from("# raytracer geodesics\n");
+    let mut first = 1;
+    for (k, path) in paths.iter().enumerate() {
+        writeln!(text, "o ray_{}_{}", k, path.termination.name()).unwrap();
+        for c in path.cartesian() {
+            writeln!(text, "v {} {} {}", c[0], c[1], c[2]).unwrap();
+        }
+        if path.positions.len() >= 2 {
+            let indices: Vec<String> = (first..first + path.positions.len()).map(|i| i.to_string()).collect();
+            writeln!(text, "l {}", indices.join(" ")).unwrap();
+        }
+        first += path.positions.len();
+    }
+    text
+}
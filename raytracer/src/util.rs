@@ -1,5 +1,3 @@
-pub use crate::metrics::Metric;
-
 pub type Vec4 = [f64; 4];
 pub type Vec3 = [f64; 3];
 pub type Matrix4 = [f64; 16];
@@ -137,7 +135,41 @@ pub fn spher_to_cart_vel(pos: Vec3, spher_vel: Vec3) -> Vec3 {
 
 pub fn get_vel_from_metric(vel: Vec3, g: &Matrix4) -> Vec4 {
     let mut v4 = [0.0, vel[0], vel[1], vel[2]];
-    let spatial_norm = dot4(v4, matvecmul(&g, v4));
+    let spatial_norm = dot4(v4, matvecmul(g, v4));
     v4[0] = (-spatial_norm / g[0]).sqrt();
     v4
-}
\ No newline at end of file
+}
+
+/// Orthonormal tetrad of the zero angular momentum observer for the metric `g`, which has no
+/// cross terms but g_tφ: its 4-velocity, then the unit vectors along r, θ and φ.
+pub fn zamo_tetrad(g: &Matrix4) -> [Vec4; 4] {
+    let lapse = ((g[3] * g[3] - g[0] * g[15]) / g[15]).sqrt();
+    let frame_dragging = -g[3] / g[15];
+    [
+        [1.0 / lapse, 0.0, 0.0, frame_dragging / lapse],
+        [0.0, 1.0 / g[5].sqrt(), 0.0, 0.0],
+        [0.0, 0.0, 1.0 / g[10].sqrt(), 0.0],
+        [0.0, 0.0, 0.0, 1.0 / g[15].sqrt()],
+    ]
+}
+
+/// Mix a seed with an index (splitmix64) to get an independent seed for each photon. The random
+/// numbers a photon sees therefore depend only on the run's seed and its index, not on which
+/// thread traced it or when.
+pub fn mix_seed(seed: u64, index: u64) -> u64 {
+    let mut z = seed.wrapping_add(index.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// 64-bit FNV-1a hash. Unlike `std::hash`, the result is the same on every platform and Rust
+/// version, so it can be stored in files.
+pub fn hash_str(s: &str) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in s.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}